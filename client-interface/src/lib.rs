@@ -1,4 +1,9 @@
-use std::future::Future;
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -10,32 +15,55 @@ pub struct ImageData {
 }
 
 impl ImageData {
+    /// Decode a PNG into RGBA, PNGs without alpha or in grayscale are converted.
     pub fn from_png(bytes: &[u8]) -> anyhow::Result<Self> {
-        let decoder = png::Decoder::new(std::io::Cursor::new(bytes));
-        let mut reader = decoder.read_info().expect("Failed to decode icon");
-        let info = reader.info();
-        let mut buf = vec![0; info.raw_bytes()];
-        let output_info = reader
-            .next_frame(buf.as_mut_slice())
-            .expect("Failed to decode icon");
-        output_info.buffer_size();
+        Self::read_png(std::io::Cursor::new(bytes))
+    }
+
+    /// Like [`ImageData::from_png`], but reads the PNG as it is decoded, e.g. from a file.
+    pub fn read_png(reader: impl std::io::BufRead + std::io::Seek) -> anyhow::Result<Self> {
+        let mut decoder = png::Decoder::new(reader);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let output_info = reader.next_frame(buf.as_mut_slice())?;
+        buf.truncate(output_info.buffer_size());
+        let data = match output_info.color_type {
+            png::ColorType::Rgba => buf,
+            png::ColorType::Rgb => buf
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buf
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            png::ColorType::Grayscale => buf.iter().flat_map(|&p| [p, p, p, 255]).collect(),
+            png::ColorType::Indexed => anyhow::bail!("Indexed PNG was not expanded"),
+        };
 
         Ok(Self {
             width: output_info.width as usize,
             height: output_info.height as usize,
-            data: buf[0..output_info.buffer_size()].to_vec(),
+            data,
         })
     }
 
     pub fn to_png(&self) -> anyhow::Result<Vec<u8>> {
         let mut buf = vec![];
-        let mut encoder = png::Encoder::new(&mut buf, self.width as u32, self.height as u32);
+        self.write_png(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Like [`ImageData::to_png`], but writes the PNG as it is encoded, e.g. to a file.
+    pub fn write_png(&self, writer: impl std::io::Write) -> anyhow::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
         encoder.set_color(png::ColorType::Rgba);
         encoder.set_depth(png::BitDepth::Eight);
         let mut writer = encoder.write_header()?;
         writer.write_image_data(&self.data)?;
         writer.finish()?;
-        Ok(buf)
+        Ok(())
     }
 }
 
@@ -62,6 +90,15 @@ impl ClipboardContent {
             ClipboardContent::Image(img) => img.data.is_empty(),
         }
     }
+
+    /// The content as it's put on the system clipboard.
+    pub fn normalize(self) -> Self {
+        match self {
+            // HACK: Windows and macOS/Linux have different line endings.
+            ClipboardContent::Text(text) => ClipboardContent::Text(text.replace("\r\n", "\n")),
+            content => content,
+        }
+    }
 }
 
 impl std::fmt::Debug for ClipboardContent {
@@ -79,8 +116,163 @@ pub struct ClipboardRecord {
     pub content: ClipboardContent,
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeviceFilter {
+    #[serde(default)]
+    pub accept_from: Vec<String>,
+    #[serde(default)]
+    pub deny_from: Vec<String>,
+}
+
+impl DeviceFilter {
+    pub fn accepts(&self, device: &str) -> bool {
+        if self.deny_from.iter().any(|d| d == device) {
+            return false;
+        }
+        self.accept_from.is_empty() || self.accept_from.iter().any(|d| d == device)
+    }
+}
+
+/// Transfers at least this large are logged at the info level.
+const LARGE_TRANSFER: u64 = 1024 * 1024;
+
+/// Logs how far a transfer of `total` bytes got, at most every 10%.
+pub struct Progress {
+    what: String,
+    total: u64,
+    logged: u64,
+}
+
+impl Progress {
+    pub fn new(what: impl ToString, total: u64) -> Self {
+        Self {
+            what: what.to_string(),
+            total,
+            logged: 0,
+        }
+    }
+
+    pub fn update(&mut self, done: u64) {
+        let percent = (done * 100).checked_div(self.total).unwrap_or(100).min(100);
+        if percent < self.logged + 10 && percent < 100 {
+            return;
+        }
+        if percent == 100 && self.logged == 100 {
+            return;
+        }
+        self.logged = percent;
+        let level = if self.total >= LARGE_TRANSFER {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        };
+        log::log!(level, "{}: {}% of {} bytes", self.what, percent, self.total);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    BackingOff { retry_at: i64 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    pub since: i64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl std::fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.state {
+            ConnectionState::Connecting => write!(f, "connecting (attempt {})", self.attempts)?,
+            ConnectionState::Connected => write!(f, "connected")?,
+            ConnectionState::BackingOff { retry_at } => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or_default();
+                write!(f, "backing off, retry in {}s", (retry_at - now).max(0))?
+            }
+        }
+        if let Some(error) = &self.last_error {
+            write!(f, ", last error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `[reconnect]` settings, shared by all connections that are retried.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ReconnectConfig {
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    #[serde(default = "default_max_delay_secs")]
+    pub max_delay_secs: u64,
+}
+
+fn default_initial_delay_ms() -> u64 {
+    500
+}
+
+fn default_max_delay_secs() -> u64 {
+    60
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: default_initial_delay_ms(),
+            max_delay_secs: default_max_delay_secs(),
+        }
+    }
+}
+
+/// Exponential backoff with "equal jitter", every delay is randomly picked from `[d/2, d]`.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfig) -> Self {
+        let initial = Duration::from_millis(config.initial_delay_ms.max(1));
+        let max = Duration::from_secs(config.max_delay_secs).max(initial);
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        let half = delay / 2;
+        // `RandomState` is randomly seeded, which is good enough for jitter.
+        let random = RandomState::new().build_hasher().finish();
+        half + Duration::from_nanos(random % (half.as_nanos() as u64 + 1))
+    }
+}
+
 pub trait ClipboardSource {
     fn poll(&mut self) -> impl Future<Output = anyhow::Result<ClipboardRecord>>;
+
+    /// Close the connection cleanly, called after the sink is closed.
+    fn close(&mut self) -> impl Future<Output = anyhow::Result<()>> {
+        async { Ok(()) }
+    }
 }
 
 pub trait ClipboardSink {
@@ -88,6 +280,11 @@ pub trait ClipboardSink {
         &mut self,
         data: Option<ClipboardRecord>,
     ) -> impl Future<Output = anyhow::Result<()>>;
+
+    /// Close the connection cleanly, e.g. on shutdown.
+    fn close(&mut self) -> impl Future<Output = anyhow::Result<()>> {
+        async { Ok(()) }
+    }
 }
 
 pub trait ClipSyncClient {
@@ -101,7 +298,7 @@ pub trait ClipSyncClient {
 mod ws {
     use serde::{Deserialize, Serialize};
 
-    #[derive(Debug, Clone, Deserialize)]
+    #[derive(Debug, Clone, Default, Deserialize)]
     pub struct Params {
         #[serde(default)]
         pub q: Option<String>,
@@ -117,6 +314,11 @@ mod ws {
         pub skip: Option<usize>,
         #[serde(default)]
         pub sort: Option<String>,
+        #[serde(default)]
+        pub channel: Option<String>,
+        /// Only pinned entries
+        #[serde(default)]
+        pub pinned: Option<bool>,
     }
 
     impl Params {
@@ -140,9 +342,15 @@ mod ws {
             if let Some(skip) = &self.skip {
                 query.push(("skip", skip.to_string()));
             }
-            if let Some(sort) = &self.skip {
+            if let Some(sort) = &self.sort {
                 query.push(("sort", sort.to_string()));
             }
+            if let Some(channel) = &self.channel {
+                query.push(("channel", channel.to_string()));
+            }
+            if let Some(pinned) = &self.pinned {
+                query.push(("pinned", pinned.to_string()));
+            }
             query
         }
     }
@@ -157,6 +365,9 @@ mod ws {
         pub entry: ServerClipboardRecord,
         #[serde(default = "default_timestamp")]
         pub timestamp: i64,
+        /// Pinned in the history, only set by the server
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub pinned: bool,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -166,6 +377,10 @@ mod ws {
         pub source: String,
         #[serde(flatten)]
         pub content: ServerClipboardContent,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub targets: Option<Vec<String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub channel: Option<String>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -178,3 +393,24 @@ mod ws {
 
 #[cfg(feature = "websocket")]
 pub use ws::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(&ReconnectConfig {
+            initial_delay_ms: 1000,
+            max_delay_secs: 4,
+        });
+        let bounds = [(500, 1000), (1000, 2000), (2000, 4000), (2000, 4000)];
+        for (low, high) in bounds {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(low), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(high), "{:?}", delay);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(1000));
+    }
+}
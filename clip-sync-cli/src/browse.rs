@@ -0,0 +1,721 @@
+//! `clip-sync-cli browse`, a full-screen browser for the history on the server.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Context;
+use client_interface::{
+    ClipSyncClient, ClipboardContent, ClipboardMessage, ClipboardSource, ServerClipboardContent,
+};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::Instant,
+};
+
+use crate::get::{fetch_image, server_url, with_secret};
+
+const PAGE_SIZE: usize = 50;
+
+/// How long typing has to pause before the query is sent.
+const SEARCH_DELAY: Duration = Duration::from_millis(200);
+
+/// Entries are broadcast before they are indexed, so the refresh after a live update waits a bit.
+const REFRESH_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct BrowseArgs {
+    /// Initial search text
+    #[clap(index = 1)]
+    text: Option<String>,
+    /// Only show entries from this device
+    #[arg(short, long)]
+    from: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeRange {
+    All,
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl TimeRange {
+    fn next(self) -> Self {
+        match self {
+            TimeRange::All => TimeRange::Hour,
+            TimeRange::Hour => TimeRange::Day,
+            TimeRange::Day => TimeRange::Week,
+            TimeRange::Week => TimeRange::Month,
+            TimeRange::Month => TimeRange::All,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            TimeRange::All => "any time",
+            TimeRange::Hour => "last hour",
+            TimeRange::Day => "last 24 hours",
+            TimeRange::Week => "last 7 days",
+            TimeRange::Month => "last 30 days",
+        }
+    }
+
+    fn begin(self) -> Option<i64> {
+        let seconds = match self {
+            TimeRange::All => return None,
+            TimeRange::Hour => 3600,
+            TimeRange::Day => 24 * 3600,
+            TimeRange::Week => 7 * 24 * 3600,
+            TimeRange::Month => 30 * 24 * 3600,
+        };
+        Some(chrono::Utc::now().timestamp() - seconds)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Page {
+    total: usize,
+    data: Vec<ClipboardMessage>,
+}
+
+enum Update {
+    /// Result of the query with this generation, older ones are dropped
+    Page(u64, anyhow::Result<Page>),
+    Devices(Vec<String>),
+    /// An entry was published by `source`
+    Live(String),
+    LiveStopped(String),
+    /// Size and dimensions of an image, or why they are unknown
+    ImageInfo(String, String),
+    Copy(anyhow::Result<ClipboardContent>),
+    /// A change to the history is done, the message is shown in the status line
+    Changed(anyhow::Result<String>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Mode {
+    Normal,
+    /// Typing in the search box
+    Search,
+    /// Waiting for `y` to delete the entry with this ID
+    ConfirmDelete(String),
+}
+
+struct App {
+    args: clip_sync_config::Args,
+    updates: UnboundedSender<Update>,
+    mode: Mode,
+    text: String,
+    devices: Vec<String>,
+    device: Option<String>,
+    time_range: TimeRange,
+    pinned_only: bool,
+    page: usize,
+    total: usize,
+    entries: Vec<ClipboardMessage>,
+    list: ListState,
+    generation: u64,
+    loading: bool,
+    /// When to send the next query, typing and live updates push it back
+    search_at: Option<Instant>,
+    live: bool,
+    status: String,
+    image_info: HashMap<String, String>,
+    /// Only used when the daemon isn't running, its content is lost when this exits on some platforms
+    clipboard: Option<arboard::Clipboard>,
+}
+
+fn request(
+    args: &clip_sync_config::Args,
+    method: reqwest::Method,
+    path: &str,
+) -> anyhow::Result<reqwest::RequestBuilder> {
+    let url = format!("{}api/{}", server_url(args)?, path);
+    Ok(with_secret(
+        args,
+        reqwest::Client::new().request(method, url),
+    ))
+}
+
+async fn fetch_devices(args: &clip_sync_config::Args) -> anyhow::Result<Vec<String>> {
+    Ok(request(args, reqwest::Method::GET, "device-list")?
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+async fn image_info(args: &clip_sync_config::Args, image: &str) -> anyhow::Result<String> {
+    let png = fetch_image(args, image, false).await?;
+    let (width, height) = image::ImageReader::new(std::io::Cursor::new(&png))
+        .with_guessed_format()?
+        .into_dimensions()?;
+    Ok(format!(
+        "{}x{}, {:.1} KB",
+        width,
+        height,
+        png.len() as f64 / 1024.0
+    ))
+}
+
+/// Report every entry published on the server, the entries themselves are queried again.
+async fn watch(args: clip_sync_config::Args, updates: UnboundedSender<Update>) {
+    let mut config = args.websocket_client.clone();
+    // A name of its own, the server doesn't forward entries back to the device that sent them
+    config.client_id = Some(format!(
+        "$browse-{}",
+        random_string::generate(8, "abcdefghijklmnopqrstuvwxyz0123456789")
+    ));
+    let error = match websocket_client::WebsocketClipSyncClient::connect(config).await {
+        Ok((_, mut source, _sink)) => loop {
+            match source.poll().await {
+                Ok(record) => {
+                    if updates.send(Update::Live(record.source)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => break e,
+            }
+        },
+        Err(e) => e,
+    };
+    updates
+        .send(Update::LiveStopped(format!("{:#}", error)))
+        .ok();
+}
+
+fn first_line(text: &str) -> String {
+    let line = text
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("");
+    line.trim().chars().take(200).collect()
+}
+
+fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+impl App {
+    fn new(
+        args: clip_sync_config::Args,
+        browse: BrowseArgs,
+        updates: UnboundedSender<Update>,
+    ) -> Self {
+        Self {
+            args,
+            updates,
+            mode: Mode::Normal,
+            text: browse.text.unwrap_or_default(),
+            devices: browse.from.iter().cloned().collect(),
+            device: browse.from,
+            time_range: TimeRange::All,
+            pinned_only: false,
+            page: 0,
+            total: 0,
+            entries: vec![],
+            list: ListState::default(),
+            generation: 0,
+            loading: false,
+            search_at: None,
+            live: true,
+            status: String::new(),
+            image_info: HashMap::new(),
+            clipboard: None,
+        }
+    }
+
+    fn selected(&self) -> Option<&ClipboardMessage> {
+        self.list.selected().and_then(|i| self.entries.get(i))
+    }
+
+    fn params(&self) -> client_interface::Params {
+        client_interface::Params {
+            q: Some(self.text.clone()).filter(|text| !text.trim().is_empty()),
+            from: self.device.clone(),
+            begin: self.time_range.begin(),
+            size: Some(PAGE_SIZE),
+            skip: Some(self.page * PAGE_SIZE),
+            pinned: self.pinned_only.then_some(true),
+            ..Default::default()
+        }
+    }
+
+    /// Send the query now, results of earlier queries still on the way are ignored.
+    fn search(&mut self) {
+        self.search_at = None;
+        self.generation += 1;
+        self.loading = true;
+        let generation = self.generation;
+        let query = self.params().to_query();
+        let args = self.args.clone();
+        let updates = self.updates.clone();
+        tokio::spawn(async move {
+            let result = async {
+                Ok(request(&args, reqwest::Method::GET, "query")?
+                    .query(&query)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?)
+            }
+            .await;
+            updates.send(Update::Page(generation, result)).ok();
+        });
+    }
+
+    fn search_later(&mut self, delay: Duration) {
+        self.search_at = Some(Instant::now() + delay);
+    }
+
+    /// The filters changed, start again from the first page.
+    fn filter_changed(&mut self, delay: Duration) {
+        self.page = 0;
+        self.search_later(delay);
+    }
+
+    fn load_devices(&self) {
+        let args = self.args.clone();
+        let updates = self.updates.clone();
+        tokio::spawn(async move {
+            match fetch_devices(&args).await {
+                Ok(devices) => updates.send(Update::Devices(devices)).ok(),
+                Err(e) => updates
+                    .send(Update::Changed(Err(e.context("Failed to list devices"))))
+                    .ok(),
+            };
+        });
+    }
+
+    /// Look up the image dimensions when an image is selected for the first time.
+    fn load_preview(&mut self) {
+        let Some(ServerClipboardContent::ImageUrl(image)) =
+            self.selected().map(|msg| msg.entry.content.clone())
+        else {
+            return;
+        };
+        if self.image_info.contains_key(&image) {
+            return;
+        }
+        self.image_info
+            .insert(image.clone(), "loading...".to_string());
+        let args = self.args.clone();
+        let updates = self.updates.clone();
+        tokio::spawn(async move {
+            let info = image_info(&args, &image)
+                .await
+                .unwrap_or_else(|e| format!("unknown, {:#}", e));
+            updates.send(Update::ImageInfo(image, info)).ok();
+        });
+    }
+
+    fn copy(&mut self) {
+        let Some(msg) = self.selected().cloned() else {
+            return;
+        };
+        self.status = "Copying...".to_string();
+        let args = self.args.clone();
+        let updates = self.updates.clone();
+        tokio::spawn(async move {
+            let content = crate::get::clipboard_content(&args, &msg, false).await;
+            updates.send(Update::Copy(content)).ok();
+        });
+    }
+
+    fn set_clipboard(&mut self, content: ClipboardContent) -> anyhow::Result<String> {
+        Ok(
+            if crate::get::set_clipboard(&self.args, &mut self.clipboard, content)? {
+                "Copied to the clipboard".to_string()
+            } else {
+                "Copied to the clipboard, clip-sync isn't running so it may be lost on exit"
+                    .to_string()
+            },
+        )
+    }
+
+    fn change(&mut self, method: reqwest::Method, path: String, done: String) {
+        let args = self.args.clone();
+        let updates = self.updates.clone();
+        tokio::spawn(async move {
+            let result = async {
+                request(&args, method, &path)?
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(done)
+            }
+            .await;
+            updates.send(Update::Changed(result)).ok();
+        });
+    }
+
+    fn toggle_pin(&mut self) {
+        let Some(msg) = self.selected() else {
+            return;
+        };
+        let id = msg.entry.id.clone().unwrap_or_default();
+        if msg.pinned {
+            self.change(
+                reqwest::Method::DELETE,
+                format!("entry/{}/pin", id),
+                "Unpinned".to_string(),
+            );
+        } else {
+            self.change(
+                reqwest::Method::PUT,
+                format!("entry/{}/pin", id),
+                "Pinned".to_string(),
+            );
+        }
+    }
+
+    fn select(&mut self, index: usize) {
+        if self.entries.is_empty() {
+            self.list.select(None);
+        } else {
+            self.list.select(Some(index.min(self.entries.len() - 1)));
+            self.load_preview();
+        }
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        let index = self.list.selected().unwrap_or(0);
+        self.select(index.saturating_add_signed(delta));
+    }
+
+    fn pages(&self) -> usize {
+        self.total.div_ceil(PAGE_SIZE).max(1)
+    }
+
+    fn turn_page(&mut self, forward: bool) {
+        if forward && self.page + 1 < self.pages() {
+            self.page += 1;
+        } else if !forward && self.page > 0 {
+            self.page -= 1;
+        } else {
+            return;
+        }
+        self.search();
+    }
+
+    fn on_update(&mut self, update: Update) {
+        match update {
+            Update::Page(generation, _) if generation != self.generation => {}
+            Update::Page(_, Ok(page)) => {
+                self.loading = false;
+                // Keep the selection on the same entry if it is still there
+                let selected = self.selected().and_then(|msg| msg.entry.id.clone());
+                self.total = page.total;
+                self.entries = page.data;
+                let index = selected
+                    .and_then(|id| {
+                        self.entries
+                            .iter()
+                            .position(|msg| msg.entry.id.as_ref() == Some(&id))
+                    })
+                    .or(self.list.selected())
+                    .unwrap_or(0);
+                self.select(index);
+            }
+            Update::Page(_, Err(e)) => {
+                self.loading = false;
+                self.status = format!("Search failed: {:#}", e);
+            }
+            Update::Devices(devices) => self.devices = devices,
+            Update::Live(source) => {
+                self.status = format!("New entry from {}", source);
+                if !self.devices.contains(&source) {
+                    self.devices.push(source);
+                }
+                self.search_later(REFRESH_DELAY);
+            }
+            Update::LiveStopped(reason) => {
+                self.live = false;
+                self.status = format!("Live updates stopped: {}", reason);
+            }
+            Update::ImageInfo(image, info) => {
+                self.image_info.insert(image, info);
+            }
+            Update::Copy(content) => {
+                self.status = match content.and_then(|content| self.set_clipboard(content)) {
+                    Ok(message) => message,
+                    Err(e) => format!("Copy failed: {:#}", e),
+                };
+            }
+            Update::Changed(Ok(message)) => {
+                self.status = message;
+                self.search();
+            }
+            Update::Changed(Err(e)) => self.status = format!("{:#}", e),
+        }
+    }
+
+    /// Returns false to quit.
+    fn on_key(&mut self, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return false;
+        }
+        match &self.mode {
+            Mode::ConfirmDelete(id) => {
+                if key.code == KeyCode::Char('y') {
+                    let path = format!("entry/{}", id);
+                    self.change(reqwest::Method::DELETE, path, "Deleted".to_string());
+                } else {
+                    self.status = "Not deleted".to_string();
+                }
+                self.mode = Mode::Normal;
+                return true;
+            }
+            Mode::Search => {
+                match key.code {
+                    KeyCode::Char(c) => self.text.push(c),
+                    KeyCode::Backspace => {
+                        self.text.pop();
+                    }
+                    KeyCode::Enter => self.mode = Mode::Normal,
+                    KeyCode::Esc => {
+                        self.mode = Mode::Normal;
+                        self.text.clear();
+                    }
+                    KeyCode::Up => self.move_by(-1),
+                    KeyCode::Down => self.move_by(1),
+                    _ => {}
+                }
+                if matches!(
+                    key.code,
+                    KeyCode::Char(_) | KeyCode::Backspace | KeyCode::Esc
+                ) {
+                    self.filter_changed(SEARCH_DELAY);
+                }
+                return true;
+            }
+            Mode::Normal => {}
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('/') => self.mode = Mode::Search,
+            KeyCode::Up | KeyCode::Char('k') => self.move_by(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_by(1),
+            KeyCode::Home | KeyCode::Char('g') => self.select(0),
+            KeyCode::End | KeyCode::Char('G') => self.select(usize::MAX),
+            KeyCode::PageDown | KeyCode::Char(']') => self.turn_page(true),
+            KeyCode::PageUp | KeyCode::Char('[') => self.turn_page(false),
+            KeyCode::Char('d') => {
+                // All devices, then each device in turn
+                let next = match &self.device {
+                    None => 0,
+                    Some(device) => self
+                        .devices
+                        .iter()
+                        .position(|d| d == device)
+                        .map_or(0, |i| i + 1),
+                };
+                self.device = self.devices.get(next).cloned();
+                self.filter_changed(Duration::ZERO);
+            }
+            KeyCode::Char('t') => {
+                self.time_range = self.time_range.next();
+                self.filter_changed(Duration::ZERO);
+            }
+            KeyCode::Char('P') => {
+                self.pinned_only = !self.pinned_only;
+                self.filter_changed(Duration::ZERO);
+            }
+            KeyCode::Char('r') => {
+                self.load_devices();
+                self.search();
+            }
+            KeyCode::Enter | KeyCode::Char('c') => self.copy(),
+            KeyCode::Char('p') => self.toggle_pin(),
+            KeyCode::Delete | KeyCode::Char('x') => {
+                if let Some(id) = self.selected().and_then(|msg| msg.entry.id.clone()) {
+                    self.status = "Delete this entry? (y/n)".to_string();
+                    self.mode = Mode::ConfirmDelete(id);
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn list_item(msg: &ClipboardMessage) -> ListItem<'static> {
+        let summary = match &msg.entry.content {
+            ServerClipboardContent::Text(text) => first_line(text),
+            ServerClipboardContent::ImageUrl(image) => format!("[image] {}", image),
+        };
+        ListItem::new(Line::from(vec![
+            if msg.pinned { "* " } else { "  " }.yellow(),
+            format_time(msg.timestamp).dim(),
+            " ".into(),
+            msg.entry.source.clone().cyan(),
+            " ".into(),
+            summary.into(),
+        ]))
+    }
+
+    fn preview(&self) -> Vec<Line<'static>> {
+        let Some(msg) = self.selected() else {
+            return vec![Line::from("No entry".dim())];
+        };
+        let mut lines = vec![
+            Line::from(format!("From:    {}", msg.entry.source)),
+            Line::from(format!("Time:    {}", format_time(msg.timestamp))),
+        ];
+        if let Some(channel) = &msg.entry.channel {
+            lines.push(Line::from(format!("Channel: {}", channel)));
+        }
+        if msg.pinned {
+            lines.push(Line::from("Pinned".yellow()));
+        }
+        match &msg.entry.content {
+            ServerClipboardContent::Text(text) => {
+                lines.push(Line::from(format!(
+                    "Text:    {} characters",
+                    text.chars().count()
+                )));
+                lines.push(Line::default());
+                lines.extend(
+                    text.lines()
+                        .map(|line| Line::from(line.replace('\t', "    "))),
+                );
+            }
+            ServerClipboardContent::ImageUrl(image) => {
+                lines.push(Line::from(format!("Image:   {}", image)));
+                let info = self.image_info.get(image).map_or("", String::as_str);
+                lines.push(Line::from(format!("Size:    {}", info)));
+            }
+        }
+        lines
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [search_area, body, status_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+        let [list_area, preview_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(body);
+
+        let title = format!(
+            " Search · {} · {}{} ",
+            self.device.as_deref().unwrap_or("all devices"),
+            self.time_range.label(),
+            if self.pinned_only { " · pinned" } else { "" }
+        );
+        let border = if self.mode == Mode::Search {
+            Style::new().yellow()
+        } else {
+            Style::new()
+        };
+        frame.render_widget(
+            Paragraph::new(self.text.as_str())
+                .block(Block::bordered().title(title).border_style(border)),
+            search_area,
+        );
+        if self.mode == Mode::Search {
+            let width = Line::from(self.text.as_str()).width() as u16;
+            frame.set_cursor_position((search_area.x + 1 + width, search_area.y + 1));
+        }
+
+        let title = format!(
+            " {} entries · page {}/{}{} ",
+            self.total,
+            self.page + 1,
+            self.pages(),
+            if self.loading { " · loading" } else { "" }
+        );
+        let list = List::new(self.entries.iter().map(Self::list_item))
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, list_area, &mut self.list);
+
+        frame.render_widget(
+            Paragraph::new(self.preview())
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(" Preview ")),
+            preview_area,
+        );
+
+        let status = if self.live {
+            format!("live · {}", self.status)
+        } else {
+            self.status.clone()
+        };
+        let help = match self.mode {
+            Mode::Search => "type to search · enter done · esc clear",
+            _ => "/ search · d device · t time · P pinned only · [ ] page · enter copy · p pin · x delete · r refresh · q quit",
+        };
+        frame.render_widget(
+            Paragraph::new(vec![Line::from(status), Line::from(help.dim())]),
+            status_area,
+        );
+    }
+
+    async fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        mut updates: UnboundedReceiver<Update>,
+    ) -> anyhow::Result<()> {
+        let mut events = EventStream::new();
+        self.load_devices();
+        self.search();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let search_at = self.search_at;
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                        if !self.on_key(key) {
+                            return Ok(());
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                },
+                Some(update) = updates.recv() => self.on_update(update),
+                _ = tokio::time::sleep_until(search_at.unwrap_or_else(Instant::now)), if search_at.is_some() => {
+                    self.search();
+                }
+            }
+        }
+    }
+}
+
+pub(crate) async fn run(args: &clip_sync_config::Args, browse: BrowseArgs) -> anyhow::Result<()> {
+    args.get_server_url()
+        .context("No server configured, the history is browsed on the server")?;
+    let (sender, receiver) = unbounded_channel();
+    let watcher = tokio::spawn(watch(args.clone(), sender.clone()));
+    let mut app = App::new(args.clone(), browse, sender);
+    // Log lines would be written over the screen
+    let level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, receiver).await;
+    ratatui::restore();
+    log::set_max_level(level);
+    watcher.abort();
+    result
+}
@@ -0,0 +1,317 @@
+//! `clip-sync-cli doctor`, checks the configuration and the connections step by step.
+
+use std::{future::Future, path::PathBuf, time::Duration};
+
+use serde::Serialize;
+
+/// How long a single check may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Pass,
+    Fail,
+    /// Not run because an earlier check failed
+    Skip,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    /// Run a check unless `blocked`, returns whether it passed.
+    async fn run<F>(&mut self, name: &'static str, blocked: bool, check: F) -> bool
+    where
+        F: Future<Output = anyhow::Result<String>>,
+    {
+        let (status, detail) = if blocked {
+            (Status::Skip, String::new())
+        } else {
+            match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+                Ok(Ok(detail)) => (Status::Pass, detail),
+                Ok(Err(e)) => (Status::Fail, format!("{:#}", e)),
+                Err(_) => (Status::Fail, "timed out".to_string()),
+            }
+        };
+        self.checks.push(Check {
+            name,
+            status,
+            detail,
+        });
+        status == Status::Pass
+    }
+
+    fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.status == Status::Pass)
+    }
+
+    fn print(&self) {
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Pass => "PASS",
+                Status::Fail => "FAIL",
+                Status::Skip => "SKIP",
+            };
+            if check.detail.is_empty() {
+                println!("[{}] {}", status, check.name);
+            } else {
+                println!("[{}] {}: {}", status, check.name, check.detail);
+            }
+        }
+    }
+}
+
+/// A name for the probes that is hidden from device lists and can't clash with a device.
+fn probe_id() -> String {
+    format!(
+        "$doctor-{}",
+        random_string::generate(8, "abcdefghijklmnopqrstuvwxyz0123456789")
+    )
+}
+
+/// A small image with some variation, so the PNG round trip is meaningful.
+fn probe_image() -> client_interface::ImageData {
+    let (width, height) = (8, 8);
+    client_interface::ImageData {
+        width,
+        height,
+        data: (0..width * height * 4)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect(),
+    }
+}
+
+#[cfg(feature = "websocket")]
+async fn check_websocket(report: &mut Report, args: &clip_sync_config::Args) {
+    use client_interface::ClipSyncClient;
+
+    let config = args.websocket_client.clone();
+    let server_url = args.get_server_url();
+    let url = url::Url::parse(&config.server_url);
+    let secure = matches!(
+        url.as_ref().map(|url| url.scheme()),
+        Ok("https") | Ok("wss")
+    );
+    let mut ok = report
+        .run("resolve server", false, async {
+            let url = url.as_ref().map_err(|e| anyhow::anyhow!("{}", e))?;
+            let host = url
+                .host_str()
+                .ok_or_else(|| anyhow::anyhow!("No host in {}", url))?;
+            let port = url.port_or_known_default().unwrap_or(80);
+            let addrs: Vec<String> = tokio::net::lookup_host((host, port))
+                .await?
+                .map(|addr| addr.ip().to_string())
+                .collect();
+            Ok(format!("{} is {}", host, addrs.join(", ")))
+        })
+        .await;
+    let client = reqwest::Client::new();
+    let server_url = server_url.unwrap_or_default();
+    ok = report
+        .run(
+            if secure {
+                "connect with TLS"
+            } else {
+                "connect"
+            },
+            !ok,
+            async {
+                // Any answer will do, the root is only served with `web-root`
+                client.get(&server_url).send().await?;
+                Ok(format!("Reached {}", server_url))
+            },
+        )
+        .await;
+    let authorized = |request: reqwest::RequestBuilder| match &config.secret {
+        Some(secret) => request.bearer_auth(secret),
+        None => request,
+    };
+    ok = report
+        .run("authenticate", !ok, async {
+            let response = authorized(client.get(format!("{}api/device-list", server_url)))
+                .send()
+                .await?;
+            match response.status() {
+                reqwest::StatusCode::UNAUTHORIZED if config.secret.is_some() => {
+                    anyhow::bail!("The server rejected the secret")
+                }
+                reqwest::StatusCode::UNAUTHORIZED => {
+                    anyhow::bail!("The server requires a secret")
+                }
+                status if !status.is_success() => anyhow::bail!("The server answered {}", status),
+                _ => Ok(if config.secret.is_some() {
+                    "Secret accepted".to_string()
+                } else {
+                    "No secret required".to_string()
+                }),
+            }
+        })
+        .await;
+    let probe = probe_id();
+    let websocket_ok = report
+        .run("open websocket", !ok, async {
+            let mut probe_config = config.clone();
+            probe_config.client_id = Some(probe.clone());
+            let (_, _source, mut sink) =
+                websocket_client::WebsocketClipSyncClient::connect(probe_config).await?;
+            client_interface::ClipboardSink::close(&mut sink).await?;
+            Ok("Connected".to_string())
+        })
+        .await;
+    report
+        .run("image upload and download", !ok || !websocket_ok, async {
+            let image = probe_image();
+            let part = reqwest::multipart::Part::bytes(image.to_png()?).mime_str("image/png")?;
+            let response =
+                authorized(client.post(format!("{}api/upload-image/{}", server_url, probe)))
+                    .multipart(reqwest::multipart::Form::new().part("file", part))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            let name = response.text().await?;
+            let response = authorized(client.get(format!("{}api/images/{}", server_url, name)))
+                .send()
+                .await?
+                .error_for_status()?;
+            if client_interface::ImageData::from_png(&response.bytes().await?)? != image {
+                anyhow::bail!("The downloaded image is different from the uploaded one");
+            }
+            Ok(format!("Round trip through {}", name))
+        })
+        .await;
+}
+
+#[cfg(feature = "mqtt")]
+async fn check_mqtt(report: &mut Report, args: &clip_sync_config::Args) {
+    use client_interface::{ClipSyncClient, ClipboardSink, ClipboardSource};
+
+    // The probe goes to a topic of its own so other devices don't receive it
+    let topic = format!("{}/doctor", args.mqtt_client.topic());
+    let probe = probe_id();
+    // Nothing is retained or kept in a session, the probe topics are never used again
+    let config = |client_id: String| mqtt_client::MqttClientConfig {
+        mqtt_topic: Some(format!("{}/{}", topic, probe)),
+        mqtt_client_id: Some(client_id),
+        presence: Some(false),
+        home_assistant: None,
+        retain_latest: false,
+        persistent_session: false,
+        ..args.mqtt_client.clone()
+    };
+    let mut receiver = None;
+    let ok = report
+        .run("connect to MQTT broker", false, async {
+            let (_, mut source, sink) =
+                mqtt_client::MqttClipSyncClient::connect(config(format!("{}-rx", probe))).await?;
+            source.wait_subscribed().await?;
+            // The sink is kept so the connection stays open
+            receiver = Some((source, sink));
+            Ok(format!(
+                "Connected to {} and subscribed",
+                args.mqtt_client.broker()
+            ))
+        })
+        .await;
+    report
+        .run("publish and receive", !ok, async {
+            let (mut receiver, _) = receiver.take().expect("Connected before");
+            let (sender_id, mut sender_source, mut sink) =
+                mqtt_client::MqttClipSyncClient::connect(config(format!("{}-tx", probe))).await?;
+            sink.publish(Some(client_interface::ClipboardRecord {
+                source: sender_id,
+                content: client_interface::ClipboardContent::Text(probe.clone()),
+            }))
+            .await?;
+            // The sender only talks to the broker while its event loop is polled
+            let record = tokio::select! {
+                record = receiver.poll() => record?,
+                result = sender_source.poll() => anyhow::bail!("Sender failed: {:?}", result.err()),
+            };
+            if record.content != client_interface::ClipboardContent::Text(probe.clone()) {
+                anyhow::bail!("Received something else than the probe");
+            }
+            Ok(format!("Probe delivered through {}/{}", topic, probe))
+        })
+        .await;
+}
+
+fn check_clipboard() -> anyhow::Result<String> {
+    let mut clipboard = arboard::Clipboard::new()?;
+    match clipboard.get_text() {
+        Ok(text) => Ok(format!(
+            "Contains {} characters of text",
+            text.chars().count()
+        )),
+        Err(arboard::Error::ContentNotAvailable) => Ok("Contains no text".to_string()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) async fn run(config_path: Option<PathBuf>, json: bool) -> anyhow::Result<()> {
+    let mut report = Report::default();
+    let mut args = None;
+    report
+        .run("config", false, async {
+            let loaded = clip_sync_config::parse_config(config_path)?;
+            let detail = format!(
+                "{:?}, roles {}",
+                loaded.config_path,
+                loaded
+                    .roles
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            args = Some(loaded);
+            Ok(detail)
+        })
+        .await;
+    if let Some(args) = &args {
+        #[cfg(feature = "websocket")]
+        if args
+            .roles
+            .contains(&clip_sync_config::Role::WebsocketClient)
+        {
+            check_websocket(&mut report, args).await;
+        }
+        #[cfg(feature = "mqtt")]
+        if args.roles.contains(&clip_sync_config::Role::MqttClient) {
+            check_mqtt(&mut report, args).await;
+        }
+    }
+    report
+        .run("clipboard", false, async { check_clipboard() })
+        .await;
+    report
+        .run("daemon", args.is_none(), async {
+            let socket = args.as_ref().expect("Config loaded").get_control_socket();
+            clip_sync_config::control::send_request(
+                &socket,
+                &clip_sync_config::control::ControlRequest::Status,
+            )?;
+            Ok(format!("Running, control socket at {:?}", socket))
+        })
+        .await;
+
+    if json {
+        println!("{}", serde_json::to_string(&report.checks)?);
+    } else {
+        report.print();
+    }
+    if !report.passed() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+//! `clip-sync-cli get`, fetches a single entry from the history.
+
+use std::{io::Write, path::PathBuf};
+
+use anyhow::Context;
+use client_interface::{ClipboardContent, ClipboardMessage, ImageData, ServerClipboardContent};
+use clip_sync_config::control::ControlRequest;
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct GetArgs {
+    /// Only consider entries from this device
+    #[arg(short, long)]
+    from: Option<String>,
+    /// Position of the entry, 0 is the latest
+    #[arg(short, long, default_value = "0", conflicts_with = "id")]
+    index: usize,
+    /// ID of the entry, as in the output of `search --json`
+    #[arg(long)]
+    id: Option<String>,
+    /// Put the entry on the clipboard of this device instead of printing it
+    #[arg(long, default_value = "false", conflicts_with = "output")]
+    copy: bool,
+    /// File to write the entry to, omit to write to stdout, images are written as PNG
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Get the entry from the local history instead of the server
+    #[arg(long, default_value = "false")]
+    local: bool,
+}
+
+pub(crate) fn with_secret(
+    args: &clip_sync_config::Args,
+    request: reqwest::RequestBuilder,
+) -> reqwest::RequestBuilder {
+    match &args.websocket_client.secret {
+        Some(secret) => request.bearer_auth(secret),
+        None => request,
+    }
+}
+
+pub(crate) fn server_url(args: &clip_sync_config::Args) -> anyhow::Result<String> {
+    args.get_server_url()
+        .context("No server configured, use --local to read the local history")
+}
+
+async fn fetch_entry(
+    args: &clip_sync_config::Args,
+    get: &GetArgs,
+) -> anyhow::Result<ClipboardMessage> {
+    if get.local {
+        return match &get.id {
+            Some(id) => {
+                crate::local_entry(args, id)?.with_context(|| format!("No entry with ID '{}'", id))
+            }
+            None => crate::search_local(args, params(get))?
+                .into_iter()
+                .next()
+                .context("No entry found"),
+        };
+    }
+    let url = server_url(args)?;
+    let client = reqwest::Client::new();
+    if let Some(id) = &get.id {
+        let response = with_secret(args, client.get(format!("{}api/entry/{}", url, id)))
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            anyhow::bail!("No entry with ID '{}'", id);
+        }
+        return Ok(response.error_for_status()?.json().await?);
+    }
+    #[derive(serde::Deserialize)]
+    struct Response {
+        data: Vec<ClipboardMessage>,
+    }
+    let response: Response = with_secret(args, client.get(format!("{}api/query", url)))
+        .query(&params(get).to_query())
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    response.data.into_iter().next().context("No entry found")
+}
+
+fn params(get: &GetArgs) -> client_interface::Params {
+    client_interface::Params {
+        from: get.from.clone(),
+        size: Some(1),
+        skip: Some(get.index),
+        ..Default::default()
+    }
+}
+
+/// The PNG of an image entry, `image` is a path in the local history or a name on the server.
+pub(crate) async fn fetch_image(
+    args: &clip_sync_config::Args,
+    image: &str,
+    local: bool,
+) -> anyhow::Result<Vec<u8>> {
+    if local {
+        return Ok(tokio::fs::read(image).await?);
+    }
+    let url = format!("{}api/images/{}", server_url(args)?, image);
+    let response = with_secret(args, reqwest::Client::new().get(url))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// The entry as it would be put on the clipboard, images are downloaded and decoded.
+pub(crate) async fn clipboard_content(
+    args: &clip_sync_config::Args,
+    entry: &ClipboardMessage,
+    local: bool,
+) -> anyhow::Result<ClipboardContent> {
+    Ok(match &entry.entry.content {
+        ServerClipboardContent::Text(text) => ClipboardContent::Text(text.clone()),
+        ServerClipboardContent::ImageUrl(image) => ClipboardContent::Image(ImageData::from_png(
+            &fetch_image(args, image, local).await?,
+        )?),
+    })
+}
+
+/// Put `content` on the clipboard through the daemon, so it isn't published again, or directly
+/// if the daemon isn't running. Returns `false` in that case, the content may then be lost when
+/// `clipboard` is dropped, on some platforms.
+pub(crate) fn set_clipboard(
+    args: &clip_sync_config::Args,
+    clipboard: &mut Option<arboard::Clipboard>,
+    content: ClipboardContent,
+) -> anyhow::Result<bool> {
+    let content = match crate::control_request(
+        args,
+        ControlRequest::SetClipboard {
+            content: content.clone(),
+        },
+    ) {
+        Ok(_) => return Ok(true),
+        Err(_) => content.normalize(),
+    };
+    let clipboard = match clipboard {
+        Some(clipboard) => clipboard,
+        None => clipboard.insert(arboard::Clipboard::new()?),
+    };
+    match content {
+        ClipboardContent::Text(text) => clipboard.set_text(text)?,
+        ClipboardContent::Image(image) => clipboard.set_image(arboard::ImageData {
+            width: image.width,
+            height: image.height,
+            bytes: image.data.into(),
+        })?,
+    }
+    Ok(false)
+}
+
+pub(crate) async fn run(args: &clip_sync_config::Args, get: GetArgs) -> anyhow::Result<()> {
+    let entry = fetch_entry(args, &get).await?;
+    if get.copy {
+        let content = clipboard_content(args, &entry, get.local).await?;
+        if !set_clipboard(args, &mut None, content)? {
+            log::warn!("clip-sync isn't running, the clipboard may be cleared when this exits");
+        }
+        return Ok(());
+    }
+    let bytes = match &entry.entry.content {
+        ServerClipboardContent::Text(text) => text.clone().into_bytes(),
+        ServerClipboardContent::ImageUrl(image) => fetch_image(args, image, get.local).await?,
+    };
+    match get.output {
+        Some(path) => tokio::fs::write(&path, &bytes)
+            .await
+            .with_context(|| format!("Failed to write {:?}", path))?,
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            match stdout.write_all(&bytes).and_then(|_| stdout.flush()) {
+                // The reader is gone, e.g. `clip-sync-cli get | head`
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
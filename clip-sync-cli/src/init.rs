@@ -0,0 +1,309 @@
+//! `clip-sync-cli init`, writes a config file for a websocket or MQTT client.
+
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use client_interface::ClipSyncClient;
+
+/// How long the connection test may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct InitArgs {
+    /// URL of the clip-sync server, e.g. https://clip.example.com/
+    #[cfg(feature = "websocket")]
+    #[arg(long)]
+    server_url: Option<String>,
+    /// Secret of the clip-sync server
+    #[cfg(feature = "websocket")]
+    #[arg(long)]
+    secret: Option<String>,
+    /// Address of the MQTT broker, as host, host:port or a ws:// or wss:// URL
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    #[cfg_attr(feature = "websocket", arg(conflicts_with = "server_url"))]
+    mqtt_broker: Option<String>,
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    mqtt_username: Option<String>,
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    mqtt_password: Option<String>,
+    /// Name of this device, default is the hostname with a random suffix
+    #[arg(long)]
+    client_id: Option<String>,
+    /// Don't ask anything, everything comes from the flags
+    #[arg(long, default_value = "false")]
+    non_interactive: bool,
+    /// Write the config even if the connection test fails
+    #[arg(long, default_value = "false")]
+    no_check: bool,
+    /// Overwrite an existing config file
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+enum Transport {
+    #[cfg(feature = "websocket")]
+    Websocket(websocket_client::ClientConfig),
+    #[cfg(feature = "mqtt")]
+    Mqtt(Box<mqtt_client::MqttClientConfig>),
+}
+
+/// Ask on stdin, an empty answer is `default`.
+fn ask(question: &str, default: Option<&str>) -> anyhow::Result<String> {
+    match default {
+        Some(default) if !default.is_empty() => print!("{} [{}]: ", question, default),
+        _ => print!("{}: ", question),
+    }
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+        anyhow::bail!("No answer to \"{}\"", question);
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.unwrap_or_default().to_string()
+    } else {
+        answer.to_string()
+    })
+}
+
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    let answer = ask(&format!("{} [y/N]", question), None)?;
+    Ok(matches!(answer.to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn non_empty(value: String) -> Option<String> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+/// The hostname with a random suffix, so two machines with the same name don't clash.
+fn generate_client_id() -> String {
+    let hostname = gethostname::gethostname()
+        .into_string()
+        .unwrap_or("device".to_string());
+    format!(
+        "{}-{}",
+        hostname,
+        random_string::generate(6, "abcdefghijklmnopqrstuvwxyz0123456789")
+    )
+}
+
+#[cfg(feature = "mqtt")]
+fn parse_broker(broker: &str) -> anyhow::Result<(String, u16)> {
+    // Websocket URLs carry their own port
+    if broker.starts_with("ws://") || broker.starts_with("wss://") {
+        return Ok((broker.to_string(), 0));
+    }
+    match broker.rsplit_once(':') {
+        Some((host, port)) => Ok((
+            host.to_string(),
+            port.parse()
+                .with_context(|| format!("Invalid port in {:?}", broker))?,
+        )),
+        None => Ok((broker.to_string(), 1883)),
+    }
+}
+
+impl InitArgs {
+    fn transport(&self, client_id: String) -> anyhow::Result<Transport> {
+        #[cfg(feature = "websocket")]
+        if let Some(server_url) = &self.server_url {
+            return Ok(Transport::Websocket(websocket_client::ClientConfig {
+                server_url: server_url.clone(),
+                secret: self.secret.clone(),
+                client_id: Some(client_id),
+                ..Default::default()
+            }));
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(broker) = &self.mqtt_broker {
+            let (mqtt_server_addr, mqtt_server_port) = parse_broker(broker)?;
+            return Ok(Transport::Mqtt(Box::new(mqtt_client::MqttClientConfig {
+                mqtt_server_addr,
+                mqtt_server_port,
+                mqtt_username: self.mqtt_username.clone(),
+                mqtt_password: self.mqtt_password.clone(),
+                mqtt_client_id: Some(client_id),
+                ..Default::default()
+            })));
+        }
+        if self.non_interactive {
+            anyhow::bail!("Either --server-url or --mqtt-broker is required");
+        }
+        ask_transport(client_id)
+    }
+}
+
+fn ask_transport(client_id: String) -> anyhow::Result<Transport> {
+    #[cfg(all(feature = "websocket", feature = "mqtt"))]
+    let websocket = ask(
+        "Sync through a clip-sync server or an MQTT broker? (server/mqtt)",
+        Some("server"),
+    )?
+    .starts_with('s');
+    #[cfg(all(feature = "websocket", not(feature = "mqtt")))]
+    let websocket = true;
+    #[cfg(feature = "websocket")]
+    if websocket {
+        return Ok(Transport::Websocket(websocket_client::ClientConfig {
+            server_url: ask("Server URL, e.g. https://clip.example.com/", None)?,
+            secret: non_empty(ask("Secret, empty if not required", None)?),
+            client_id: Some(client_id),
+            ..Default::default()
+        }));
+    }
+    #[cfg(feature = "mqtt")]
+    {
+        let (mqtt_server_addr, mqtt_server_port) = parse_broker(&ask(
+            "MQTT broker, as host, host:port or ws(s):// URL",
+            None,
+        )?)?;
+        return Ok(Transport::Mqtt(Box::new(mqtt_client::MqttClientConfig {
+            mqtt_server_addr,
+            mqtt_server_port,
+            mqtt_username: non_empty(ask("MQTT username, empty if not required", None)?),
+            mqtt_password: non_empty(ask("MQTT password, empty if not required", None)?),
+            mqtt_client_id: Some(client_id),
+            ..Default::default()
+        })));
+    }
+    #[allow(unreachable_code)]
+    {
+        anyhow::bail!("This build supports neither the websocket nor the MQTT client")
+    }
+}
+
+/// Connect like the daemon would, including authentication.
+async fn check(transport: &Transport) -> anyhow::Result<()> {
+    match transport {
+        #[cfg(feature = "websocket")]
+        Transport::Websocket(config) => {
+            websocket_client::WebsocketClipSyncClient::connect(config.clone()).await?;
+        }
+        #[cfg(feature = "mqtt")]
+        Transport::Mqtt(config) => {
+            let (_, mut source, _sink) =
+                mqtt_client::MqttClipSyncClient::connect(*config.clone()).await?;
+            source.wait_subscribed().await?;
+        }
+    }
+    Ok(())
+}
+
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+fn render(transport: &Transport) -> String {
+    let mut lines = vec![
+        "# clip-sync, generated by `clip-sync-cli init`".to_string(),
+        "# Refer to config.toml in the clip-sync repository for all settings".to_string(),
+        String::new(),
+    ];
+    match transport {
+        #[cfg(feature = "websocket")]
+        Transport::Websocket(config) => {
+            lines.push("roles = [\"websocket-client\"]".to_string());
+            lines.push(String::new());
+            lines.push("[websocket-client]".to_string());
+            lines.push(format!("server-url = {}", quote(&config.server_url)));
+            if let Some(secret) = &config.secret {
+                lines.push(format!("secret = {}", quote(secret)));
+            }
+            if let Some(client_id) = &config.client_id {
+                lines.push(format!("client-id = {}", quote(client_id)));
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        Transport::Mqtt(config) => {
+            lines.push("roles = [\"mqtt-client\"]".to_string());
+            lines.push(String::new());
+            lines.push("[mqtt-client]".to_string());
+            lines.push(format!(
+                "mqtt-server-addr = {}",
+                quote(&config.mqtt_server_addr)
+            ));
+            if !config.is_websocket() {
+                lines.push(format!("mqtt-server-port = {}", config.mqtt_server_port));
+            }
+            if let Some(username) = &config.mqtt_username {
+                lines.push(format!("mqtt-username = {}", quote(username)));
+            }
+            if let Some(password) = &config.mqtt_password {
+                lines.push(format!("mqtt-password = {}", quote(password)));
+            }
+            if let Some(client_id) = &config.mqtt_client_id {
+                lines.push(format!("mqtt-client-id = {}", quote(client_id)));
+            }
+        }
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+fn write_config(path: &Path, config: &str) -> anyhow::Result<()> {
+    let args: clip_sync_config::Args = toml::from_str(config)?;
+    let problems = args.validate();
+    if let Some(problem) = problems.first() {
+        anyhow::bail!("Invalid setting `{}`: {}", problem.key, problem.message);
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, config).with_context(|| format!("Failed to write {:?}", path))?;
+    // The config may contain secrets
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn run(init: InitArgs, config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    let path = config_path.unwrap_or_else(clip_sync_config::get_config_file);
+    if path.exists() && !init.force {
+        if init.non_interactive {
+            anyhow::bail!("{:?} already exists, use --force to overwrite it", path);
+        }
+        if !confirm(&format!("{:?} already exists, overwrite it?", path))? {
+            return Ok(());
+        }
+    }
+    let client_id = match &init.client_id {
+        Some(client_id) => client_id.clone(),
+        None if init.non_interactive => generate_client_id(),
+        None => ask("Name of this device", Some(&generate_client_id()))?,
+    };
+    let transport = init.transport(client_id)?;
+    if !init.no_check {
+        println!("Checking the connection...");
+        let result = tokio::time::timeout(CHECK_TIMEOUT, check(&transport))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out")));
+        match result {
+            Ok(()) => println!("Connected"),
+            Err(e) if init.non_interactive => {
+                return Err(e.context("Connection test failed, use --no-check to skip it"))
+            }
+            Err(e) => {
+                println!("Connection test failed: {:#}", e);
+                if !confirm("Write the config anyway?")? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    write_config(&path, &render(&transport))?;
+    println!(
+        "Config written to {:?}, run `clip-sync` to start syncing",
+        path
+    );
+    Ok(())
+}
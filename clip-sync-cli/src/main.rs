@@ -3,31 +3,47 @@ use std::{path::PathBuf, sync::Arc};
 use clap::{Parser, Subcommand};
 
 use client_interface::{ClipSyncClient, ClipboardMessage, ClipboardRecord, ImageData};
-use serde::Deserialize;
-use tokio::io::AsyncWriteExt;
+use clip_sync_config::{
+    control::{ControlRequest, ControlResponse, EntrySummary, ReceivedEntry},
+    Role,
+};
 
+#[cfg(feature = "tui")]
+mod browse;
 mod client;
+mod doctor;
+#[cfg(feature = "websocket")]
+mod get;
+mod init;
+mod monitor;
+#[cfg(feature = "websocket")]
+mod search;
 
 #[derive(Debug, Subcommand)]
 enum Commands {
-    #[cfg(feature = "websocket")]
+    /// Create the config file, asking for the server or MQTT broker to use
+    Init(init::InitArgs),
+    /// Check the config, the connection to the server or MQTT broker, and the clipboard
+    Doctor,
+    /// List the devices known to the server, or that announced themselves on the MQTT topic
+    #[cfg(any(feature = "websocket", feature = "mqtt"))]
     #[command(aliases = &["l"])]
     ListDevices {
         #[arg(short, long, default_value = "false")]
         online_only: bool,
     },
+    /// Search the history
     #[cfg(feature = "websocket")]
     #[command(aliases = &["s"])]
-    Search {
-        #[clap(index = 1)]
-        text: Option<String>,
-        #[arg(short, long)]
-        skip: Option<usize>,
-        #[arg(short, long)]
-        limit: Option<usize>,
-        #[arg(short, long)]
-        device: Vec<String>,
-    },
+    Search(search::SearchArgs),
+    /// Browse the history on the server in a full-screen view
+    #[cfg(feature = "tui")]
+    #[command(aliases = &["b", "tui"])]
+    Browse(browse::BrowseArgs),
+    /// Print an entry from the history, or put it on the clipboard
+    #[cfg(feature = "websocket")]
+    #[command(aliases = &["paste", "g"])]
+    Get(get::GetArgs),
     /// Send text to the server
     #[command(arg_required_else_help = true, aliases = &["text", "t"])]
     SendText {
@@ -44,29 +60,23 @@ enum Commands {
     },
     /// Monitor clipboard content
     #[command(aliases = &["mon", "m"])]
-    Monitor {
-        /// Path to file to write clipboard content to, omit to write to stdout
-        #[arg(short, long)]
-        output: Option<PathBuf>,
-        /// Directory to write images to, omit to ignore images
-        #[arg(short, long)]
-        image_dir: Option<PathBuf>,
-        /// Whether to include timestamp in output
-        #[arg(short, long, default_value = "true")]
-        timestamp: bool,
-        /// Whether to include source in output
-        #[arg(short, long, default_value = "true")]
-        source: bool,
-        /// Whether to escape special characters
-        #[arg(short, long, default_value = "false")]
-        escape: bool,
-    },
+    Monitor(monitor::MonitorArgs),
+    /// Show the status of the running daemon
+    Status,
+    /// Pause syncing in the running daemon
+    Pause,
+    /// Resume syncing in the running daemon
+    Resume,
+    /// Make the running daemon send the current clipboard content again
+    Resend,
+    /// Make the running daemon reload its configuration
+    Reload,
 }
 
 #[derive(Debug, Parser)] // requires `derive` feature
 struct Cli {
     /// Path to config file
-    #[arg(short, long = "config")]
+    #[arg(short, long = "config", env = "CLIPSYNC_CONFIG")]
     config_path: Option<std::path::PathBuf>,
     /// Whether to output JSON
     #[arg(short, long, default_value = "false")]
@@ -79,6 +89,102 @@ struct Cli {
     command: Commands,
 }
 
+/// Search the local history recorded by the `clip-sync` daemon on this device.
+#[cfg(all(feature = "websocket", feature = "history"))]
+fn search_local(
+    args: &clip_sync_config::Args,
+    params: client_interface::Params,
+) -> anyhow::Result<Vec<ClipboardMessage>> {
+    let Some(history) = &args.history else {
+        anyhow::bail!("Local history is not configured");
+    };
+    let search = websocket_server::Search::open(Some(history.index_path()))?;
+    let mut result = search.query(params.into())?;
+    for msg in result.data.iter_mut() {
+        localize_image(history, msg);
+    }
+    Ok(result.data)
+}
+
+/// Get an entry from the local history by its ID.
+#[cfg(all(feature = "websocket", feature = "history"))]
+fn local_entry(
+    args: &clip_sync_config::Args,
+    id: &str,
+) -> anyhow::Result<Option<ClipboardMessage>> {
+    let Some(history) = &args.history else {
+        anyhow::bail!("Local history is not configured");
+    };
+    let search = websocket_server::Search::open(Some(history.index_path()))?;
+    let mut entry = search.get_entry_by_id(id)?;
+    if let Some(msg) = entry.as_mut() {
+        localize_image(history, msg);
+    }
+    Ok(entry)
+}
+
+/// Images are stored locally, point to where they are.
+#[cfg(all(feature = "websocket", feature = "history"))]
+fn localize_image(history: &clip_sync_config::HistoryConfig, msg: &mut ClipboardMessage) {
+    if let client_interface::ServerClipboardContent::ImageUrl(url) = &mut msg.entry.content {
+        *url = history
+            .image_path()
+            .join(&url)
+            .to_string_lossy()
+            .to_string();
+    }
+}
+
+#[cfg(all(feature = "websocket", not(feature = "history")))]
+fn search_local(
+    _args: &clip_sync_config::Args,
+    _params: client_interface::Params,
+) -> anyhow::Result<Vec<ClipboardMessage>> {
+    anyhow::bail!("Local history is not supported by this build")
+}
+
+#[cfg(all(feature = "websocket", not(feature = "history")))]
+fn local_entry(
+    _args: &clip_sync_config::Args,
+    _id: &str,
+) -> anyhow::Result<Option<ClipboardMessage>> {
+    anyhow::bail!("Local history is not supported by this build")
+}
+
+/// Send a request to the running `clip-sync` daemon.
+fn control_request(
+    args: &clip_sync_config::Args,
+    request: ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    match clip_sync_config::control::send_request(&args.get_control_socket(), &request)? {
+        ControlResponse::Error { message } => Err(anyhow::anyhow!(message)),
+        response => Ok(response),
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+fn format_entry(entry: &ReceivedEntry) -> String {
+    let content = match &entry.content {
+        EntrySummary::Text(text) => serde_json::to_string(text).unwrap_or_default(),
+        EntrySummary::Image { width, height } => format!("<image {}x{}>", width, height),
+    };
+    format!(
+        "{} from {}: {}",
+        format_timestamp(entry.timestamp),
+        entry.source,
+        content
+    )
+}
+
 async fn start_msg_client(
     args: &clip_sync_config::Args,
 ) -> anyhow::Result<(
@@ -93,7 +199,7 @@ async fn start_msg_client(
 
     let shutdown = Arc::new(std::sync::atomic::AtomicBool::new(false));
 
-    let (client_id, join_handler) = if args.roles.contains(&("websocket-client").to_string()) {
+    let (client_id, join_handler) = if args.roles.contains(&Role::WebsocketClient) {
         args.websocket_client.client_id = Some(
             args.websocket_client
                 .client_id
@@ -124,12 +230,15 @@ async fn start_msg_client(
         )
     } else {
         #[cfg(feature = "mqtt")]
-        if args.roles.contains(&("mqtt-client").to_string()) {
+        if args.roles.contains(&Role::MqttClient) {
             args.mqtt_client.mqtt_client_id = Some(
                 args.mqtt_client
                     .mqtt_client_id
                     .unwrap_or("$cli".to_string()),
             );
+            // Short-lived, it would only leave an `offline` status and a stale device behind
+            args.mqtt_client.presence = Some(false);
+            args.mqtt_client.home_assistant = None;
             let (client_id, source, sink) =
                 mqtt_client::MqttClipSyncClient::connect(args.mqtt_client).await?;
             let client_id_clone = client_id.clone();
@@ -160,6 +269,38 @@ async fn start_msg_client(
     Ok((client_id, sender, receiver, join_handler))
 }
 
+#[cfg(any(feature = "websocket", feature = "mqtt"))]
+async fn list_devices(
+    args: &clip_sync_config::Args,
+    online_only: bool,
+) -> anyhow::Result<Vec<String>> {
+    #[cfg(feature = "websocket")]
+    if let Some(url) = args.get_server_url() {
+        let url = if online_only {
+            format!("{}api/online-device-list", url)
+        } else {
+            format!("{}api/device-list", url)
+        };
+        let client = reqwest::Client::new();
+        return Ok(client.get(&url).send().await?.json().await?);
+    }
+    #[cfg(feature = "mqtt")]
+    if args.roles.contains(&Role::MqttClient) {
+        let devices = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            mqtt_client::list_devices(&args.mqtt_client),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out connecting to the MQTT broker"))??;
+        return Ok(devices
+            .into_iter()
+            .filter(|(_, online)| *online || !online_only)
+            .map(|(device, _)| device)
+            .collect());
+    }
+    Ok(vec![])
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
@@ -167,78 +308,37 @@ async fn main() -> anyhow::Result<()> {
         .filter_level(cli.verbose.log_level_filter())
         .filter_module("tantivy", log::LevelFilter::Warn) // Tantivy is too talky at the INFO level
         .init();
+    // There is no config to read yet
+    let command = match cli.command {
+        Commands::Init(init) => return init::run(init, cli.config_path).await,
+        // Problems with the config are part of the report
+        Commands::Doctor => return doctor::run(cli.config_path, cli.json).await,
+        command => command,
+    };
     let args = clip_sync_config::parse_config(cli.config_path)?;
 
-    match cli.command {
-        #[cfg(feature = "websocket")]
+    match command {
+        #[cfg(any(feature = "websocket", feature = "mqtt"))]
         Commands::ListDevices { online_only } => {
-            if let Some(url) = args.get_server_url() {
-                let url = if online_only {
-                    format!("{}api/online-device-list", url)
-                } else {
-                    format!("{}api/device-list", url)
-                };
-                let client = reqwest::Client::new();
-                let resp: Vec<String> = client.get(&url).send().await?.json().await?;
-                let devices: Vec<String> =
-                    resp.into_iter().filter(|d| !d.starts_with('$')).collect();
-                if cli.json {
-                    println!("{}", serde_json::to_string(&devices)?);
-                } else {
-                    for device in devices {
-                        println!("{}", device);
-                    }
+            let devices = list_devices(&args, online_only).await?;
+            let devices: Vec<String> = devices
+                .into_iter()
+                .filter(|d| !d.starts_with('$'))
+                .collect();
+            if cli.json {
+                println!("{}", serde_json::to_string(&devices)?);
+            } else {
+                for device in devices {
+                    println!("{}", device);
                 }
             }
         }
         #[cfg(feature = "websocket")]
-        Commands::Search {
-            text,
-            skip,
-            limit,
-            device,
-        } => {
-            if let Some(url) = args.get_server_url() {
-                let url = format!("{}api/query", url);
-                let client = reqwest::Client::new();
-                let param = client_interface::Params {
-                    q: text,
-                    from: if device.is_empty() {
-                        None
-                    } else {
-                        Some(device.join(","))
-                    },
-                    begin: None,
-                    end: None,
-                    size: limit,
-                    skip,
-                    sort: None,
-                }
-                .to_query();
-                #[allow(unused)]
-                #[derive(Debug, Clone, Default, Deserialize)]
-                struct Response {
-                    total: usize,
-                    skip: usize,
-                    data: Vec<ClipboardMessage>,
-                }
-                let resp: Response = client.get(&url).query(&param).send().await?.json().await?;
-                if cli.json {
-                    println!("{}", serde_json::to_string(&resp.data)?);
-                } else {
-                    for record in resp.data {
-                        match &record.entry.content {
-                            client_interface::ServerClipboardContent::Text(text) => {
-                                println!("{}", text);
-                            }
-                            client_interface::ServerClipboardContent::ImageUrl(image) => {
-                                println!("{}", image);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        Commands::Search(search) => search::run(&args, search, cli.json).await?,
+        #[cfg(feature = "tui")]
+        Commands::Browse(browse) => browse::run(&args, browse).await?,
+        #[cfg(feature = "websocket")]
+        Commands::Get(get) => get::run(&args, get).await?,
         Commands::SendText { text_or_file } => {
             let (client_id, sender, mut receiver, join_handler) = start_msg_client(&args).await?;
             if text_or_file.starts_with('@') {
@@ -291,53 +391,39 @@ async fn main() -> anyhow::Result<()> {
             receiver.close();
             join_handler.await?;
         }
-        Commands::Monitor {
-            output,
-            image_dir,
-            timestamp,
-            source,
-            escape,
-        } => {
-            let (_, _, mut receiver, _) = start_msg_client(&args).await?;
-
-            let output = output.unwrap_or_else(|| PathBuf::from("/dev/stdout"));
-            let mut image_index = 0;
-            let mut output = tokio::fs::File::create(output).await?;
-            loop {
-                let Some(record) = receiver.recv().await else {
-                    break;
-                };
-                match record.content {
-                    client_interface::ClipboardContent::Text(text) => {
-                        let mut v = vec![];
-                        if timestamp {
-                            v.push(chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
-                        }
-                        if source {
-                            v.push(record.source);
-                        }
-                        if escape {
-                            v.push(serde_json::to_string(&text)?);
-                        } else {
-                            v.push(text);
-                        }
-                        let text = if escape { v.join("\t") } else { v.join("\n") };
-                        output.write_all(text.as_bytes()).await?;
-                        output.write_all(b"\n").await?;
-                    }
-                    client_interface::ClipboardContent::Image(image) => {
-                        if let Some(image_dir) = &image_dir {
-                            let path = image_dir.join(format!("{}.png", image_index));
-                            image_index += 1;
-                            let mut file = tokio::fs::File::create(path).await?;
-                            file.write_all(&image.to_png()?).await?;
-                        } else {
-                            // Ignore image
-                        }
-                    }
+        Commands::Monitor(monitor) => monitor::run(&args, monitor).await?,
+        Commands::Status => {
+            let response = control_request(&args, ControlRequest::Status)?;
+            if cli.json {
+                println!("{}", serde_json::to_string(&response)?);
+            } else if let ControlResponse::Status {
+                paused,
+                roles,
+                last_received,
+            } = response
+            {
+                println!("Syncing: {}", if paused { "paused" } else { "active" });
+                for (name, status) in roles {
+                    println!("{}: {}", name, status);
+                }
+                if let Some(entry) = last_received {
+                    println!("Last received: {}", format_entry(&entry));
                 }
             }
         }
+        Commands::Pause => {
+            control_request(&args, ControlRequest::Pause)?;
+        }
+        Commands::Resume => {
+            control_request(&args, ControlRequest::Resume)?;
+        }
+        Commands::Resend => {
+            control_request(&args, ControlRequest::Resend)?;
+        }
+        Commands::Reload => {
+            control_request(&args, ControlRequest::Reload)?;
+        }
+        Commands::Init(_) | Commands::Doctor => unreachable!(),
     }
     Ok(())
 }
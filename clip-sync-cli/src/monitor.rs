@@ -0,0 +1,274 @@
+//! `clip-sync-cli monitor`, writes the received entries as they arrive.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use client_interface::{ClipboardContent, ClipboardRecord};
+use log::error;
+use serde::Serialize;
+use sha2::Digest;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Format {
+    /// The text of each entry, with the flags below
+    Text,
+    /// One JSON object per entry with all metadata, images are included if --image-dir is set
+    Jsonl,
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct MonitorArgs {
+    /// Path to file to write clipboard content to, omit to write to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Directory to write images to, omit to ignore images. Files are named by their content
+    #[arg(short, long)]
+    image_dir: Option<PathBuf>,
+    #[arg(short, long, value_enum, default_value = "text")]
+    format: Format,
+    /// Whether to include timestamp in output
+    #[arg(short, long, default_value = "true")]
+    timestamp: bool,
+    /// Whether to include source in output
+    #[arg(short, long, default_value = "true")]
+    source: bool,
+    /// Whether to escape special characters
+    #[arg(short, long, default_value = "false")]
+    escape: bool,
+    /// Run this shell command for each entry, with the text or PNG on stdin and the metadata in
+    /// CLIP_SOURCE, CLIP_TYPE, CLIP_TIMESTAMP, CLIP_HASH, CLIP_IMAGE_PATH, CLIP_WIDTH and CLIP_HEIGHT
+    #[arg(long)]
+    exec: Option<String>,
+}
+
+/// The metadata of a received entry, as written by `--format jsonl`.
+#[derive(Debug, Serialize)]
+struct Entry {
+    /// RFC 3339 in local time
+    timestamp: String,
+    unix: i64,
+    source: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// SHA-512 of the text or the PNG, for text it is the ID of the entry in the history
+    hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<usize>,
+}
+
+fn digest(bytes: &[u8]) -> String {
+    let mut hasher = <sha2::Sha512 as Digest>::new();
+    hasher.update(bytes);
+    hex::encode(Into::<[u8; 64]>::into(hasher.finalize()))
+}
+
+impl Entry {
+    /// The entry and its content as bytes, images are saved into `image_dir` under their hash so
+    /// they are written once and never overwrite other images.
+    async fn new(
+        record: ClipboardRecord,
+        image_dir: Option<&Path>,
+    ) -> anyhow::Result<(Self, Vec<u8>)> {
+        let now = chrono::Local::now();
+        let mut entry = Entry {
+            timestamp: now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+            unix: now.timestamp(),
+            source: record.source,
+            kind: "text",
+            hash: String::new(),
+            text: None,
+            image: None,
+            width: None,
+            height: None,
+        };
+        let bytes = match record.content {
+            ClipboardContent::Text(text) => {
+                entry.hash = digest(text.as_bytes());
+                entry.text = Some(text.clone());
+                text.into_bytes()
+            }
+            ClipboardContent::Image(image) => {
+                let png = image.to_png()?;
+                entry.kind = "image";
+                entry.hash = digest(&png);
+                entry.width = Some(image.width);
+                entry.height = Some(image.height);
+                if let Some(image_dir) = image_dir {
+                    let path = image_dir.join(format!("{}.png", &entry.hash[0..32]));
+                    if !path.exists() {
+                        tokio::fs::create_dir_all(image_dir).await?;
+                        tokio::fs::write(&path, &png)
+                            .await
+                            .with_context(|| format!("Failed to write {:?}", path))?;
+                    }
+                    entry.image = Some(path);
+                }
+                png
+            }
+        };
+        Ok((entry, bytes))
+    }
+
+    fn env(&self) -> Vec<(&'static str, String)> {
+        // Not CLIPSYNC_*, those would be read as settings if the command runs clip-sync-cli
+        let mut env = vec![
+            ("CLIP_SOURCE", self.source.clone()),
+            ("CLIP_TYPE", self.kind.to_string()),
+            ("CLIP_TIMESTAMP", self.unix.to_string()),
+            ("CLIP_HASH", self.hash.clone()),
+        ];
+        if let Some(image) = &self.image {
+            env.push(("CLIP_IMAGE_PATH", image.to_string_lossy().to_string()));
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            env.push(("CLIP_WIDTH", width.to_string()));
+            env.push(("CLIP_HEIGHT", height.to_string()));
+        }
+        env
+    }
+
+    /// The format from before `--format`, images are not written.
+    fn text(&self, args: &MonitorArgs) -> anyhow::Result<Option<String>> {
+        let Some(text) = &self.text else {
+            return Ok(None);
+        };
+        let mut v = vec![];
+        if args.timestamp {
+            let time = chrono::DateTime::from_timestamp(self.unix, 0)
+                .unwrap_or_default()
+                .with_timezone(&chrono::Local);
+            v.push(time.format("%Y-%m-%d %H:%M:%S").to_string());
+        }
+        if args.source {
+            v.push(self.source.clone());
+        }
+        if args.escape {
+            v.push(serde_json::to_string(text)?);
+        } else {
+            v.push(text.clone());
+        }
+        Ok(Some(if args.escape {
+            v.join("\t")
+        } else {
+            v.join("\n")
+        }))
+    }
+}
+
+/// Run `command` with `input` on stdin, entries are handled one at a time so hooks see them in
+/// order.
+async fn run_hook(command: &str, entry: &Entry, input: &[u8]) -> anyhow::Result<()> {
+    #[cfg(windows)]
+    let mut child = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    child.arg("/C");
+    #[cfg(not(windows))]
+    let mut child = tokio::process::Command::new("sh");
+    #[cfg(not(windows))]
+    child.arg("-c");
+    let mut child = child
+        .arg(command)
+        .envs(entry.env())
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", command))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    match stdin.write_all(input).await {
+        // The command doesn't read its input
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        result => result?,
+    }
+    drop(stdin);
+    let status = child.wait().await?;
+    if !status.success() {
+        anyhow::bail!("{:?} failed, {}", command, status);
+    }
+    Ok(())
+}
+
+pub(crate) async fn run(args: &clip_sync_config::Args, monitor: MonitorArgs) -> anyhow::Result<()> {
+    let (_, _, mut receiver, _) = crate::start_msg_client(args).await?;
+
+    let output = monitor
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("/dev/stdout"));
+    let mut output = tokio::fs::File::create(output).await?;
+    while let Some(record) = receiver.recv().await {
+        let (entry, content) = Entry::new(record, monitor.image_dir.as_deref()).await?;
+        let line = match monitor.format {
+            Format::Text => entry.text(&monitor)?,
+            Format::Jsonl => Some(serde_json::to_string(&entry)?),
+        };
+        if let Some(line) = line {
+            output.write_all(line.as_bytes()).await?;
+            output.write_all(b"\n").await?;
+            output.flush().await?;
+        }
+        if let Some(command) = &monitor.exec {
+            // A failing hook shouldn't stop the monitor
+            if let Err(e) = run_hook(command, &entry, &content).await {
+                error!("{:#}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_entry() {
+        let dir = std::env::temp_dir().join(format!("clip-sync-monitor-{}", std::process::id()));
+        let image = client_interface::ImageData {
+            width: 2,
+            height: 1,
+            data: vec![255, 0, 0, 255, 0, 255, 0, 255],
+        };
+        let record = ClipboardRecord {
+            source: "laptop".to_string(),
+            content: ClipboardContent::Image(image.clone()),
+        };
+        let (entry, png) = Entry::new(record.clone(), Some(&dir)).await.unwrap();
+        let path = entry.image.clone().unwrap();
+        assert_eq!(path, dir.join(format!("{}.png", &digest(&png)[0..32])));
+        assert_eq!(std::fs::read(&path).unwrap(), png);
+        // The same image goes to the same file
+        let (again, _) = Entry::new(record, Some(&dir)).await.unwrap();
+        assert_eq!(again.image, Some(path));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let json: serde_json::Value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["type"], "image");
+        assert_eq!(json["source"], "laptop");
+        assert_eq!(json["width"], 2);
+        assert!(json.get("text").is_none());
+
+        #[cfg(unix)]
+        {
+            let record = ClipboardRecord {
+                source: "laptop".to_string(),
+                content: ClipboardContent::Text("hello".to_string()),
+            };
+            let (entry, text) = Entry::new(record, None).await.unwrap();
+            let out = std::env::temp_dir().join(format!("clip-sync-hook-{}", std::process::id()));
+            let command = format!(
+                "printf '%s %s ' \"$CLIP_SOURCE\" \"$CLIP_TYPE\" > {0}; cat >> {0}",
+                out.display()
+            );
+            run_hook(&command, &entry, &text).await.unwrap();
+            assert_eq!(std::fs::read_to_string(&out).unwrap(), "laptop text hello");
+            std::fs::remove_file(&out).unwrap();
+            assert!(run_hook("exit 3", &entry, &text).await.is_err());
+        }
+    }
+}
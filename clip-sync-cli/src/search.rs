@@ -0,0 +1,374 @@
+//! `clip-sync-cli search`, queries the history on the server or on this device.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, TimeZone};
+use client_interface::{ClipboardMessage, ServerClipboardContent};
+
+use crate::get::{fetch_image, with_secret};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Sort {
+    /// Newest first
+    Time,
+    /// Best match first
+    Score,
+    /// Oldest first
+    Oldest,
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct SearchArgs {
+    #[clap(index = 1)]
+    text: Option<String>,
+    #[arg(short, long)]
+    skip: Option<usize>,
+    #[arg(short, long)]
+    limit: Option<usize>,
+    #[arg(short, long)]
+    device: Vec<String>,
+    /// Only search entries in these channels
+    #[arg(long)]
+    channel: Vec<String>,
+    /// Only entries from this time on, e.g. 2h, 3d, yesterday, 2024-05-01 or 2024-05-01T12:00:00Z
+    #[arg(long, value_parser = parse_time)]
+    since: Option<i64>,
+    /// Only entries before this time, in the same formats as --since
+    #[arg(long, value_parser = parse_time)]
+    until: Option<i64>,
+    #[arg(long, value_enum, default_value = "time")]
+    sort: Sort,
+    /// Print each entry with this template, e.g. '{timestamp} {source} {text}'.
+    /// Fields are id, source, timestamp, unix, channel, pinned, text, image and content
+    #[arg(long)]
+    format: Option<String>,
+    /// Save images into this directory, their paths are printed instead of their names
+    #[arg(long)]
+    download: Option<PathBuf>,
+    /// Search the local history instead of the server
+    #[arg(long, default_value = "false")]
+    local: bool,
+}
+
+fn parse_time(value: &str) -> Result<i64, String> {
+    parse_time_at(value, chrono::Local::now())
+}
+
+/// Parse an RFC 3339 time, a date, `now`, `today`, `yesterday` or a duration before `now` like
+/// `2h`, `1d12h` or `30m ago`.
+fn parse_time_at<Tz: TimeZone>(value: &str, now: DateTime<Tz>) -> Result<i64, String> {
+    let value = value.trim();
+    let midnight = |date: NaiveDate| {
+        date.and_hms_opt(0, 0, 0)
+            .and_then(|time| time.and_local_timezone(now.timezone()).earliest())
+            .map(|time| time.timestamp())
+    };
+    let time = match value {
+        "now" => Some(now.timestamp()),
+        "today" => midnight(now.date_naive()),
+        "yesterday" => now.date_naive().pred_opt().and_then(midnight),
+        _ => DateTime::parse_from_rfc3339(value)
+            .map(|time| time.timestamp())
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                    .ok()
+                    .and_then(midnight)
+            })
+            .or_else(|| {
+                let duration = value.strip_suffix("ago").unwrap_or(value);
+                parse_duration(duration).map(|seconds| now.timestamp() - seconds)
+            }),
+    };
+    time.ok_or_else(|| {
+        format!(
+            "Invalid time {:?}, expected e.g. 2h, 3d, yesterday, 2024-05-01 or 2024-05-01T12:00:00Z",
+            value
+        )
+    })
+}
+
+/// Seconds in a duration like `90s`, `2h` or `1d 12h`.
+fn parse_duration(value: &str) -> Option<i64> {
+    let mut seconds = 0i64;
+    let mut number = String::new();
+    let mut units = 0;
+    for c in value.chars().filter(|c| !c.is_whitespace()) {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 24 * 3600,
+            'w' => 7 * 24 * 3600,
+            _ => return None,
+        };
+        seconds = seconds.checked_add(number.parse::<i64>().ok()?.checked_mul(unit)?)?;
+        number.clear();
+        units += 1;
+    }
+    (number.is_empty() && units > 0).then_some(seconds)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Id,
+    Source,
+    Timestamp,
+    Unix,
+    Channel,
+    Pinned,
+    Text,
+    Image,
+    /// The text, or the image
+    Content,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// Split a template into literals and fields, `{{` and `}}` are literal braces and `\t`, `\n`
+/// and `\\` are escapes.
+fn parse_template(template: &str) -> anyhow::Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => literal.push('\t'),
+                Some('n') => literal.push('\n'),
+                Some('\\') => literal.push('\\'),
+                Some(c) => {
+                    literal.push('\\');
+                    literal.push(c);
+                }
+                None => literal.push('\\'),
+            },
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+                if !closed {
+                    anyhow::bail!("Unclosed {{ in the format");
+                }
+                let field = match name.as_str() {
+                    "id" => Field::Id,
+                    "source" => Field::Source,
+                    "timestamp" => Field::Timestamp,
+                    "unix" => Field::Unix,
+                    "channel" => Field::Channel,
+                    "pinned" => Field::Pinned,
+                    "text" => Field::Text,
+                    "image" => Field::Image,
+                    "content" => Field::Content,
+                    _ => anyhow::bail!("Unknown field {{{}}} in the format", name),
+                };
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Field(field));
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+fn render(template: &[Segment], msg: &ClipboardMessage) -> String {
+    let (text, image) = match &msg.entry.content {
+        ServerClipboardContent::Text(text) => (text.as_str(), ""),
+        ServerClipboardContent::ImageUrl(image) => ("", image.as_str()),
+    };
+    let mut output = String::new();
+    for segment in template {
+        match segment {
+            Segment::Literal(literal) => output.push_str(literal),
+            Segment::Field(field) => match field {
+                Field::Id => output.push_str(msg.entry.id.as_deref().unwrap_or_default()),
+                Field::Source => output.push_str(&msg.entry.source),
+                Field::Timestamp => output.push_str(
+                    &chrono::DateTime::from_timestamp(msg.timestamp, 0)
+                        .map(|time| {
+                            time.with_timezone(&chrono::Local)
+                                .format("%Y-%m-%d %H:%M:%S")
+                                .to_string()
+                        })
+                        .unwrap_or_default(),
+                ),
+                Field::Unix => output.push_str(&msg.timestamp.to_string()),
+                Field::Channel => output.push_str(msg.entry.channel.as_deref().unwrap_or_default()),
+                Field::Pinned => output.push_str(&msg.pinned.to_string()),
+                Field::Text => output.push_str(text),
+                Field::Image => output.push_str(image),
+                Field::Content => output.push_str(if image.is_empty() { text } else { image }),
+            },
+        }
+    }
+    output
+}
+
+/// Save the images of the results into `dir`, and point the results to the saved files.
+async fn download(
+    args: &clip_sync_config::Args,
+    data: &mut [ClipboardMessage],
+    dir: &Path,
+    local: bool,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create {:?}", dir))?;
+    for msg in data.iter_mut() {
+        if let ServerClipboardContent::ImageUrl(image) = &mut msg.entry.content {
+            let name = Path::new(image.as_str())
+                .file_name()
+                .with_context(|| format!("Invalid image name {:?}", image))?;
+            let path = dir.join(name);
+            let png = fetch_image(args, image, local)
+                .await
+                .with_context(|| format!("Failed to download {}", image))?;
+            tokio::fs::write(&path, png)
+                .await
+                .with_context(|| format!("Failed to write {:?}", path))?;
+            *image = path.to_string_lossy().to_string();
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn run(
+    args: &clip_sync_config::Args,
+    search: SearchArgs,
+    json: bool,
+) -> anyhow::Result<()> {
+    let template = match &search.format {
+        Some(_) if json => anyhow::bail!("--format and --json can't be used together"),
+        Some(format) => Some(parse_template(format)?),
+        None => None,
+    };
+    let params = client_interface::Params {
+        q: search.text,
+        from: if search.device.is_empty() {
+            None
+        } else {
+            Some(search.device.join(","))
+        },
+        begin: search.since,
+        end: search.until,
+        size: search.limit,
+        skip: search.skip,
+        sort: match search.sort {
+            Sort::Time => None,
+            Sort::Score => Some("score".to_string()),
+            Sort::Oldest => Some("oldest".to_string()),
+        },
+        channel: if search.channel.is_empty() {
+            None
+        } else {
+            Some(search.channel.join(","))
+        },
+        pinned: None,
+    };
+    let mut data = if search.local {
+        crate::search_local(args, params)?
+    } else if let Some(url) = args.get_server_url() {
+        let url = format!("{}api/query", url);
+        let client = reqwest::Client::new();
+        #[derive(serde::Deserialize)]
+        struct Response {
+            data: Vec<ClipboardMessage>,
+        }
+        let resp: Response = with_secret(args, client.get(&url))
+            .query(&params.to_query())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        resp.data
+    } else {
+        vec![]
+    };
+    if let Some(dir) = &search.download {
+        download(args, &mut data, dir, search.local).await?;
+    }
+    if json {
+        println!("{}", serde_json::to_string(&data)?);
+    } else if let Some(template) = template {
+        for record in data {
+            println!("{}", render(&template, &record));
+        }
+    } else {
+        for record in data {
+            match &record.entry.content {
+                ServerClipboardContent::Text(text) => {
+                    println!("{}", text);
+                }
+                ServerClipboardContent::ImageUrl(image) => {
+                    println!("{}", image);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        let now = chrono::Utc.with_ymd_and_hms(2024, 5, 2, 15, 30, 0).unwrap();
+        let at = |value| parse_time_at(value, now);
+        assert_eq!(at("now"), Ok(now.timestamp()));
+        assert_eq!(at("2h"), Ok(now.timestamp() - 7200));
+        assert_eq!(at("1d 12h ago"), Ok(now.timestamp() - 36 * 3600));
+        assert_eq!(at("90s"), Ok(now.timestamp() - 90));
+        let may_1 = chrono::Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        assert_eq!(at("yesterday"), Ok(may_1.timestamp()));
+        assert_eq!(at("2024-05-01"), Ok(may_1.timestamp()));
+        assert_eq!(at("today"), Ok(may_1.timestamp() + 24 * 3600));
+        assert_eq!(at("2024-05-01T02:00:00+02:00"), Ok(may_1.timestamp()));
+        assert!(at("").is_err());
+        assert!(at("2").is_err());
+        assert!(at("2x").is_err());
+        assert!(at("last week").is_err());
+    }
+
+    #[test]
+    fn test_template() {
+        let msg: ClipboardMessage = serde_json::from_str(
+            r#"{"id": "abc", "source": "laptop", "text": "hi", "timestamp": 0}"#,
+        )
+        .unwrap();
+        let template = parse_template(r"{source}\t{text}{image} {{{unix}}}").unwrap();
+        assert_eq!(render(&template, &msg), "laptop\thi {0}");
+        assert!(parse_template("{nope}").is_err());
+        assert!(parse_template("{text").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Protocol between the `clip-sync` daemon and `clip-sync-cli` over the local control socket.
+//!
+//! Each request and response is a single line of JSON.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use client_interface::{ClipboardContent, ConnectionStatus};
+use platform_dirs::AppDirs;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    Status,
+    Pause,
+    Resume,
+    Resend,
+    LastReceived,
+    Reload,
+    /// Put content on the clipboard of this device without publishing it
+    SetClipboard {
+        content: ClipboardContent,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntrySummary {
+    Text(String),
+    Image { width: usize, height: usize },
+}
+
+impl From<&ClipboardContent> for EntrySummary {
+    fn from(content: &ClipboardContent) -> Self {
+        match content {
+            ClipboardContent::Text(text) => EntrySummary::Text(text.clone()),
+            ClipboardContent::Image(image) => EntrySummary::Image {
+                width: image.width,
+                height: image.height,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceivedEntry {
+    pub source: String,
+    pub timestamp: i64,
+    pub content: EntrySummary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Ok,
+    Status {
+        paused: bool,
+        roles: BTreeMap<String, ConnectionStatus>,
+        last_received: Option<ReceivedEntry>,
+    },
+    LastReceived {
+        entry: Option<ReceivedEntry>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// The socket is in the runtime directory if there is one, otherwise in the data directory.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("clip-sync.sock"),
+        None => {
+            let app_dirs = AppDirs::new(Some("clip-sync"), false).unwrap();
+            app_dirs.data_dir.join("clip-sync.sock")
+        }
+    }
+}
+
+/// Send a request to the daemon listening on `path` and wait for the response.
+#[cfg(unix)]
+pub fn send_request(
+    path: &std::path::Path,
+    request: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(path).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to connect to the daemon at {:?}, is clip-sync running? ({})",
+            path,
+            e
+        )
+    })?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+#[cfg(not(unix))]
+pub fn send_request(
+    _path: &std::path::Path,
+    _request: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    anyhow::bail!("The control socket is not supported on this platform")
+}
@@ -0,0 +1,277 @@
+//! Settings are layered: defaults, then the config file, then `CLIPSYNC_*` environment
+//! variables, then `--set` flags.
+//!
+//! Environment variables name a setting in upper case with `__` between the table and the
+//! key, e.g. `CLIPSYNC_SERVER__USE_TLS=true` for `use-tls` in `[server]`. Values are read
+//! according to the type of the setting in the schema, lists are comma separated.
+
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use toml::{Table, Value};
+
+pub const ENV_PREFIX: &str = "CLIPSYNC_";
+
+/// Environment variables that are command line flags rather than settings.
+const ENV_FLAGS: &[&str] = &["CLIPSYNC_CONFIG"];
+
+/// `CLIPSYNC_MQTT_CLIENT__MQTT_PASSWORD` is `mqtt-client.mqtt-password`.
+fn env_key(name: &str) -> Option<String> {
+    let key = name.strip_prefix(ENV_PREFIX)?;
+    Some(
+        key.split("__")
+            .map(|part| part.to_lowercase().replace('_', "-"))
+            .collect::<Vec<_>>()
+            .join("."),
+    )
+}
+
+/// Follow a local `$ref` like `#/properties/mqtt-client`, tables with the same settings share them.
+fn resolve_ref<'a>(
+    schema: &'a serde_json::Value,
+    node: &'a serde_json::Value,
+) -> Option<&'a serde_json::Value> {
+    match node.get("$ref") {
+        Some(pointer) => schema.pointer(pointer.as_str()?.strip_prefix('#')?),
+        None => Some(node),
+    }
+}
+
+/// The `type` of a dotted key in the schema.
+fn setting_type<'a>(schema: &'a serde_json::Value, key: &str) -> Option<&'a str> {
+    let mut node = schema;
+    for part in key.split('.') {
+        node = resolve_ref(schema, node.get("properties")?.get(part)?)?;
+    }
+    node.get("type")?.as_str()
+}
+
+fn parse_value(schema: &serde_json::Value, key: &str, raw: &str) -> anyhow::Result<Value> {
+    let value = match setting_type(schema, key) {
+        None => bail!("Unknown setting `{}`", key),
+        Some("string") => Value::String(raw.to_string()),
+        Some("integer") => Value::Integer(
+            raw.trim()
+                .parse()
+                .with_context(|| format!("`{}` must be an integer", key))?,
+        ),
+        Some("boolean") => Value::Boolean(
+            raw.trim()
+                .parse()
+                .with_context(|| format!("`{}` must be true or false", key))?,
+        ),
+        Some("array") if raw.trim_start().starts_with('[') => {
+            toml::from_str::<Table>(&format!("value = {}", raw))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .with_context(|| format!("`{}` must be a list", key))?
+        }
+        Some("array") => Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        ),
+        Some(_) => bail!("`{}` is a table, set its keys instead", key),
+    };
+    Ok(value)
+}
+
+/// Set a dotted key, replacing its `-file` counterpart so the later layer wins.
+fn set(table: &mut Table, key: &str, value: Value) -> anyhow::Result<()> {
+    let (parent, name) = match key.rsplit_once('.') {
+        Some((parent, name)) => {
+            let parent = table
+                .entry(parent)
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .with_context(|| format!("`{}` is not a table", parent))?;
+            (parent, name)
+        }
+        None => (table, key),
+    };
+    match name.strip_suffix("-file") {
+        Some(base) => parent.remove(base),
+        None => parent.remove(&format!("{}-file", name)),
+    };
+    parent.insert(name.to_string(), value);
+    Ok(())
+}
+
+/// Replace `secret-file` style settings by the content of the file, e.g. a Docker secret.
+fn resolve_files(
+    schema: &serde_json::Value,
+    table: &mut Table,
+    prefix: &str,
+) -> anyhow::Result<()> {
+    let keys: Vec<String> = table.keys().cloned().collect();
+    for name in keys {
+        let key = format!("{}{}", prefix, name);
+        if let Some(Value::Table(inner)) = table.get_mut(&name) {
+            resolve_files(schema, inner, &format!("{}.", key))?;
+            continue;
+        }
+        let Some(base) = name.strip_suffix("-file") else {
+            continue;
+        };
+        if setting_type(schema, &format!("{}{}", prefix, base)).is_none() {
+            // e.g. `log-file` is a setting of its own
+            continue;
+        }
+        if table.contains_key(base) {
+            bail!("Only one of `{}{}` and `{}` can be set", prefix, base, key);
+        }
+        let Some(Value::String(path)) = table.remove(&name) else {
+            bail!("`{}` must be a path", key);
+        };
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read `{}` from {:?}", key, path))?;
+        let content = content.trim_end_matches(['\r', '\n']).to_string();
+        table.insert(base.to_string(), Value::String(content));
+    }
+    Ok(())
+}
+
+/// Layer `env` and `overrides` (`key=value`) over the config file in `source`.
+pub(crate) fn load_with<T: DeserializeOwned>(
+    source: &str,
+    env: impl IntoIterator<Item = (String, String)>,
+    overrides: &[String],
+) -> anyhow::Result<T> {
+    let schema: serde_json::Value = serde_json::from_str(crate::CONFIG_SCHEMA)?;
+    let file: Table = toml::from_str(source)?;
+    let mut table = file.clone();
+    let mut env: Vec<(String, String)> = env
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && !ENV_FLAGS.contains(&name.as_str()))
+        .collect();
+    env.sort();
+    for (name, raw) in env {
+        let key = env_key(&name).unwrap_or_default();
+        let value =
+            parse_value(&schema, &key, &raw).with_context(|| format!("Invalid {}", name))?;
+        set(&mut table, &key, value)?;
+    }
+    for item in overrides {
+        let (key, raw) = item
+            .split_once('=')
+            .with_context(|| format!("Expected KEY=VALUE, got `{}`", item))?;
+        let key = key.trim();
+        let value =
+            parse_value(&schema, key, raw).with_context(|| format!("Invalid --set {}", item))?;
+        set(&mut table, key, value)?;
+    }
+    resolve_files(&schema, &mut table, "")?;
+    if table == file {
+        // Errors from the file alone have line numbers
+        return Ok(toml::from_str(source)?);
+    }
+    Value::Table(table)
+        .try_into::<T>()
+        .context("Invalid setting, the environment, --set or a `-file` setting may be involved")
+}
+
+/// Layer the `CLIPSYNC_*` environment variables and `overrides` over the config file in `source`.
+pub fn load<T: DeserializeOwned>(source: &str, overrides: &[String]) -> anyhow::Result<T> {
+    load_with(source, std::env::vars(), overrides)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_layers() {
+        let source =
+            "roles = [\"server\"]\nlog-level = \"info\"\n[reconnect]\nmax-delay-secs = 10\n";
+        let args: Args = load_with(
+            source,
+            env(&[
+                ("CLIPSYNC_ROLES", "server, websocket-client"),
+                ("CLIPSYNC_LOG_LEVEL", "debug"),
+                ("CLIPSYNC_RECONNECT__MAX_DELAY_SECS", "20"),
+                ("CLIPSYNC_CONFIG", "/elsewhere.toml"),
+                ("PATH", "/bin"),
+            ]),
+            &["reconnect.max-delay-secs=30".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            args.roles,
+            vec![crate::Role::Server, crate::Role::WebsocketClient]
+        );
+        assert_eq!(args.log_level.as_deref(), Some("debug"));
+        assert_eq!(args.reconnect.max_delay_secs, 30);
+
+        let error = load_with::<Args>(
+            source,
+            env(&[("CLIPSYNC_RECONECT__MAX_DELAY_SECS", "1")]),
+            &[],
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Invalid CLIPSYNC_RECONECT__MAX_DELAY_SECS: Unknown setting `reconect.max-delay-secs`"
+        );
+        assert!(load_with::<Args>(
+            source,
+            vec![],
+            &["reconnect.max-delay-secs=soon".to_string()]
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_secret_file() {
+        let path = std::env::temp_dir().join(format!("clip-sync-secret-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\n").unwrap();
+        let source = format!(
+            "roles = [\"server\"]\n[server]\nendpoint = \"0.0.0.0:3000\"\nsecret-file = {:?}\n",
+            path
+        );
+        let args: Args = load_with(&source, vec![], &[]).unwrap();
+        assert_eq!(args.server.secret.as_deref(), Some("s3cret"));
+        // A later layer replaces the indirection
+        let args: Args =
+            load_with(&source, env(&[("CLIPSYNC_SERVER__SECRET", "other")]), &[]).unwrap();
+        assert_eq!(args.server.secret.as_deref(), Some("other"));
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_with::<Args>(&source, vec![], &[]).is_err());
+    }
+
+    #[cfg(feature = "mqtt-bridge")]
+    #[test]
+    fn test_mqtt_bridge() {
+        let path = std::env::temp_dir().join(format!("clip-sync-bridge-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\n").unwrap();
+        let source = format!(
+            "roles = [\"server\", \"mqtt-bridge\"]\n[mqtt-bridge]\nmqtt-server-addr = \"broker\"\nmqtt-password-file = {:?}\n",
+            path
+        );
+        // `[mqtt-bridge]` refers to the settings of `[mqtt-client]` in the schema
+        let args: Args = load_with(
+            &source,
+            env(&[("CLIPSYNC_MQTT_BRIDGE__MQTT_SERVER_PORT", "1883")]),
+            &["mqtt-bridge.qos=2".to_string()],
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(args.mqtt_bridge.mqtt_password.as_deref(), Some("s3cret"));
+        assert_eq!(args.mqtt_bridge.mqtt_server_port, 1883);
+        assert_eq!(args.mqtt_bridge.qos, Some(2));
+        assert!(load_with::<Args>(
+            &source,
+            env(&[("CLIPSYNC_MQTT_BRIDGE__MQTT_PORT", "1883")]),
+            &[]
+        )
+        .is_err());
+    }
+}
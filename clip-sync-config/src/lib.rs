@@ -1,32 +1,150 @@
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+pub mod control;
+pub mod layers;
+mod validate;
+
+pub use validate::ConfigProblem;
+
+/// JSON Schema of `config.toml`, for completion and validation in editors.
+pub const CONFIG_SCHEMA: &str = include_str!("../config.schema.json");
+
+use anyhow::Context;
 use clap::Parser;
+use client_interface::DeviceFilter;
+pub use client_interface::ReconnectConfig;
 use platform_dirs::AppDirs;
 use serde::Deserialize;
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutboxKeep {
+    #[default]
+    All,
+    Latest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OutboxConfig {
+    #[serde(default)]
+    pub keep: OutboxKeep,
+    #[serde(default = "default_outbox_max_entries")]
+    pub max_entries: usize,
+    pub path: Option<PathBuf>,
+}
+
+fn default_outbox_max_entries() -> usize {
+    10
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            keep: OutboxKeep::default(),
+            max_entries: default_outbox_max_entries(),
+            path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HistoryConfig {
+    pub path: PathBuf,
+}
+
+impl HistoryConfig {
+    pub fn index_path(&self) -> PathBuf {
+        self.path.join("index")
+    }
+
+    pub fn image_path(&self) -> PathBuf {
+        self.path.join("images")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Server,
+    WebsocketClient,
+    MqttClient,
+    /// Relays entries between the server and an MQTT broker, runs inside the server role
+    MqttBridge,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Server => "server",
+            Role::WebsocketClient => "websocket-client",
+            Role::MqttClient => "mqtt-client",
+            Role::MqttBridge => "mqtt-bridge",
+        }
+    }
+
+    /// Whether this build of `clip-sync` can run the role.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Role::Server => cfg!(feature = "server"),
+            Role::WebsocketClient => cfg!(feature = "websocket"),
+            Role::MqttClient => cfg!(feature = "mqtt"),
+            Role::MqttBridge => cfg!(feature = "mqtt-bridge"),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[derive(Debug, Clone, Default, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Args {
-    pub roles: Vec<String>,
+    pub roles: Vec<Role>,
     #[cfg(feature = "server")]
     #[serde(default)]
     pub server: websocket_server::ServerConfig,
     #[cfg(feature = "mqtt")]
     #[serde(default)]
     pub mqtt_client: mqtt_client::MqttClientConfig,
+    #[cfg(feature = "mqtt-bridge")]
+    #[serde(default)]
+    pub mqtt_bridge: mqtt_client::MqttClientConfig,
     #[cfg(feature = "websocket")]
     #[serde(default)]
     pub websocket_client: websocket_client::ClientConfig,
+    #[serde(flatten)]
+    pub device_filter: DeviceFilter,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    pub history: Option<HistoryConfig>,
+    pub control_socket: Option<PathBuf>,
+
+    /// The file this configuration was read from
+    #[serde(skip)]
+    pub config_path: PathBuf,
+    /// `--set` flags from the command line, applied again on reload
+    #[serde(skip)]
+    pub overrides: Vec<String>,
 
     pub log_file: Option<String>,
     pub log_level: Option<String>,
+    /// The log level from the command line, used when `log-level` isn't set
+    #[serde(skip)]
+    pub default_log_level: Option<log::LevelFilter>,
 }
 
 impl Args {
     #[cfg(feature = "websocket")]
     pub fn get_server_url(&self) -> Option<String> {
-        if self.roles.contains(&"websocket-client".to_string()) {
+        if self.roles.contains(&Role::WebsocketClient) {
             if let Ok(mut url) = url::Url::parse(&self.websocket_client.server_url) {
                 let scheme = if (url.scheme() == "wss") || url.scheme() == "https" {
                     "https"
@@ -51,41 +169,172 @@ impl Args {
     pub fn get_server_url(&self) -> Option<String> {
         None
     }
+
+    pub fn log_level_filter(&self) -> anyhow::Result<log::LevelFilter> {
+        match &self.log_level {
+            Some(log_level) => Ok(log_level.parse()?),
+            None => Ok(self.default_log_level.unwrap_or(log::LevelFilter::Info)),
+        }
+    }
+
+    pub fn get_control_socket(&self) -> PathBuf {
+        self.control_socket
+            .clone()
+            .unwrap_or_else(control::default_socket_path)
+    }
 }
 
-fn get_config_file() -> PathBuf {
+pub fn get_config_file() -> PathBuf {
     let app_dirs = AppDirs::new(Some("clip-sync"), false).unwrap();
     app_dirs.config_dir.join("config.toml")
 }
 
+/// Path and content of the config file, the default file may be missing if everything is
+/// set through the environment.
+pub fn read_config_file<P: AsRef<Path>>(
+    config_path: Option<P>,
+) -> anyhow::Result<(PathBuf, String)> {
+    let (config_path, required) = match config_path {
+        Some(path) => (path.as_ref().to_path_buf(), true),
+        None => (get_config_file(), false),
+    };
+    match std::fs::read_to_string(&config_path) {
+        Ok(config) => Ok((config_path, config)),
+        Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+            Ok((config_path, String::new()))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read config at {:?}", config_path)),
+    }
+}
+
+/// Read and validate the configuration, the default config file is used if `config_path` is `None`.
 pub fn parse_config<P: AsRef<Path>>(config_path: Option<P>) -> anyhow::Result<Args> {
-    let config_path: PathBuf = config_path
-        .map(|p| p.as_ref().to_path_buf())
-        .unwrap_or(get_config_file());
-    let config = std::fs::read_to_string(&config_path)
-        .unwrap_or_else(|_| panic!("Failed to read config at '{:?}'", config_path));
-    Ok(toml::from_str::<Args>(&config)?)
+    load_config(config_path, &[])
+}
+
+/// Like [`parse_config`], with `--set` style `key=value` overrides on top of the config file
+/// and the environment.
+pub fn load_config<P: AsRef<Path>>(
+    config_path: Option<P>,
+    overrides: &[String],
+) -> anyhow::Result<Args> {
+    let (config_path, config) = read_config_file(config_path)?;
+    let mut args = layers::load::<Args>(&config, overrides)
+        .with_context(|| format!("Invalid config at {:?}", config_path))?;
+    let problems = args.validate();
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "Invalid config at {:?}:\n{}",
+            config_path,
+            validate::describe(&config, &problems)
+        );
+    }
+    args.config_path = config_path;
+    args.overrides = overrides.to_vec();
+    Ok(args)
+}
+
+/// Read the configuration again from where `args` came from, and apply the new log level.
+///
+/// The current configuration should be kept if this fails.
+pub fn reload(args: &Args) -> anyhow::Result<Args> {
+    // The default config file stays optional
+    let config_path = Some(&args.config_path).filter(|path| **path != get_config_file());
+    let mut new_args = load_config(config_path, &args.overrides)?;
+    new_args.default_log_level = args.default_log_level;
+    log::set_max_level(new_args.log_level_filter()?);
+    Ok(new_args)
+}
+
+/// How often the configuration file is checked for changes.
+const CONFIG_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Resolves on Ctrl-C, or SIGTERM e.g. from `systemctl stop`.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Call `reload` on every SIGHUP.
+pub async fn reload_on_sighup(reload: impl Fn()) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received");
+            reload();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = reload;
+}
+
+/// Call `reload` whenever the configuration file at `path` is modified.
+pub async fn reload_on_change(reload: impl Fn(), path: PathBuf) {
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::time::sleep(CONFIG_POLL_INTERVAL).await;
+        let current = modified(&path);
+        // The file may be missing for a moment while an editor replaces it
+        if current.is_some() && current != last_modified {
+            log::info!("Configuration file {:?} changed", path);
+            reload();
+        }
+        last_modified = current.or(last_modified);
+    }
 }
 
 pub fn parse() -> anyhow::Result<Args> {
     #[derive(Debug, Clone, Parser)]
     struct Config {
-        #[arg(long = "config")]
+        #[arg(long = "config", env = "CLIPSYNC_CONFIG")]
         config_path: Option<std::path::PathBuf>,
+        /// Override a setting of the config file, e.g. `--set server.endpoint=0.0.0.0:3000`
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        overrides: Vec<String>,
         #[arg(long, default_value = "false")]
         no_tray: bool,
+        /// Validate the config file and exit
+        #[arg(long)]
+        check_config: bool,
+        /// Print the JSON Schema of the config file and exit
+        #[arg(long)]
+        config_schema: bool,
         #[command(flatten)]
         verbose: clap_verbosity_flag::Verbosity,
     }
 
     let cli = Config::parse();
-    let args = parse_config(cli.config_path)?;
-
-    let log_level = if let Some(log_level) = args.log_level.as_ref() {
-        log_level.parse()?
-    } else {
-        cli.verbose.log_level_filter()
-    };
+    if cli.config_schema {
+        println!("{}", CONFIG_SCHEMA);
+        std::process::exit(0);
+    }
+    if cli.check_config {
+        match load_config(cli.config_path, &cli.overrides) {
+            Ok(args) => {
+                println!("Config at {:?} is valid", args.config_path);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut args = load_config(cli.config_path, &cli.overrides)?;
+    args.default_log_level = Some(cli.verbose.log_level_filter());
+    let log_level = args.log_level_filter()?;
 
     let debug = log_level == log::LevelFilter::Debug
         || cli.verbose.log_level_filter() == log::LevelFilter::Trace;
@@ -116,14 +365,16 @@ pub fn parse() -> anyhow::Result<Args> {
                 }
             })
             .target(env_logger::Target::Pipe(target))
-            .filter_level(log_level)
+            .filter_level(log::LevelFilter::Trace)
             .filter_module("tantivy", log::LevelFilter::Warn) // Tantivy is too talky at the INFO level
             .init();
     } else {
         env_logger::Builder::new()
-            .filter_level(log_level)
+            .filter_level(log::LevelFilter::Trace)
             .filter_module("tantivy", log::LevelFilter::Warn) // Tantivy is too talky at the INFO level
             .init();
     }
+    // The logger lets everything through, so the level can be changed on reload
+    log::set_max_level(log_level);
     Ok(args)
 }
@@ -0,0 +1,470 @@
+//! Checks for configurations that parse fine but can't work.
+
+use std::collections::HashSet;
+
+use crate::Args;
+
+/// A setting that is invalid, `key` is a dotted path like `server.use-tls`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    fn new(key: &str, message: impl ToString) -> Self {
+        Self {
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Args {
+    /// Cross-field checks that serde can't do, e.g. `use-tls` without a certificate.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        if self.roles.is_empty() {
+            problems.push(ConfigProblem::new("roles", "no role specified"));
+        }
+        let mut seen = HashSet::new();
+        for role in &self.roles {
+            if !seen.insert(role) {
+                problems.push(ConfigProblem::new(
+                    "roles",
+                    format!("role '{}' is listed more than once", role),
+                ));
+            } else if !role.is_supported() {
+                problems.push(ConfigProblem::new(
+                    "roles",
+                    format!("role '{}' is not supported by this build", role),
+                ));
+            }
+        }
+        if let Some(log_level) = &self.log_level {
+            if log_level.parse::<log::LevelFilter>().is_err() {
+                problems.push(ConfigProblem::new(
+                    "log-level",
+                    format!(
+                        "'{}' is not one of off, error, warn, info, debug, trace",
+                        log_level
+                    ),
+                ));
+            }
+        }
+        if self.outbox.max_entries == 0 {
+            problems.push(ConfigProblem::new(
+                "outbox.max-entries",
+                "must be at least 1",
+            ));
+        }
+        #[cfg(feature = "server")]
+        if self.roles.contains(&crate::Role::Server) {
+            let server = &self.server;
+            if server.endpoint.is_empty() {
+                problems.push(ConfigProblem::new(
+                    "server",
+                    "`endpoint` is required by the server role",
+                ));
+            }
+            if server.use_tls {
+                for (key, path) in [
+                    ("cert-path", &server.cert_path),
+                    ("key-path", &server.key_path),
+                ] {
+                    match path {
+                        None => problems.push(ConfigProblem::new(
+                            "server.use-tls",
+                            format!("`{}` is required when `use-tls` is true", key),
+                        )),
+                        Some(path) if !path.is_file() => problems.push(ConfigProblem::new(
+                            &format!("server.{}", key),
+                            format!("file {:?} doesn't exist", path),
+                        )),
+                        _ => {}
+                    }
+                }
+            }
+            if server.max_upload_size == Some(0) {
+                problems.push(ConfigProblem::new(
+                    "server.max-upload-size",
+                    "must be at least 1",
+                ));
+            }
+        }
+        #[cfg(feature = "websocket")]
+        if self.roles.contains(&crate::Role::WebsocketClient) {
+            let server_url = &self.websocket_client.server_url;
+            if server_url.is_empty() {
+                problems.push(ConfigProblem::new(
+                    "websocket-client",
+                    "`server-url` is required by the websocket-client role",
+                ));
+            } else {
+                match url::Url::parse(server_url) {
+                    Ok(url) if ["ws", "wss", "http", "https"].contains(&url.scheme()) => {}
+                    Ok(url) => problems.push(ConfigProblem::new(
+                        "websocket-client.server-url",
+                        format!(
+                            "scheme '{}' is not one of ws, wss, http, https",
+                            url.scheme()
+                        ),
+                    )),
+                    Err(e) => problems.push(ConfigProblem::new(
+                        "websocket-client.server-url",
+                        format!("'{}' is not a valid URL: {}", server_url, e),
+                    )),
+                }
+            }
+            if self.websocket_client.chunk_size == Some(0) {
+                problems.push(ConfigProblem::new(
+                    "websocket-client.chunk-size",
+                    "must be at least 1",
+                ));
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        if self.roles.contains(&crate::Role::MqttClient) {
+            problems.extend(mqtt_problems("mqtt-client", &self.mqtt_client));
+        }
+        #[cfg(feature = "mqtt-bridge")]
+        if self.roles.contains(&crate::Role::MqttBridge) {
+            if !self.roles.contains(&crate::Role::Server) {
+                problems.push(ConfigProblem::new(
+                    "roles",
+                    "role 'mqtt-bridge' needs the server role",
+                ));
+            }
+            problems.extend(mqtt_problems("mqtt-bridge", &self.mqtt_bridge));
+        }
+        problems
+    }
+}
+
+/// Problems of an MQTT connection, `table` is also the name of the role using it.
+#[cfg(feature = "mqtt")]
+fn mqtt_problems(table: &str, mqtt: &mqtt_client::MqttClientConfig) -> Vec<ConfigProblem> {
+    let mut problems = vec![];
+    if mqtt.mqtt_server_addr.is_empty() {
+        problems.push(ConfigProblem::new(
+            table,
+            format!("`mqtt-server-addr` is required by the {} role", table),
+        ));
+    }
+    match mqtt.mqtt_server_addr.split_once("://") {
+        Some((scheme, _)) if !mqtt.is_websocket() => problems.push(ConfigProblem::new(
+            &format!("{}.mqtt-server-addr", table),
+            format!(
+                "scheme '{}' is not one of ws, wss, use a host name for plain MQTT",
+                scheme
+            ),
+        )),
+        None if mqtt.mqtt_server_port == 0 => problems.push(ConfigProblem::new(
+            table,
+            format!("`mqtt-server-port` is required by the {} role", table),
+        )),
+        _ => {}
+    }
+    if mqtt.cert_path.is_some() != mqtt.key_path.is_some() {
+        problems.push(ConfigProblem::new(
+            table,
+            "`cert-path` and `key-path` must be set together",
+        ));
+    }
+    for (key, path) in [
+        ("ca-path", &mqtt.ca_path),
+        ("cert-path", &mqtt.cert_path),
+        ("key-path", &mqtt.key_path),
+    ] {
+        if let Some(path) = path.as_ref().filter(|path| !path.is_file()) {
+            problems.push(ConfigProblem::new(
+                &format!("{}.{}", table, key),
+                format!("file {:?} doesn't exist", path),
+            ));
+        }
+    }
+    if mqtt.qos.is_some_and(|qos| qos > 2) {
+        problems.push(ConfigProblem::new(
+            &format!("{}.qos", table),
+            "must be 0, 1 or 2",
+        ));
+    }
+    if mqtt.keepalive_secs.is_some_and(|secs| secs < 5) {
+        problems.push(ConfigProblem::new(
+            &format!("{}.keepalive-secs", table),
+            "must be at least 5",
+        ));
+    }
+    if let Some(chunk_size) = mqtt.chunk_size {
+        if chunk_size == 0 || chunk_size >= mqtt.max_packet_size() {
+            problems.push(ConfigProblem::new(
+                &format!("{}.chunk-size", table),
+                "must be at least 1 and less than `max-packet-size`",
+            ));
+        }
+    }
+    if let Some(home_assistant) = &mqtt.home_assistant {
+        let prefix = &home_assistant.discovery_prefix;
+        if prefix.is_empty() || prefix.contains(['+', '#']) {
+            problems.push(ConfigProblem::new(
+                &format!("{}.home-assistant.discovery-prefix", table),
+                "must be a topic without wildcards",
+            ));
+        }
+    }
+    if mqtt.protocol != mqtt_client::Protocol::V5 {
+        for (key, value) in [
+            ("session-expiry-secs", mqtt.session_expiry_secs),
+            ("message-expiry-secs", mqtt.message_expiry_secs),
+        ] {
+            if value.is_some() {
+                problems.push(ConfigProblem::new(
+                    &format!("{}.{}", table, key),
+                    "needs `protocol = \"5\"`",
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Line number of `key` in the config file, or of its table if the key isn't set.
+///
+/// Only understands the plain `[table]` and `key = value` layout of `config.toml`.
+fn line_of(source: &str, key: &str) -> Option<usize> {
+    let (table, name) = key.rsplit_once('.').unwrap_or(("", key));
+    let mut current = "";
+    let mut table_line = None;
+    // A key without a dot may also be a whole table, e.g. `server`
+    let mut header_line = None;
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            current = header.trim_end_matches(']').trim();
+            if current == table {
+                table_line = Some(i + 1);
+            } else if table.is_empty() && current == name {
+                header_line = Some(i + 1);
+            }
+            continue;
+        }
+        if current != table {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix(name) {
+            if rest.trim_start().starts_with('=') {
+                return Some(i + 1);
+            }
+        }
+    }
+    table_line.or(header_line)
+}
+
+/// One problem per line, prefixed with where it is in `source`.
+pub(crate) fn describe(source: &str, problems: &[ConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(|problem| match line_of(source, &problem.key) {
+            Some(line) => format!("  line {}: {}: {}", line, problem.key, problem.message),
+            None => format!("  {}: {}", problem.key, problem.message),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(source: &str) -> Vec<String> {
+        let args: Args = toml::from_str(source).unwrap();
+        describe(source, &args.validate())
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(
+            problems("roles = []"),
+            vec!["line 1: roles: no role specified"]
+        );
+        assert!(toml::from_str::<Args>("roles = [\"sever\"]").is_err());
+        assert_eq!(
+            problems("log-level = \"loud\"\nroles = [\"server\"]\n[outbox]\nmax-entries = 0\n")
+                .iter()
+                .filter(|p| !p.contains("roles") && !p.contains("server"))
+                .collect::<Vec<_>>(),
+            vec![
+                "line 1: log-level: 'loud' is not one of off, error, warn, info, debug, trace",
+                "line 4: outbox.max-entries: must be at least 1",
+            ]
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_validate_server() {
+        let source = "roles = [\"server\"]\n\n[server]\nendpoint = \"0.0.0.0:3000\"\nuse-tls = true\ncert-path = \"/nonexistent/server.crt\"\n";
+        assert_eq!(
+            problems(source),
+            vec![
+                "line 6: server.cert-path: file \"/nonexistent/server.crt\" doesn't exist",
+                "line 5: server.use-tls: `key-path` is required when `use-tls` is true",
+            ]
+        );
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn test_validate_websocket_client() {
+        assert_eq!(
+            problems("roles = [\"websocket-client\"]\n"),
+            vec!["websocket-client: `server-url` is required by the websocket-client role"]
+        );
+        assert_eq!(
+            problems("roles = [\"websocket-client\"]\n[websocket-client]\nserver-url = \"ftp://x/\"\n"),
+            vec!["line 3: websocket-client.server-url: scheme 'ftp' is not one of ws, wss, http, https"]
+        );
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn test_validate_mqtt_client() {
+        let mqtt = |table: &str| {
+            problems(&format!(
+                "roles = [\"mqtt-client\"]\n[mqtt-client]\n{}",
+                table
+            ))
+        };
+        assert_eq!(
+            mqtt("mqtt-server-addr = \"broker\"\n"),
+            vec!["line 2: mqtt-client: `mqtt-server-port` is required by the mqtt-client role"]
+        );
+        assert!(mqtt("mqtt-server-addr = \"wss://broker/mqtt\"\n").is_empty());
+        assert_eq!(
+            mqtt("mqtt-server-addr = \"mqtts://broker\"\n"),
+            vec!["line 3: mqtt-client.mqtt-server-addr: scheme 'mqtts' is not one of ws, wss, use a host name for plain MQTT"]
+        );
+        assert_eq!(
+            mqtt("mqtt-server-addr = \"broker\"\nmqtt-server-port = 8883\nuse-tls = true\ncert-path = \"/nonexistent/client.crt\"\n"),
+            vec![
+                "line 2: mqtt-client: `cert-path` and `key-path` must be set together",
+                "line 6: mqtt-client.cert-path: file \"/nonexistent/client.crt\" doesn't exist",
+            ]
+        );
+        assert_eq!(
+            mqtt("mqtt-server-addr = \"broker\"\nmqtt-server-port = 1883\nqos = 3\nmessage-expiry-secs = 60\n"),
+            vec![
+                "line 5: mqtt-client.qos: must be 0, 1 or 2",
+                "line 6: mqtt-client.message-expiry-secs: needs `protocol = \"5\"`",
+            ]
+        );
+        assert!(mqtt("mqtt-server-addr = \"broker\"\nmqtt-server-port = 1883\nprotocol = \"5\"\nmessage-expiry-secs = 60\n").is_empty());
+        assert_eq!(
+            mqtt("mqtt-server-addr = \"broker\"\nmqtt-server-port = 1883\n[mqtt-client.home-assistant]\ndiscovery-prefix = \"ha/#\"\n"),
+            vec!["line 6: mqtt-client.home-assistant.discovery-prefix: must be a topic without wildcards"]
+        );
+        assert_eq!(
+            mqtt("mqtt-server-addr = \"broker\"\nmqtt-server-port = 1883\nmax-packet-size = 1000\nchunk-size = 1000\n"),
+            vec!["line 6: mqtt-client.chunk-size: must be at least 1 and less than `max-packet-size`"]
+        );
+    }
+
+    #[cfg(feature = "mqtt-bridge")]
+    #[test]
+    fn test_validate_mqtt_bridge() {
+        assert_eq!(
+            problems("roles = [\"mqtt-bridge\"]\n[mqtt-bridge]\nmqtt-server-addr = \"broker\"\nmqtt-server-port = 1883\n"),
+            vec!["line 1: roles: role 'mqtt-bridge' needs the server role"]
+        );
+        assert_eq!(
+            problems("roles = [\"server\", \"mqtt-bridge\"]\n[server]\nendpoint = \"0.0.0.0:3000\"\n[mqtt-bridge]\nmqtt-server-addr = \"broker\"\n"),
+            vec!["line 4: mqtt-bridge: `mqtt-server-port` is required by the mqtt-bridge role"]
+        );
+    }
+
+    /// Field names of a `deny_unknown_fields` struct, from the error for an unknown field.
+    fn fields<T: serde::de::DeserializeOwned + std::fmt::Debug>() -> Vec<String> {
+        let error = toml::from_str::<T>("__unknown = 1")
+            .unwrap_err()
+            .to_string();
+        let expected = error.split("expected").nth(1).unwrap_or_default();
+        let mut fields: Vec<String> = expected
+            .split('`')
+            .skip(1)
+            .step_by(2)
+            .map(ToString::to_string)
+            .collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema: serde_json::Value = serde_json::from_str(crate::CONFIG_SCHEMA).unwrap();
+        let properties = |table: &str| -> Vec<String> {
+            let properties = schema["properties"][table]["properties"]
+                .as_object()
+                .unwrap_or_else(|| panic!("`{}` is missing from the schema", table));
+            // `secret-file` style indirections are resolved before deserializing
+            let mut keys: Vec<String> = properties
+                .keys()
+                .filter(|key| {
+                    key.strip_suffix("-file")
+                        .map_or(true, |base| !properties.contains_key(base))
+                })
+                .cloned()
+                .collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(properties("reconnect"), fields::<crate::ReconnectConfig>());
+        assert_eq!(properties("outbox"), fields::<crate::OutboxConfig>());
+        assert_eq!(properties("history"), fields::<crate::HistoryConfig>());
+        #[cfg(feature = "server")]
+        assert_eq!(
+            properties("server"),
+            fields::<websocket_server::ServerConfig>()
+        );
+        #[cfg(feature = "websocket")]
+        assert_eq!(
+            properties("websocket-client"),
+            fields::<websocket_client::ClientConfig>()
+        );
+        #[cfg(feature = "mqtt")]
+        assert_eq!(
+            properties("mqtt-client"),
+            fields::<mqtt_client::MqttClientConfig>()
+        );
+        #[cfg(feature = "mqtt")]
+        {
+            let home_assistant =
+                &schema["properties"]["mqtt-client"]["properties"]["home-assistant"]["properties"];
+            let mut keys: Vec<String> = home_assistant
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect();
+            keys.sort();
+            assert_eq!(keys, fields::<mqtt_client::HomeAssistantConfig>());
+        }
+        let roles: Vec<&str> = schema["properties"]["roles"]["items"]["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|role| role.as_str())
+            .collect();
+        assert_eq!(
+            roles,
+            vec!["server", "websocket-client", "mqtt-client", "mqtt-bridge"]
+        );
+        // The bridge connects like the MQTT client
+        assert_eq!(
+            schema["properties"]["mqtt-bridge"]["$ref"],
+            "#/properties/mqtt-client"
+        );
+    }
+}
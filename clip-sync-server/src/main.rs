@@ -1,28 +1,78 @@
-use std::path::PathBuf;
+use std::{path::Path, sync::Arc};
 
+use anyhow::Context;
 use clap::Parser;
-use log::info;
-use platform_dirs::AppDirs;
+use log::{error, info};
 use serde::Deserialize;
+use tokio::sync::{oneshot, Notify};
 
 #[derive(Debug, Clone, Default, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct Args {
     #[serde(default)]
     pub server: websocket_server::ServerConfig,
+    #[cfg(feature = "mqtt-bridge")]
+    #[serde(default)]
+    pub roles: Vec<clip_sync_config::Role>,
+    #[cfg(feature = "mqtt-bridge")]
+    #[serde(default)]
+    pub mqtt_bridge: mqtt_client::MqttClientConfig,
+    #[cfg(feature = "mqtt-bridge")]
+    #[serde(default)]
+    pub reconnect: clip_sync_config::ReconnectConfig,
+}
+
+impl Args {
+    /// The broker to bridge to, if the `mqtt-bridge` role is enabled.
+    #[cfg(feature = "mqtt-bridge")]
+    fn bridge(&self) -> Option<&mqtt_client::MqttClientConfig> {
+        self.roles
+            .contains(&clip_sync_config::Role::MqttBridge)
+            .then_some(&self.mqtt_bridge)
+    }
+
+    /// Whether the server has to be restarted to use `new`.
+    fn changed(&self, new: &Args) -> bool {
+        #[cfg(feature = "mqtt-bridge")]
+        if self.bridge() != new.bridge() || self.reconnect != new.reconnect {
+            return true;
+        }
+        self.server != new.server
+    }
+
+    async fn run(self, signal: impl std::future::Future<Output = ()>) -> std::io::Result<()> {
+        #[cfg(feature = "mqtt-bridge")]
+        if let Some(bridge) = self.bridge().cloned() {
+            info!("Bridging to the MQTT broker {}", bridge.broker());
+            return websocket_server::server_main_with_bridge(
+                self.server,
+                bridge,
+                self.reconnect,
+                signal,
+            )
+            .await;
+        }
+        websocket_server::server_main_with_shutdown(self.server, signal).await
+    }
 }
 
-fn get_config_file() -> PathBuf {
-    let app_dirs = AppDirs::new(Some("clip-sync"), false).unwrap();
-    app_dirs.config_dir.join("config.toml")
+/// The `[server]` table of the config file, layered with `CLIPSYNC_SERVER__*` environment
+/// variables and `--set` flags like the configuration of `clip-sync`.
+fn read_config(config_path: Option<&Path>, overrides: &[String]) -> anyhow::Result<Args> {
+    let (path, config) = clip_sync_config::read_config_file(config_path)?;
+    clip_sync_config::layers::load(&config, overrides)
+        .with_context(|| format!("Invalid config at {:?}", path))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     #[derive(Debug, Clone, Parser)]
     struct Config {
-        #[arg(long = "config")]
+        #[arg(long = "config", env = "CLIPSYNC_CONFIG")]
         config_path: Option<std::path::PathBuf>,
+        /// Override a setting of the config file, e.g. `--set server.endpoint=0.0.0.0:3000`
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        overrides: Vec<String>,
         #[cfg(not(feature = "server-only"))]
         #[arg(long, default_value = "false")]
         no_tray: bool,
@@ -35,13 +85,53 @@ async fn main() -> anyhow::Result<()> {
         .filter_level(cli.verbose.log_level_filter())
         .filter_module("tantivy", log::LevelFilter::Warn) // Tantivy is too talky at the INFO level
         .init();
-    let config_path = cli.config_path.unwrap_or(get_config_file());
-    let config = std::fs::read_to_string(&config_path)
-        .unwrap_or_else(|_| panic!("Failed to read config at '{:?}'", config_path));
-    let args = toml::from_str::<Args>(&config)?;
-
-    info!("Starting websocket server");
-    websocket_server::server_main(args.server)
-        .await
-        .map_err(|e| anyhow::anyhow!("Server error: {}", e))
+    let config_path = cli.config_path.as_deref();
+    let mut args = read_config(config_path, &cli.overrides)?;
+
+    let reload = Arc::new(Notify::new());
+    let notify = reload.clone();
+    tokio::spawn(clip_sync_config::reload_on_sighup(move || {
+        notify.notify_one()
+    }));
+    let notify = reload.clone();
+    tokio::spawn(clip_sync_config::reload_on_change(
+        move || notify.notify_one(),
+        cli.config_path
+            .clone()
+            .unwrap_or_else(clip_sync_config::get_config_file),
+    ));
+    let shutdown = clip_sync_config::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        info!("Starting websocket server");
+        let (stop, stopped) = oneshot::channel::<()>();
+        let mut server = tokio::spawn(args.clone().run(async move {
+            stopped.await.ok();
+        }));
+        // The server is only restarted if its configuration changed, TLS certificates are
+        // reloaded by the server itself
+        let restart = loop {
+            tokio::select! {
+                result = &mut server => {
+                    return result?.map_err(|e| anyhow::anyhow!("Server error: {}", e));
+                }
+                _ = &mut shutdown => break false,
+                _ = reload.notified() => match read_config(config_path, &cli.overrides) {
+                    Ok(new_args) if args.changed(&new_args) => {
+                        args = new_args;
+                        break true;
+                    }
+                    Ok(_) => info!("Server configuration unchanged"),
+                    Err(e) => error!("Invalid configuration, keeping the current one: {}", e),
+                },
+            }
+        };
+        stop.send(()).ok();
+        server
+            .await?
+            .map_err(|e| anyhow::anyhow!("Server error: {}", e))?;
+        if !restart {
+            return Ok(());
+        }
+    }
 }
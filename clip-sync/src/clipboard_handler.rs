@@ -1,111 +1,248 @@
 use std::{
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
-use arboard::Clipboard;
-use clipboard_master::{CallbackResult, ClipboardHandler, Master};
 use log::{debug, info, trace, warn};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{watch, Notify};
 
 use client_interface::{
-    ClipboardContent, ClipboardRecord, ClipboardSink, ClipboardSource, ImageData,
+    ClipboardContent, ClipboardRecord, ClipboardSink, ClipboardSource, DeviceFilter, ImageData,
 };
+use clip_sync_config::control::ReceivedEntry;
 
-pub struct Handler {
-    pub sender: Sender<ClipboardRecord>,
-    pub provider: Clipboard,
-    pub sender_id: String,
-    pub last_set_content: Arc<Mutex<ClipboardContent>>,
+use crate::{
+    history::{self, History},
+    outbox::Outbox,
+    system_clipboard::SystemClipboard,
+};
+
+struct Handler {
+    clipboard: Arc<dyn SystemClipboard>,
+    device_id: String,
+    last_set_content: Arc<Mutex<ClipboardContent>>,
+    outbox: Arc<Mutex<Outbox>>,
+    changed: Arc<Notify>,
+    history: Option<Arc<History>>,
+    /// The watcher runs on its own thread, history writes go to the runtime's blocking pool
+    runtime: tokio::runtime::Handle,
+    sync: SyncState,
+    stopped: watch::Receiver<bool>,
 }
 
-impl ClipboardHandler for Handler {
-    fn on_clipboard_change(&mut self) -> CallbackResult {
+impl Handler {
+    /// Called on every clipboard change, returns `false` to stop watching.
+    fn on_clipboard_change(&mut self) -> bool {
+        if *self.stopped.borrow() {
+            return false;
+        }
         debug!("Clipboard change happened!");
-        if let Ok(Some(content)) = get_clipboard_content(&mut self.provider) {
+        if self.sync.is_paused() {
+            debug!("Syncing is paused, ignoring clipboard change");
+            return true;
+        }
+        if let Ok(Some(content)) = get_clipboard_content(&*self.clipboard) {
             {
                 let mut guard = self.last_set_content.lock().unwrap();
                 if *guard == content {
                     debug!("Skipping clipboard update from self");
-                    return CallbackResult::Next;
+                    return true;
                 }
                 *guard = content.clone();
             }
-            let data = ClipboardRecord {
-                source: self.sender_id.clone(),
-                content,
-            };
-            self.sender.blocking_send(data).ok();
+            self.runtime.spawn(history::record(
+                self.history.clone(),
+                ClipboardRecord {
+                    source: self.device_id.clone(),
+                    content: content.clone(),
+                },
+            ));
+            self.outbox.lock().unwrap().push(content);
+            self.changed.notify_one();
         }
-        CallbackResult::Next
+        true
+    }
+}
+
+/// Sync state shared by all roles, it's controlled from the control socket.
+#[derive(Clone, Default)]
+pub struct SyncState {
+    paused: Arc<AtomicBool>,
+    last_received: Arc<Mutex<Option<ReceivedEntry>>>,
+}
+
+impl SyncState {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
     }
 
-    fn on_clipboard_error(&mut self, error: std::io::Error) -> CallbackResult {
-        warn!("Error: {}", error);
-        CallbackResult::Next
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
     }
+
+    pub fn last_received(&self) -> Option<ReceivedEntry> {
+        self.last_received.lock().unwrap().clone()
+    }
+
+    fn received(&self, record: &ClipboardRecord) {
+        *self.last_received.lock().unwrap() = Some(ReceivedEntry {
+            source: record.source.clone(),
+            timestamp: chrono::Utc::now().timestamp(),
+            content: (&record.content).into(),
+        });
+    }
+}
+
+/// The clipboard side of a role, it outlives connections so changes made while disconnected
+/// are kept in the outbox and published after reconnecting.
+#[derive(Clone)]
+pub struct ClipboardContext {
+    clipboard: Arc<dyn SystemClipboard>,
+    last_set_content: Arc<Mutex<ClipboardContent>>,
+    outbox: Arc<Mutex<Outbox>>,
+    changed: Arc<Notify>,
+    device_filter: DeviceFilter,
+    history: Option<Arc<History>>,
+    sync: SyncState,
+    stopped: Arc<watch::Sender<bool>>,
 }
 
+impl ClipboardContext {
+    /// Start watching the system clipboard, local changes are recorded as from `device_id`.
+    pub fn new(
+        clipboard: Arc<dyn SystemClipboard>,
+        device_id: String,
+        device_filter: DeviceFilter,
+        outbox: Outbox,
+        history: Option<Arc<History>>,
+        sync: SyncState,
+    ) -> Self {
+        let context = Self {
+            clipboard,
+            last_set_content: Arc::new(Mutex::new(ClipboardContent::Text("".to_string()))),
+            outbox: Arc::new(Mutex::new(outbox)),
+            changed: Arc::new(Notify::new()),
+            device_filter,
+            history,
+            sync,
+            stopped: Arc::new(watch::Sender::new(false)),
+        };
+        let mut handler = Handler {
+            clipboard: context.clipboard.clone(),
+            device_id,
+            last_set_content: context.last_set_content.clone(),
+            outbox: context.outbox.clone(),
+            changed: context.changed.clone(),
+            history: context.history.clone(),
+            runtime: tokio::runtime::Handle::current(),
+            sync: context.sync.clone(),
+            stopped: context.stopped.subscribe(),
+        };
+        let clipboard = context.clipboard.clone();
+        let stopped = context.stopped.subscribe();
+        std::thread::spawn(move || {
+            let on_change = Box::new(move || handler.on_clipboard_change());
+            if let Err(e) = clipboard.watch(on_change, stopped) {
+                warn!("Failed to watch the clipboard: {}", e);
+            }
+        });
+        context
+    }
+
+    /// Stop watching the system clipboard and close the connection of the role.
+    pub fn stop(&self) {
+        self.stopped.send_replace(true);
+    }
+
+    async fn stopped(&self) {
+        self.stopped
+            .subscribe()
+            .wait_for(|stopped| *stopped)
+            .await
+            .ok();
+    }
+
+    /// Set the system clipboard without publishing the content, returns whether it changed.
+    pub fn set_content(&self, content: ClipboardContent) -> anyhow::Result<bool> {
+        let content = content.normalize();
+        // Must be set before the clipboard changes, or the watcher may publish it back
+        *self.last_set_content.lock().unwrap() = content.clone();
+        set_clipboard_content(&*self.clipboard, content)
+    }
+
+    /// Publish the current clipboard content again, even if it was already sent.
+    pub fn resend(&self) -> anyhow::Result<()> {
+        let Some(content) = get_clipboard_content(&*self.clipboard)? else {
+            anyhow::bail!("The clipboard is empty");
+        };
+        *self.last_set_content.lock().unwrap() = content.clone();
+        self.outbox.lock().unwrap().push(content);
+        self.changed.notify_one();
+        Ok(())
+    }
+}
+
+/// Sync the clipboard until the connection fails or the context is stopped.
 pub async fn start(
     sender_id: String,
-    source: impl ClipboardSource,
-    sink: impl ClipboardSink,
+    mut source: impl ClipboardSource,
+    mut sink: impl ClipboardSink,
+    context: ClipboardContext,
 ) -> anyhow::Result<()> {
-    let last_set_content: Arc<Mutex<ClipboardContent>> =
-        Arc::new(Mutex::new(ClipboardContent::Text("".to_string())));
-
-    let provider = Clipboard::new().map_err(|e| {
-        anyhow::anyhow!("Failed to initialize clipboard provider: {}", e.to_string())
-    })?;
-    let (sender, receiver) = tokio::sync::mpsc::channel(10);
-
-    let publisher_task = clipboard_publisher(sink, receiver);
-    let subscriber_task = clipboard_subscriber(source, sender_id.clone(), last_set_content.clone());
-
-    let handler = Handler {
-        sender,
-        provider,
-        sender_id: sender_id.clone(),
-        last_set_content,
-    };
-
-    std::thread::spawn(move || {
-        let _ = Master::new(handler).run();
-    });
-    let (r1, r2) = tokio::join!(publisher_task, subscriber_task);
-    r1?;
-    r2?;
+    let publisher_task = clipboard_publisher(&mut sink, sender_id.clone(), context.clone());
+    let subscriber_task = clipboard_subscriber(&mut source, sender_id, context.clone());
+    tokio::select! {
+        // Either side failing means the connection is gone, e.g. a dropped MQTT connection only
+        // fails the subscriber
+        result = async { tokio::try_join!(publisher_task, subscriber_task) } => {
+            result?;
+            return Ok(());
+        }
+        _ = context.stopped() => {}
+    }
+    info!("Closing connection");
+    if let Err(e) = sink.close().await.and(source.close().await) {
+        warn!("Failed to close connection: {}", e);
+    }
     Ok(())
 }
 
-/// Publish the clipboard content to the sink if it's changed.
+/// Publish the clipboard content in the outbox to the sink, in the order it was copied.
 async fn clipboard_publisher(
-    mut sink: impl ClipboardSink,
-    mut receiver: Receiver<ClipboardRecord>,
+    sink: &mut impl ClipboardSink,
+    sender_id: String,
+    context: ClipboardContext,
 ) -> anyhow::Result<()> {
     loop {
-        match tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await {
-            Ok(Some(data)) => {
-                sink.publish(Some(data)).await?;
-            }
-            Ok(None) => {
-                warn!("Channel closed");
-                break;
-            }
-            Err(_) => {
-                trace!("Sending ping to server");
-                sink.publish(None).await?;
-            }
+        // Removed only once published, so a failure or a crash doesn't lose it
+        let next = context.outbox.lock().unwrap().front();
+        if let Some(content) = next {
+            let data = ClipboardRecord {
+                source: sender_id.clone(),
+                content: content.clone(),
+            };
+            sink.publish(Some(data)).await?;
+            context.outbox.lock().unwrap().delivered(&content);
+            continue;
+        }
+        if tokio::time::timeout(Duration::from_secs(5), context.changed.notified())
+            .await
+            .is_err()
+        {
+            trace!("Sending ping to server");
+            sink.publish(None).await?;
         }
     }
-    Err(anyhow::anyhow!("Publisher source channel closed"))
 }
 
 /// Poll the clipboard content from the source and set it to the system clipboard.
 async fn clipboard_subscriber(
-    mut source: impl ClipboardSource,
+    source: &mut impl ClipboardSource,
     client_id: String,
-    last_set_content: Arc<Mutex<ClipboardContent>>,
+    context: ClipboardContext,
 ) -> anyhow::Result<()> {
     loop {
         if let Ok(clipboard_data) = source.poll().await {
@@ -114,21 +251,27 @@ async fn clipboard_subscriber(
                 debug!("Skipping clipboard update message sent by self");
                 continue;
             }
-            Clipboard::new()
-                .map_err(|e| {
-                    anyhow::anyhow!("Failed to initialize clipboard provider: {}", e.to_string())
-                })
-                .and_then(|mut provider| {
-                    set_clipboard_content(&mut provider, clipboard_data.content.clone()).map(
-                        |changed| {
-                            if changed {
-                                info!("Clipboard updated");
-                                *last_set_content.lock().unwrap() = clipboard_data.content;
-                            }
-                        },
-                    )
-                })
-                .ok();
+            if !context.device_filter.accepts(&clipboard_data.source) {
+                debug!(
+                    "Skipping clipboard update from filtered device '{}'",
+                    clipboard_data.source
+                );
+                continue;
+            }
+            context.sync.received(&clipboard_data);
+            if context.sync.is_paused() {
+                debug!("Syncing is paused, not updating the clipboard");
+                continue;
+            }
+            let mut clipboard_data = clipboard_data;
+            clipboard_data.content = clipboard_data.content.normalize();
+            let changed = context
+                .set_content(clipboard_data.content.clone())
+                .unwrap_or_default();
+            if changed {
+                info!("Clipboard updated");
+                history::record(context.history.clone(), clipboard_data).await;
+            }
         } else {
             warn!("Failed to receive clipboard data");
             return Err(anyhow::anyhow!("Failed to receive clipboard data"));
@@ -136,50 +279,25 @@ async fn clipboard_subscriber(
     }
 }
 
-fn get_clipboard_text(provider: &mut Clipboard) -> anyhow::Result<Option<String>> {
-    match provider.get_text() {
-        Ok(text) => {
-            if text.is_empty() {
-                Ok(None)
-            } else {
-                // HACK: Windows and macOS/Linux have different line endings.
-                Ok(Some(text.replace("\r\n", "\n")))
-            }
-        }
-        Err(arboard::Error::ContentNotAvailable) => Ok(None),
-        Err(e) => {
-            debug!("Failed to get text from clipboard: {}", e);
-            Err(anyhow::anyhow!("Failed to get text from clipboard"))
-        }
-    }
+fn get_clipboard_text(clipboard: &dyn SystemClipboard) -> anyhow::Result<Option<String>> {
+    Ok(clipboard
+        .get_text()?
+        .filter(|text| !text.is_empty())
+        // HACK: Windows and macOS/Linux have different line endings.
+        .map(|text| text.replace("\r\n", "\n")))
 }
 
-fn get_clipboard_image(provider: &mut Clipboard) -> anyhow::Result<Option<ImageData>> {
-    match provider.get_image() {
-        Ok(img) => {
-            if img.bytes.len() == 0 {
-                Ok(None)
-            } else {
-                Ok(Some(ImageData {
-                    width: img.width,
-                    height: img.height,
-                    data: img.bytes.to_vec(),
-                }))
-            }
-        }
-        Err(arboard::Error::ContentNotAvailable) => Ok(None),
-        Err(e) => {
-            debug!("Failed to get image from clipboard: {}", e);
-            Err(anyhow::anyhow!("Failed to get image from clipboard"))
-        }
-    }
+fn get_clipboard_image(clipboard: &dyn SystemClipboard) -> anyhow::Result<Option<ImageData>> {
+    Ok(clipboard.get_image()?.filter(|img| !img.data.is_empty()))
 }
 
-fn get_clipboard_content(provider: &mut Clipboard) -> anyhow::Result<Option<ClipboardContent>> {
-    if let Some(text) = get_clipboard_text(provider)? {
+fn get_clipboard_content(
+    clipboard: &dyn SystemClipboard,
+) -> anyhow::Result<Option<ClipboardContent>> {
+    if let Some(text) = get_clipboard_text(clipboard)? {
         debug!("Got text from clipboard: {}", text);
         Ok(Some(ClipboardContent::Text(text)))
-    } else if let Some(image) = get_clipboard_image(provider)? {
+    } else if let Some(image) = get_clipboard_image(clipboard)? {
         debug!("Got image from clipboard {:?}.", image);
         Ok(Some(ClipboardContent::Image(image)))
     } else {
@@ -189,12 +307,12 @@ fn get_clipboard_content(provider: &mut Clipboard) -> anyhow::Result<Option<Clip
 }
 
 fn set_clipboard_content(
-    provider: &mut Clipboard,
+    clipboard: &dyn SystemClipboard,
     content: ClipboardContent,
 ) -> anyhow::Result<bool> {
     let existing = match &content {
-        ClipboardContent::Text(_) => get_clipboard_text(provider)?.map(ClipboardContent::Text),
-        ClipboardContent::Image(_) => get_clipboard_image(provider)?.map(ClipboardContent::Image),
+        ClipboardContent::Text(_) => get_clipboard_text(clipboard)?.map(ClipboardContent::Text),
+        ClipboardContent::Image(_) => get_clipboard_image(clipboard)?.map(ClipboardContent::Image),
     };
     if let Some(existing) = existing {
         if existing == content {
@@ -202,15 +320,141 @@ fn set_clipboard_content(
             return Ok(false);
         }
     }
-    provider.clear()?;
     match content {
-        // HACK: Windows and macOS/Linux have different line endings.
-        ClipboardContent::Text(text) => provider.set_text(text.replace("\r\n", "\n")),
-        ClipboardContent::Image(image) => provider.set_image(arboard::ImageData {
-            bytes: image.data.into(),
-            width: image.width,
-            height: image.height,
-        }),
+        ClipboardContent::Text(text) => clipboard.set_text(text),
+        ClipboardContent::Image(image) => clipboard.set_image(image),
     }?;
     Ok(true)
 }
+
+#[cfg(test)]
+mod tests {
+    use clip_sync_config::OutboxConfig;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::system_clipboard::MemoryClipboard;
+
+    struct ChannelSource(mpsc::Receiver<ClipboardRecord>);
+
+    impl ClipboardSource for ChannelSource {
+        async fn poll(&mut self) -> anyhow::Result<ClipboardRecord> {
+            self.0
+                .recv()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Source closed"))
+        }
+    }
+
+    struct ChannelSink(mpsc::Sender<ClipboardRecord>);
+
+    impl ClipboardSink for ChannelSink {
+        async fn publish(&mut self, data: Option<ClipboardRecord>) -> anyhow::Result<()> {
+            if let Some(data) = data {
+                self.0.send(data).await?;
+            }
+            Ok(())
+        }
+    }
+
+    fn record(source: &str, text: &str) -> ClipboardRecord {
+        ClipboardRecord {
+            source: source.to_string(),
+            content: self::text(text),
+        }
+    }
+
+    fn text(text: &str) -> ClipboardContent {
+        ClipboardContent::Text(text.to_string())
+    }
+
+    async fn next_published(published: &mut mpsc::Receiver<ClipboardRecord>) -> ClipboardContent {
+        let record = tokio::time::timeout(Duration::from_secs(5), published.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.source, "local");
+        record.content
+    }
+
+    async fn wait_for_text(clipboard: &MemoryClipboard, text: &str) {
+        for _ in 0..100 {
+            if clipboard.get_text().unwrap().as_deref() == Some(text) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Clipboard was not set to {:?}", text);
+    }
+
+    #[tokio::test]
+    async fn test_sync_loop() {
+        let clipboard = Arc::new(MemoryClipboard::default());
+        let context = ClipboardContext::new(
+            clipboard.clone(),
+            "local".to_string(),
+            DeviceFilter::default(),
+            Outbox::open(&OutboxConfig::default(), "test"),
+            None,
+            SyncState::default(),
+        );
+        let (remote, source) = mpsc::channel(10);
+        let (sink, mut published) = mpsc::channel(10);
+        tokio::spawn(start(
+            "local".to_string(),
+            ChannelSource(source),
+            ChannelSink(sink),
+            context.clone(),
+        ));
+
+        // Local changes are published with normalized line endings
+        clipboard.set_text("a\r\nb".to_string()).unwrap();
+        assert_eq!(next_published(&mut published).await, text("a\nb"));
+
+        // Remote updates are applied, but not published back
+        remote.send(record("remote", "c\r\nd")).await.unwrap();
+        wait_for_text(&clipboard, "c\nd").await;
+        clipboard.set_text("e".to_string()).unwrap();
+        assert_eq!(next_published(&mut published).await, text("e"));
+
+        // Updates sent by this device are ignored
+        remote.send(record("local", "f")).await.unwrap();
+        remote.send(record("remote", "g")).await.unwrap();
+        wait_for_text(&clipboard, "g").await;
+
+        // Content set through the control socket isn't published either
+        context.set_content(text("h\r\ni")).unwrap();
+        wait_for_text(&clipboard, "h\ni").await;
+        clipboard.set_text("j".to_string()).unwrap();
+        assert_eq!(next_published(&mut published).await, text("j"));
+        context.stop();
+    }
+
+    #[tokio::test]
+    async fn test_source_failure() {
+        let context = ClipboardContext::new(
+            Arc::new(MemoryClipboard::default()),
+            "local".to_string(),
+            DeviceFilter::default(),
+            Outbox::open(&OutboxConfig::default(), "test"),
+            None,
+            SyncState::default(),
+        );
+        let (remote, source) = mpsc::channel(10);
+        // The sink stays healthy, the publisher never fails by itself
+        let (sink, _published) = mpsc::channel(10);
+        let task = tokio::spawn(start(
+            "local".to_string(),
+            ChannelSource(source),
+            ChannelSink(sink),
+            context.clone(),
+        ));
+        drop(remote);
+        let result = tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("The connection should end when the source fails")
+            .unwrap();
+        assert!(result.is_err());
+        context.stop();
+    }
+}
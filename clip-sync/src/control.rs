@@ -0,0 +1,191 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use clip_sync_config::control::{ControlRequest, ControlResponse};
+use log::info;
+use tokio::sync::{watch, Notify};
+
+use clip_sync::{
+    clipboard_handler::{ClipboardContext, SyncState},
+    supervisor::StatusBoard,
+};
+
+/// Daemon state shared between the roles, the tray and the control socket.
+#[derive(Clone, Default)]
+pub struct Control {
+    pub status: StatusBoard,
+    pub sync: SyncState,
+    contexts: Arc<Mutex<BTreeMap<String, ClipboardContext>>>,
+    reload: Arc<Notify>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Control {
+    /// Make the clipboard of a running role reachable by `resend`.
+    pub fn register(&self, role: &str, context: ClipboardContext) {
+        if let Some(old) = self
+            .contexts
+            .lock()
+            .unwrap()
+            .insert(role.to_string(), context)
+        {
+            old.stop();
+        }
+    }
+
+    /// Stop the clipboard watcher of a role, which also closes its connection.
+    pub fn unregister(&self, role: &str) {
+        if let Some(context) = self.contexts.lock().unwrap().remove(role) {
+            context.stop();
+        }
+    }
+
+    /// Stop the clipboard watchers of all registered roles.
+    pub fn stop_all(&self) {
+        for (_, context) in std::mem::take(&mut *self.contexts.lock().unwrap()) {
+            context.stop();
+        }
+    }
+
+    /// Stop all roles, they are not restarted afterwards.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn shutdown_receiver(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// Re-read the configuration file and restart the roles it affects.
+    pub fn request_reload(&self) {
+        self.reload.notify_one();
+    }
+
+    /// Resolves when a reload is requested.
+    pub async fn reload_requested(&self) {
+        self.reload.notified().await
+    }
+
+    pub async fn handle(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Status => ControlResponse::Status {
+                paused: self.sync.is_paused(),
+                roles: self.status.snapshot(),
+                last_received: self.sync.last_received(),
+            },
+            ControlRequest::Pause => {
+                info!("Syncing paused");
+                self.sync.set_paused(true);
+                ControlResponse::Ok
+            }
+            ControlRequest::Resume => {
+                info!("Syncing resumed");
+                self.sync.set_paused(false);
+                ControlResponse::Ok
+            }
+            ControlRequest::Resend => {
+                let contexts: Vec<_> = self.contexts.lock().unwrap().values().cloned().collect();
+                if contexts.is_empty() {
+                    return ControlResponse::Error {
+                        message: "No client role is running".to_string(),
+                    };
+                }
+                let result = tokio::task::spawn_blocking(move || {
+                    contexts.iter().try_for_each(|context| context.resend())
+                })
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|r| r);
+                match result {
+                    Ok(_) => ControlResponse::Ok,
+                    Err(e) => ControlResponse::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ControlRequest::LastReceived => ControlResponse::LastReceived {
+                entry: self.sync.last_received(),
+            },
+            ControlRequest::Reload => {
+                self.request_reload();
+                ControlResponse::Ok
+            }
+            ControlRequest::SetClipboard { content } => {
+                // All roles share the system clipboard, any of them will do
+                let Some(context) = self.contexts.lock().unwrap().values().next().cloned() else {
+                    return ControlResponse::Error {
+                        message: "No client role is running".to_string(),
+                    };
+                };
+                let result = tokio::task::spawn_blocking(move || context.set_content(content))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| r);
+                match result {
+                    Ok(_) => ControlResponse::Ok,
+                    Err(e) => ControlResponse::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Accept control requests on a local socket, only the current user can connect to it.
+#[cfg(unix)]
+pub async fn serve(control: Control, path: PathBuf) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            anyhow::bail!("Another instance is listening on {:?}", path);
+        }
+        // Left over by a previous run that didn't exit cleanly
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {:?}", path);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(control, stream).await {
+                log::debug!("Control connection error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn handle_connection(control: Control, stream: tokio::net::UnixStream) -> anyhow::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => control.handle(request).await,
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+        let mut response = serde_json::to_string(&response)?;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_control: Control, _path: PathBuf) -> anyhow::Result<()> {
+    anyhow::bail!("The control socket is not supported on this platform")
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use client_interface::ClipboardRecord;
+use clip_sync_config::HistoryConfig;
+use log::warn;
+
+#[cfg(feature = "history")]
+mod imp {
+    use std::{path::PathBuf, sync::Mutex};
+
+    use client_interface::{
+        ClipboardContent, ClipboardMessage, ClipboardRecord, ServerClipboardContent,
+        ServerClipboardRecord,
+    };
+    use clip_sync_config::HistoryConfig;
+    use log::{debug, warn};
+    use sha2::Digest;
+    use websocket_server::Search;
+
+    /// Local clipboard history, stored in the same index format as the server.
+    pub struct History {
+        // The index allows only one writer at a time.
+        search: Mutex<Search>,
+        image_path: PathBuf,
+    }
+
+    fn digest(bytes: &[u8]) -> String {
+        let mut hasher = <sha2::Sha512 as Digest>::new();
+        hasher.update(bytes);
+        hex::encode(Into::<[u8; 64]>::into(hasher.finalize()))
+    }
+
+    impl History {
+        pub fn open(config: &HistoryConfig) -> anyhow::Result<Self> {
+            let image_path = config.image_path();
+            std::fs::create_dir_all(&image_path)?;
+            Ok(Self {
+                search: Mutex::new(Search::open(Some(config.index_path()))?),
+                image_path,
+            })
+        }
+
+        pub fn record(&self, record: &ClipboardRecord) {
+            if let Err(e) = self.add_record(record) {
+                warn!("Failed to record clipboard history: {}", e);
+            }
+        }
+
+        fn add_record(&self, record: &ClipboardRecord) -> anyhow::Result<()> {
+            let (id, content) = match &record.content {
+                ClipboardContent::Text(text) => (
+                    digest(text.as_bytes()),
+                    ServerClipboardContent::Text(text.clone()),
+                ),
+                ClipboardContent::Image(image) => {
+                    let png = image.to_png()?;
+                    let id = digest(&png);
+                    let url = format!("{}/{}.png", record.source, &id[0..32]);
+                    let path = self.image_path.join(&url);
+                    if !path.exists() {
+                        std::fs::create_dir_all(self.image_path.join(&record.source))?;
+                        std::fs::write(&path, png)?;
+                    }
+                    (id, ServerClipboardContent::ImageUrl(url))
+                }
+            };
+            debug!("Recording clipboard history entry {}", id);
+            self.search.lock().unwrap().add_entry(&ClipboardMessage {
+                entry: ServerClipboardRecord {
+                    id: Some(id),
+                    source: record.source.clone(),
+                    content,
+                    targets: None,
+                    channel: None,
+                },
+                timestamp: chrono::Utc::now().timestamp(),
+                pinned: false,
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "history"))]
+mod imp {
+    use client_interface::ClipboardRecord;
+    use clip_sync_config::HistoryConfig;
+
+    pub struct History;
+
+    impl History {
+        pub fn open(_config: &HistoryConfig) -> anyhow::Result<Self> {
+            anyhow::bail!("Local history is not supported by this build")
+        }
+
+        pub fn record(&self, _record: &ClipboardRecord) {}
+    }
+}
+
+pub use imp::History;
+
+/// Open the local history if it's configured, history is disabled if it can't be opened.
+pub fn open(config: Option<&HistoryConfig>) -> Option<Arc<History>> {
+    let config = config?;
+    match History::open(config) {
+        Ok(history) => Some(Arc::new(history)),
+        Err(e) => {
+            warn!("Local history is disabled: {}", e);
+            None
+        }
+    }
+}
+
+/// Record the entry without blocking the async runtime.
+pub async fn record(history: Option<Arc<History>>, record: ClipboardRecord) {
+    if let Some(history) = history {
+        tokio::task::spawn_blocking(move || history.record(&record))
+            .await
+            .ok();
+    }
+}
@@ -0,0 +1,8 @@
+//! The clipboard side of `clip-sync`, a library so the whole sync loop can run in tests against
+//! [`system_clipboard::MemoryClipboard`] instead of a display.
+
+pub mod clipboard_handler;
+pub mod history;
+pub mod outbox;
+pub mod supervisor;
+pub mod system_clipboard;
@@ -1,75 +1,324 @@
 #![windows_subsystem = "windows"]
 
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::{Arc, Mutex},
+};
+
 use client_interface::ClipSyncClient;
-use clip_sync_config::Args;
-use log::{info, warn};
+use clip_sync::{
+    clipboard_handler::{self, ClipboardContext},
+    history::{self, History},
+    outbox::Outbox,
+    supervisor::supervise,
+    system_clipboard::ArboardClipboard,
+};
+use clip_sync_config::{Args, Role};
+use control::Control;
+use log::{error, info};
+use tokio::{sync::watch, task::JoinHandle};
 
 pub use client_interface::{ClipboardSink, ClipboardSource};
 
-mod clipboard_handler;
+mod control;
 
 pub static APP_ICON: &[u8] = include_bytes!("../../icons/app-icon.png");
 
-async fn svc_main(args: Args) -> anyhow::Result<()> {
-    if args.roles.is_empty() {
-        anyhow::bail!("No role specified");
-    }
+#[allow(dead_code)]
+fn default_device_id() -> String {
+    gethostname::gethostname()
+        .into_string()
+        .unwrap_or("local".to_string())
+}
 
-    let mut tasks: Vec<tokio::task::JoinHandle<anyhow::Result<()>>> = vec![];
-    #[cfg(feature = "server")]
-    if args.roles.contains(&"server".to_string()) {
-        let server = args.server.clone();
-        tasks.push(tokio::spawn(async move {
-            loop {
-                info!("Starting websocket server");
-                websocket_server::server_main(server.clone())
-                    .await
-                    .map_err(|e| anyhow::anyhow!("Server error: {}", e))
-                    .ok();
-                warn!("Server exited unexpectedly, restarting in 1 second");
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+/// A role started by `svc_main`, with the configuration it was started with.
+struct RunningRole {
+    args: Args,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+/// Whether `role` has to be restarted for the configuration to change from `old` to `new`.
+fn role_changed(role: Role, old: &Args, new: &Args) -> bool {
+    #[allow(unused_variables)]
+    let clipboard_changed = old.device_filter != new.device_filter
+        || old.outbox != new.outbox
+        || old.history != new.history;
+    old.reconnect != new.reconnect
+        || match role {
+            #[cfg(feature = "server")]
+            Role::Server => old.server != new.server || bridge_changed(old, new),
+            #[cfg(feature = "mqtt")]
+            Role::MqttClient => clipboard_changed || old.mqtt_client != new.mqtt_client,
+            #[cfg(feature = "websocket")]
+            Role::WebsocketClient => {
+                clipboard_changed || old.websocket_client != new.websocket_client
             }
-        }));
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+}
+
+/// The bridge runs inside the server, which is restarted when it's added, removed or changed.
+#[cfg(feature = "server")]
+#[allow(unused_variables)]
+fn bridge_changed(old: &Args, new: &Args) -> bool {
+    #[cfg(feature = "mqtt-bridge")]
+    {
+        let enabled = |args: &Args| args.roles.contains(&Role::MqttBridge);
+        enabled(old) != enabled(new) || old.mqtt_bridge != new.mqtt_bridge
     }
-    #[cfg(feature = "mqtt")]
-    if args.roles.contains(&"mqtt-client".to_string()) {
-        let mqtt_client = args.mqtt_client.clone();
-        tasks.push(tokio::spawn(async move {
-            loop {
-                info!("Starting MQTT client");
-                if let Ok((sender_id, source, sink)) =
-                    mqtt_client::MqttClipSyncClient::connect(mqtt_client.clone()).await
-                {
-                    clipboard_handler::start(sender_id, source, sink).await.ok();
-                }
-                warn!("MQTT client exited unexpectedly, restarting in 1 second");
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            }
-        }));
+    #[cfg(not(feature = "mqtt-bridge"))]
+    false
+}
+
+/// The clipboard side of a client role, created by the first attempt that can open the system
+/// clipboard, so failing to open it is retried with backoff like a failed connection.
+#[allow(dead_code)]
+#[derive(Clone)]
+struct LazyClipboard {
+    role: Role,
+    device_id: String,
+    args: Args,
+    history: Option<Arc<History>>,
+    control: Control,
+    shutdown: watch::Receiver<bool>,
+    context: Arc<Mutex<Option<ClipboardContext>>>,
+}
+
+#[allow(dead_code)]
+impl LazyClipboard {
+    fn get(&self) -> anyhow::Result<ClipboardContext> {
+        let mut context = self.context.lock().unwrap();
+        if let Some(context) = &*context {
+            return Ok(context.clone());
+        }
+        let created = ClipboardContext::new(
+            Arc::new(ArboardClipboard::new()?),
+            self.device_id.clone(),
+            self.args.device_filter.clone(),
+            Outbox::open(&self.args.outbox, self.role.name()),
+            self.history.clone(),
+            self.control.sync.clone(),
+        );
+        self.control.register(self.role.name(), created.clone());
+        if *self.shutdown.borrow() {
+            // `stop_role` may have unregistered the role just before
+            self.control.unregister(self.role.name());
+            anyhow::bail!("{} is stopping", self.role);
+        }
+        Ok(context.insert(created).clone())
     }
-    #[cfg(feature = "websocket")]
-    if args.roles.contains(&"websocket-client".to_string()) {
-        let websocket_client = args.websocket_client.clone();
-        tasks.push(tokio::spawn(async move {
-            loop {
-                info!("Starting websocket client");
-                if let Ok((sender_id, source, sink)) =
-                    websocket_client::WebsocketClipSyncClient::connect(websocket_client.clone())
-                        .await
-                {
-                    clipboard_handler::start(sender_id, source, sink).await.ok();
-                    warn!("Websocket client exited unexpectedly, restarting in 1 second");
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+}
+
+#[allow(unused_variables)]
+fn start_role(
+    role: Role,
+    args: &Args,
+    history: Option<Arc<History>>,
+    control: &Control,
+) -> Option<RunningRole> {
+    let (stop, shutdown) = watch::channel(false);
+    let reconnect = args.reconnect.clone();
+    let task: JoinHandle<anyhow::Result<()>> = match role {
+        #[cfg(feature = "server")]
+        Role::Server => {
+            let server = args.server.clone();
+            #[cfg(feature = "mqtt-bridge")]
+            let bridge = args
+                .roles
+                .contains(&Role::MqttBridge)
+                .then(|| (args.mqtt_bridge.clone(), args.reconnect.clone()));
+            let status = control.status.clone();
+            tokio::spawn(async move {
+                supervise("server", status, reconnect, shutdown.clone(), |reporter| {
+                    let server = server.clone();
+                    #[cfg(feature = "mqtt-bridge")]
+                    let bridge = bridge.clone();
+                    let mut shutdown = shutdown.clone();
+                    async move {
+                        reporter.connected();
+                        let signal = async move {
+                            shutdown.wait_for(|shutdown| *shutdown).await.ok();
+                        };
+                        #[cfg(feature = "mqtt-bridge")]
+                        if let Some((bridge, reconnect)) = bridge {
+                            return websocket_server::server_main_with_bridge(
+                                server, bridge, reconnect, signal,
+                            )
+                            .await
+                            .map_err(|e| anyhow::anyhow!("Server error: {}", e));
+                        }
+                        websocket_server::server_main_with_shutdown(server, signal)
+                            .await
+                            .map_err(|e| anyhow::anyhow!("Server error: {}", e))
+                    }
+                })
+                .await
+            })
+        }
+        #[cfg(feature = "mqtt")]
+        Role::MqttClient => {
+            let mqtt_client = args.mqtt_client.clone();
+            let status = control.status.clone();
+            let clipboard = LazyClipboard {
+                role,
+                device_id: args
+                    .mqtt_client
+                    .mqtt_client_id
+                    .clone()
+                    .unwrap_or_else(default_device_id),
+                args: args.clone(),
+                history,
+                control: control.clone(),
+                shutdown: shutdown.clone(),
+                context: Arc::default(),
+            };
+            tokio::spawn(async move {
+                supervise("mqtt-client", status, reconnect, shutdown, |reporter| {
+                    let mqtt_client = mqtt_client.clone();
+                    let clipboard = clipboard.clone();
+                    async move {
+                        let context = clipboard.get()?;
+                        let (sender_id, source, sink) =
+                            mqtt_client::MqttClipSyncClient::connect(mqtt_client).await?;
+                        reporter.connected();
+                        clipboard_handler::start(sender_id, source, sink, context).await
+                    }
+                })
+                .await
+            })
+        }
+        #[cfg(feature = "websocket")]
+        Role::WebsocketClient => {
+            let websocket_client = args.websocket_client.clone();
+            let status = control.status.clone();
+            let clipboard = LazyClipboard {
+                role,
+                device_id: args
+                    .websocket_client
+                    .client_id
+                    .clone()
+                    .unwrap_or_else(default_device_id),
+                args: args.clone(),
+                history,
+                control: control.clone(),
+                shutdown: shutdown.clone(),
+                context: Arc::default(),
+            };
+            tokio::spawn(async move {
+                supervise(
+                    "websocket-client",
+                    status,
+                    reconnect,
+                    shutdown,
+                    |reporter| {
+                        let websocket_client = websocket_client.clone();
+                        let clipboard = clipboard.clone();
+                        async move {
+                            let context = clipboard.get()?;
+                            let (sender_id, source, sink) =
+                                websocket_client::WebsocketClipSyncClient::connect(
+                                    websocket_client,
+                                )
+                                .await?;
+                            reporter.connected();
+                            clipboard_handler::start(sender_id, source, sink, context).await
+                        }
+                    },
+                )
+                .await
+            })
+        }
+        // Started by the server role
+        #[cfg(feature = "mqtt-bridge")]
+        Role::MqttBridge => return None,
+        #[allow(unreachable_patterns)]
+        _ => {
+            log::warn!("Role '{}' is not supported by this build", role);
+            return None;
+        }
+    };
+    let name = role.to_string();
+    let task = tokio::spawn(async move {
+        match task.await {
+            Ok(Err(e)) => error!("{} failed: {}", name, e),
+            Err(e) if e.is_panic() => error!("{} panicked", name),
+            _ => {}
+        }
+    });
+    Some(RunningRole {
+        args: args.clone(),
+        stop,
+        task,
+    })
+}
+
+/// Close the connection of the role and wait for it to stop.
+async fn stop_role(control: &Control, role: Role, running: RunningRole) {
+    info!("Stopping {}", role);
+    // Stopped first, so a clipboard opened meanwhile is unregistered by `LazyClipboard::get`
+    running.stop.send_replace(true);
+    control.unregister(role.name());
+    running.task.await.ok();
+    control.status.remove(role.name());
+}
+
+/// Run all roles until shutdown. When the configuration is reloaded only the roles whose
+/// configuration changed are restarted, an invalid configuration is ignored.
+async fn svc_main(mut args: Args, control: Control) -> anyhow::Result<()> {
+    if args.roles.is_empty() {
+        anyhow::bail!("No role specified");
+    }
+    control.stop_all();
+    control.status.clear();
+    let mut history = history::open(args.history.as_ref());
+    let mut running: BTreeMap<Role, RunningRole> = BTreeMap::new();
+    let mut shutdown = control.shutdown_receiver();
+    loop {
+        for &role in &args.roles {
+            if let Entry::Vacant(entry) = running.entry(role) {
+                if let Some(started) = start_role(role, &args, history.clone(), &control) {
+                    entry.insert(started);
                 }
             }
-        }));
-    }
-    for r in futures::future::join_all(tasks.into_iter())
-        .await
-        .into_iter()
-    {
-        r??;
+        }
+        tokio::select! {
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            _ = control.reload_requested() => {}
+        }
+        info!("Reloading configuration from {:?}", args.config_path);
+        let new_args = match clip_sync_config::reload(&args) {
+            Ok(new_args) => new_args,
+            Err(e) => {
+                error!("Invalid configuration, keeping the current one: {}", e);
+                continue;
+            }
+        };
+        let changed: Vec<Role> = running
+            .iter()
+            .filter(|(role, started)| {
+                !new_args.roles.contains(role) || role_changed(**role, &started.args, &new_args)
+            })
+            .map(|(role, _)| *role)
+            .collect();
+        for role in changed {
+            if let Some(started) = running.remove(&role) {
+                stop_role(&control, role, started).await;
+            }
+        }
+        if new_args.history != args.history {
+            history = history::open(new_args.history.as_ref());
+        }
+        args = new_args;
     }
+    let control = &control;
+    futures::future::join_all(
+        running
+            .into_iter()
+            .map(|(role, started)| async move { stop_role(control, role, started).await }),
+    )
+    .await;
     Ok(())
 }
 
@@ -77,6 +326,10 @@ async fn svc_main(args: Args) -> anyhow::Result<()> {
 mod tray {
     use tray_item::{IconSource, TrayItem};
 
+    /// How often the connection status in the menu is refreshed.
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
     #[cfg(target_os = "macos")]
     fn get_app_icon() -> IconSource {
         IconSource::Data {
@@ -111,6 +364,7 @@ mod tray {
 
     pub fn run_tray(
         #[cfg(feature = "websocket")] server_url: Option<String>,
+        status: clip_sync::supervisor::StatusBoard,
     ) -> anyhow::Result<()> {
         let mut tray = TrayItem::new("ClipSync", get_app_icon())?;
 
@@ -122,6 +376,19 @@ mod tray {
                     webbrowser::open(url).ok();
                 }
             })?;
+            // The menu can't be updated once it's displayed, so the status is a notification
+            tray.inner_mut().add_menu_item("Show Status", move || {
+                let script = format!(
+                    "display notification {:?} with title \"ClipSync\"",
+                    status.summary()
+                );
+                if let Err(e) = std::process::Command::new("osascript")
+                    .args(["-e", &script])
+                    .status()
+                {
+                    log::warn!("Failed to show the status: {}", e);
+                }
+            })?;
             tray.inner_mut().add_quit_item("Quit");
             tray.inner_mut().display();
         }
@@ -138,12 +405,25 @@ mod tray {
             tray.add_menu_item("Open Portal", move || {
                 tx_clone.send(Message::Portal).unwrap();
             })?;
+            let mut summary = status.summary().replace('\n', ", ");
+            let status_label = tray.add_label_with_id(&summary)?;
             tray.add_menu_item("Quit", move || {
                 tx.send(Message::Quit).unwrap();
             })?;
-            #[allow(clippy::while_let_loop)] // In case we want to add more menu items
             loop {
-                match rx.recv()? {
+                let message = match rx.recv_timeout(STATUS_INTERVAL) {
+                    Ok(message) => message,
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                        let current = status.summary().replace('\n', ", ");
+                        if current != summary {
+                            tray.set_label(&current, status_label)?;
+                            summary = current;
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                match message {
                     Message::Portal =>
                     {
                         #[cfg(feature = "websocket")]
@@ -163,31 +443,63 @@ mod tray {
 
 fn main() -> anyhow::Result<()> {
     let args = clip_sync_config::parse()?;
+    let control = Control::default();
 
     #[cfg(all(feature = "tray", feature = "websocket"))]
     let server_url = args.get_server_url();
 
-    #[allow(unused_variables)]
+    let status = control.status.clone();
+    let runtime_control = control.clone();
     let join_handler = std::thread::spawn(move || {
+        let control = runtime_control;
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .expect("Failed to create runtime");
         runtime.block_on(async {
+            let mut args = args;
+            let socket_path = args.get_control_socket();
+            let control_clone = control.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control::serve(control_clone, socket_path).await {
+                    log::warn!("Control socket is disabled: {}", e);
+                }
+            });
+            let control_clone = control.clone();
+            tokio::spawn(async move {
+                clip_sync_config::shutdown_signal().await;
+                info!("Shutting down");
+                control_clone.shutdown();
+            });
+            let reload = control.clone();
+            tokio::spawn(clip_sync_config::reload_on_sighup(move || {
+                reload.request_reload()
+            }));
+            let reload = control.clone();
+            tokio::spawn(clip_sync_config::reload_on_change(
+                move || reload.request_reload(),
+                args.config_path.clone(),
+            ));
             loop {
-                let args_clone = args.clone();
-                match svc_main(args_clone).await {
-                    Ok(_) => {
-                        info!("Service exited normally");
-                        break;
-                    }
-                    Err(e) => {
-                        log::error!("Service exited with error: {}", e);
-                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    }
+                if let Err(e) = svc_main(args.clone(), control.clone()).await {
+                    error!("Service exited with error: {}", e);
+                }
+                // Wait for the configuration to be fixed
+                let mut shutdown = control.shutdown_receiver();
+                tokio::select! {
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+                    _ = control.reload_requested() => {}
+                }
+                match clip_sync_config::reload(&args) {
+                    Ok(new_args) => args = new_args,
+                    Err(e) => error!("Invalid configuration: {}", e),
                 }
             }
-        })
+        });
+        info!("Stopped");
+        // The tray keeps the main thread busy and can't be stopped from here
+        #[cfg(feature = "tray")]
+        std::process::exit(0);
     });
 
     #[cfg(feature = "tray")]
@@ -195,13 +507,12 @@ fn main() -> anyhow::Result<()> {
         tray::run_tray(
             #[cfg(feature = "websocket")]
             server_url,
+            status,
         )?;
+        control.shutdown();
     }
 
-    #[cfg(not(feature = "tray"))]
-    {
-        join_handler.join().unwrap();
-    }
+    join_handler.join().unwrap();
 
     Ok(())
 }
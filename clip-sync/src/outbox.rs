@@ -0,0 +1,155 @@
+use std::{collections::VecDeque, path::PathBuf};
+
+use client_interface::ClipboardContent;
+use clip_sync_config::{OutboxConfig, OutboxKeep};
+use log::{debug, warn};
+
+/// Clipboard updates waiting to be published, optionally persisted to disk until delivered.
+pub struct Outbox {
+    entries: VecDeque<ClipboardContent>,
+    keep: OutboxKeep,
+    max_entries: usize,
+    path: Option<PathBuf>,
+}
+
+impl Outbox {
+    pub fn open(config: &OutboxConfig, name: &str) -> Self {
+        let path = config
+            .path
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.outbox", name)));
+        let mut outbox = Self {
+            entries: VecDeque::new(),
+            keep: config.keep,
+            max_entries: config.max_entries.max(1),
+            path,
+        };
+        outbox.load();
+        outbox
+    }
+
+    /// Queue a new clipboard update.
+    pub fn push(&mut self, content: ClipboardContent) {
+        if self.keep == OutboxKeep::Latest {
+            self.entries.clear();
+        }
+        self.entries.push_back(content);
+        self.truncate();
+        self.save();
+    }
+
+    /// The oldest update, it stays queued until it's [delivered](Self::delivered).
+    pub fn front(&self) -> Option<ClipboardContent> {
+        self.entries.front().cloned()
+    }
+
+    /// Remove an update returned by [`front`](Self::front) once it's published. It may be gone
+    /// already if a newer update replaced it.
+    pub fn delivered(&mut self, content: &ClipboardContent) {
+        if self.entries.front() == Some(content) {
+            self.entries.pop_front();
+            self.save();
+        }
+    }
+
+    fn truncate(&mut self) {
+        while self.entries.len() > self.max_entries {
+            debug!("Outbox is full, dropping the oldest entry");
+            self.entries.pop_front();
+        }
+    }
+
+    fn load(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if !path.exists() {
+            return;
+        }
+        match std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(bincode::deserialize::<VecDeque<ClipboardContent>>(&bytes)?))
+        {
+            Ok(entries) => {
+                debug!("Loaded {} entries from outbox {:?}", entries.len(), path);
+                self.entries = entries;
+                self.truncate();
+            }
+            Err(e) => {
+                warn!("Failed to load outbox {:?}: {}", path, e);
+            }
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = if self.entries.is_empty() {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        } else {
+            path.parent()
+                .map(std::fs::create_dir_all)
+                .transpose()
+                .map_err(anyhow::Error::from)
+                .and_then(|_| Ok(bincode::serialize(&self.entries)?))
+                .and_then(|bytes| Ok(std::fs::write(path, bytes)?))
+        };
+        if let Err(e) = result {
+            warn!("Failed to save outbox {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> ClipboardContent {
+        ClipboardContent::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_outbox() {
+        let dir = std::env::temp_dir().join(format!("clip-sync-outbox-{}", std::process::id()));
+        let config = OutboxConfig {
+            keep: OutboxKeep::All,
+            max_entries: 2,
+            path: Some(dir.clone()),
+        };
+        let mut outbox = Outbox::open(&config, "test");
+        outbox.push(text("a"));
+        outbox.push(text("b"));
+        outbox.push(text("c"));
+        // Not delivered yet, so it's still queued
+        assert_eq!(outbox.front(), Some(text("b")));
+
+        // Entries survive a restart, in order
+        let mut outbox = Outbox::open(&config, "test");
+        assert_eq!(outbox.front(), Some(text("b")));
+        outbox.delivered(&text("b"));
+        assert_eq!(outbox.front(), Some(text("c")));
+        outbox.delivered(&text("c"));
+        assert_eq!(outbox.front(), None);
+        assert!(!dir.join("test.outbox").exists());
+
+        let mut outbox = Outbox::open(
+            &OutboxConfig {
+                keep: OutboxKeep::Latest,
+                ..config
+            },
+            "test",
+        );
+        outbox.push(text("a"));
+        outbox.push(text("b"));
+        // "a" was replaced while it was published
+        outbox.delivered(&text("a"));
+        assert_eq!(outbox.front(), Some(text("b")));
+        outbox.delivered(&text("b"));
+        assert_eq!(outbox.front(), None);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use client_interface::{Backoff, ConnectionState, ConnectionStatus, ReconnectConfig};
+use log::{info, warn};
+use tokio::sync::watch;
+
+/// A connection that stayed up for this long resets the backoff delay.
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+/// How long a task gets to close its connection after a shutdown is requested.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Connection states of all running roles, shared with the tray and the status command.
+#[derive(Clone, Default)]
+pub struct StatusBoard(Arc<Mutex<BTreeMap<String, ConnectionStatus>>>);
+
+impl StatusBoard {
+    pub fn reporter(&self, name: &str) -> StatusReporter {
+        StatusReporter {
+            name: name.to_string(),
+            board: self.clone(),
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, ConnectionStatus> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.0.lock().unwrap().remove(name);
+    }
+
+    pub fn summary(&self) -> String {
+        let snapshot = self.snapshot();
+        if snapshot.is_empty() {
+            return "No role is running".to_string();
+        }
+        snapshot
+            .iter()
+            .map(|(name, status)| format!("{}: {}", name, status))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[derive(Clone)]
+pub struct StatusReporter {
+    name: String,
+    board: StatusBoard,
+}
+
+impl StatusReporter {
+    fn update(&self, f: impl FnOnce(&mut ConnectionStatus)) {
+        let mut board = self.board.0.lock().unwrap();
+        let status = board
+            .entry(self.name.clone())
+            .or_insert_with(|| ConnectionStatus {
+                state: ConnectionState::Connecting,
+                since: now(),
+                attempts: 0,
+                last_error: None,
+            });
+        f(status);
+    }
+
+    fn connecting(&self) {
+        self.update(|status| {
+            status.state = ConnectionState::Connecting;
+            status.since = now();
+            status.attempts += 1;
+        });
+    }
+
+    /// Called by the supervised task once its connection is established.
+    pub fn connected(&self) {
+        info!("{} connected", self.name);
+        self.update(|status| {
+            status.state = ConnectionState::Connected;
+            status.since = now();
+            status.attempts = 0;
+        });
+    }
+
+    fn backing_off(&self, delay: Duration, error: String) {
+        self.update(|status| {
+            status.state = ConnectionState::BackingOff {
+                retry_at: now() + delay.as_secs_f64().ceil() as i64,
+            };
+            status.since = now();
+            status.last_error = Some(error);
+        });
+    }
+
+    fn is_connected(&self) -> bool {
+        self.board
+            .0
+            .lock()
+            .unwrap()
+            .get(&self.name)
+            .map(|status| status.state == ConnectionState::Connected)
+            .unwrap_or_default()
+    }
+}
+
+/// Run `task` until shutdown, restarting it with backoff whenever it fails or exits.
+///
+/// On shutdown the task is expected to return by itself, it is dropped after a grace period.
+pub async fn supervise<F, Fut>(
+    name: &str,
+    status: StatusBoard,
+    config: ReconnectConfig,
+    mut shutdown: watch::Receiver<bool>,
+    mut task: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(StatusReporter) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let reporter = status.reporter(name);
+    let mut backoff = Backoff::new(&config);
+    loop {
+        info!("Starting {}", name);
+        reporter.connecting();
+        let started = Instant::now();
+        let result = tokio::select! {
+            result = task(reporter.clone()) => result,
+            _ = async {
+                shutdown.wait_for(|shutdown| *shutdown).await.ok();
+                tokio::time::sleep(SHUTDOWN_GRACE).await;
+            } => Ok(()),
+        };
+        if *shutdown.borrow() {
+            info!("{} stopped", name);
+            return Ok(());
+        }
+        let error = match result {
+            Ok(_) => "exited unexpectedly".to_string(),
+            Err(e) => e.to_string(),
+        };
+        if reporter.is_connected() && started.elapsed() >= STABLE_CONNECTION {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        warn!("{} failed: {}, restarting in {:?}", name, error, delay);
+        reporter.backing_off(delay, error);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.wait_for(|shutdown| *shutdown) => {
+                info!("{} stopped", name);
+                return Ok(());
+            }
+        }
+    }
+}
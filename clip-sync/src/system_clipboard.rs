@@ -0,0 +1,202 @@
+use std::{sync::Mutex, time::Duration};
+
+use clipboard_master::{CallbackResult, ClipboardHandler, Master};
+use log::{debug, warn};
+use tokio::sync::watch;
+
+use client_interface::ImageData;
+
+/// Access to the system clipboard, so the sync logic doesn't depend on a display.
+pub trait SystemClipboard: Send + Sync + 'static {
+    fn get_text(&self) -> anyhow::Result<Option<String>>;
+    fn get_image(&self) -> anyhow::Result<Option<ImageData>>;
+    fn set_text(&self, text: String) -> anyhow::Result<()>;
+    fn set_image(&self, image: ImageData) -> anyhow::Result<()>;
+    /// Block the current thread and call `on_change` after every clipboard change,
+    /// until it returns `false` or `stopped` turns `true`.
+    fn watch(
+        &self,
+        on_change: Box<dyn FnMut() -> bool + Send>,
+        stopped: watch::Receiver<bool>,
+    ) -> anyhow::Result<()>;
+}
+
+/// The real system clipboard.
+pub struct ArboardClipboard(Mutex<arboard::Clipboard>);
+
+impl ArboardClipboard {
+    pub fn new() -> anyhow::Result<Self> {
+        let provider = arboard::Clipboard::new().map_err(|e| {
+            anyhow::anyhow!("Failed to initialize clipboard provider: {}", e.to_string())
+        })?;
+        Ok(Self(Mutex::new(provider)))
+    }
+}
+
+impl SystemClipboard for ArboardClipboard {
+    fn get_text(&self) -> anyhow::Result<Option<String>> {
+        match self.0.lock().unwrap().get_text() {
+            Ok(text) => Ok(Some(text)),
+            Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(e) => {
+                debug!("Failed to get text from clipboard: {}", e);
+                Err(anyhow::anyhow!("Failed to get text from clipboard"))
+            }
+        }
+    }
+
+    fn get_image(&self) -> anyhow::Result<Option<ImageData>> {
+        match self.0.lock().unwrap().get_image() {
+            Ok(img) => Ok(Some(ImageData {
+                width: img.width,
+                height: img.height,
+                data: img.bytes.to_vec(),
+            })),
+            Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(e) => {
+                debug!("Failed to get image from clipboard: {}", e);
+                Err(anyhow::anyhow!("Failed to get image from clipboard"))
+            }
+        }
+    }
+
+    fn set_text(&self, text: String) -> anyhow::Result<()> {
+        let mut provider = self.0.lock().unwrap();
+        provider.clear()?;
+        Ok(provider.set_text(text)?)
+    }
+
+    fn set_image(&self, image: ImageData) -> anyhow::Result<()> {
+        let mut provider = self.0.lock().unwrap();
+        provider.clear()?;
+        Ok(provider.set_image(arboard::ImageData {
+            bytes: image.data.into(),
+            width: image.width,
+            height: image.height,
+        })?)
+    }
+
+    fn watch(
+        &self,
+        on_change: Box<dyn FnMut() -> bool + Send>,
+        mut stopped: watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        struct Handler(Box<dyn FnMut() -> bool + Send>);
+
+        impl ClipboardHandler for Handler {
+            fn on_clipboard_change(&mut self) -> CallbackResult {
+                if (self.0)() {
+                    CallbackResult::Next
+                } else {
+                    CallbackResult::Stop
+                }
+            }
+
+            fn on_clipboard_error(&mut self, error: std::io::Error) -> CallbackResult {
+                warn!("Error: {}", error);
+                CallbackResult::Next
+            }
+        }
+
+        let mut master = Master::new(Handler(on_change))?;
+        let shutdown = master.shutdown_channel();
+        // Otherwise the watcher would only notice the stop on the next clipboard change
+        std::thread::spawn(move || {
+            futures::executor::block_on(stopped.wait_for(|stopped| *stopped)).ok();
+            shutdown.signal();
+        });
+        Ok(master.run()?)
+    }
+}
+
+/// A clipboard that only lives in memory for tests, every `set_*` call counts as a change,
+/// including the ones made before `watch` is called.
+#[derive(Default)]
+pub struct MemoryClipboard {
+    // Change counter and the current content
+    state: Mutex<(u64, Option<client_interface::ClipboardContent>)>,
+    changed: std::sync::Condvar,
+}
+
+impl MemoryClipboard {
+    fn set(&self, content: client_interface::ClipboardContent) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        state.1 = Some(content);
+        self.changed.notify_all();
+    }
+}
+
+impl SystemClipboard for MemoryClipboard {
+    fn get_text(&self) -> anyhow::Result<Option<String>> {
+        match &self.state.lock().unwrap().1 {
+            Some(client_interface::ClipboardContent::Text(text)) => Ok(Some(text.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_image(&self) -> anyhow::Result<Option<ImageData>> {
+        match &self.state.lock().unwrap().1 {
+            Some(client_interface::ClipboardContent::Image(image)) => Ok(Some(image.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_text(&self, text: String) -> anyhow::Result<()> {
+        self.set(client_interface::ClipboardContent::Text(text));
+        Ok(())
+    }
+
+    fn set_image(&self, image: ImageData) -> anyhow::Result<()> {
+        self.set(client_interface::ClipboardContent::Image(image));
+        Ok(())
+    }
+
+    fn watch(
+        &self,
+        mut on_change: Box<dyn FnMut() -> bool + Send>,
+        stopped: watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        let mut seen = 0;
+        loop {
+            let (state, _) = self
+                .changed
+                .wait_timeout_while(
+                    self.state.lock().unwrap(),
+                    Duration::from_millis(50),
+                    |state| state.0 == seen,
+                )
+                .unwrap();
+            if *stopped.borrow() {
+                return Ok(());
+            }
+            if state.0 == seen {
+                continue;
+            }
+            seen = state.0;
+            drop(state);
+            if !on_change() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_watching() {
+        let clipboard = std::sync::Arc::new(MemoryClipboard::default());
+        let (stop, stopped) = watch::channel(false);
+        let watcher = std::thread::spawn({
+            let clipboard = clipboard.clone();
+            move || clipboard.watch(Box::new(|| true), stopped)
+        });
+        clipboard.set_text("a".to_string()).unwrap();
+        // Stops without waiting for another change
+        stop.send_replace(true);
+        watcher.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use client_interface::{
+    ClipSyncClient, ClipboardRecord, ClipboardSink, ConnectionState, DeviceFilter,
+};
+use clip_sync::{
+    clipboard_handler::{self, ClipboardContext, SyncState},
+    outbox::Outbox,
+    supervisor::{supervise, StatusBoard},
+    system_clipboard::{MemoryClipboard, SystemClipboard},
+};
+use clip_sync_config::{OutboxConfig, ReconnectConfig};
+use tokio::{sync::watch, task::JoinHandle};
+use websocket_client::{ClientConfig, WebsocketClipSyncClient};
+
+use crate::TIMEOUT;
+
+/// The `websocket-client` role of `clip-sync`, supervised like in the daemon but with a
+/// [`MemoryClipboard`] instead of the system clipboard.
+pub struct TestDaemon {
+    pub clipboard: Arc<MemoryClipboard>,
+    pub status: StatusBoard,
+    name: String,
+    /// Everything the daemon published, over all connections
+    published: Arc<Mutex<Vec<ClipboardRecord>>>,
+    context: ClipboardContext,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Records what is published before passing it on.
+struct RecordingSink<S> {
+    inner: S,
+    published: Arc<Mutex<Vec<ClipboardRecord>>>,
+}
+
+impl<S: ClipboardSink> ClipboardSink for RecordingSink<S> {
+    async fn publish(&mut self, data: Option<ClipboardRecord>) -> anyhow::Result<()> {
+        self.inner.publish(data.clone()).await?;
+        if let Some(data) = data {
+            self.published.lock().unwrap().push(data);
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.inner.close().await
+    }
+}
+
+impl TestDaemon {
+    /// Start syncing with the server `config` points to, the first reconnect is after at most
+    /// `initial_delay_ms`.
+    pub fn start(config: ClientConfig, initial_delay_ms: u64) -> Self {
+        let name = config.client_id.clone().unwrap_or_default();
+        let clipboard = Arc::new(MemoryClipboard::default());
+        let context = ClipboardContext::new(
+            clipboard.clone(),
+            name.clone(),
+            DeviceFilter::default(),
+            Outbox::open(&OutboxConfig::default(), &name),
+            None,
+            SyncState::default(),
+        );
+        let status = StatusBoard::default();
+        let published = Arc::new(Mutex::new(vec![]));
+        let (stop, shutdown) = watch::channel(false);
+        let reconnect = ReconnectConfig {
+            initial_delay_ms,
+            max_delay_secs: 1,
+        };
+        let task = tokio::spawn({
+            let name = name.clone();
+            let status = status.clone();
+            let context = context.clone();
+            let published = published.clone();
+            async move {
+                supervise(&name, status, reconnect, shutdown, |reporter| {
+                    let config = config.clone();
+                    let context = context.clone();
+                    let published = published.clone();
+                    async move {
+                        let (sender_id, source, sink) =
+                            WebsocketClipSyncClient::connect(config).await?;
+                        reporter.connected();
+                        let sink = RecordingSink {
+                            inner: sink,
+                            published,
+                        };
+                        clipboard_handler::start(sender_id, source, sink, context).await
+                    }
+                })
+                .await
+            }
+        });
+        Self {
+            clipboard,
+            status,
+            name,
+            published,
+            context,
+            stop,
+            task,
+        }
+    }
+
+    pub fn published(&self) -> Vec<ClipboardRecord> {
+        self.published.lock().unwrap().clone()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.status
+            .snapshot()
+            .get(&self.name)
+            .is_some_and(|status| status.state == ConnectionState::Connected)
+    }
+
+    pub async fn wait_connected(&self) -> anyhow::Result<()> {
+        wait_until("connect", || Ok(self.is_connected())).await
+    }
+
+    /// Wait until the daemon noticed that the connection is gone.
+    pub async fn wait_disconnected(&self) -> anyhow::Result<()> {
+        wait_until("notice the lost connection", || Ok(!self.is_connected())).await
+    }
+
+    /// Wait until the clipboard holds `text`.
+    pub async fn wait_for_text(&self, text: &str) -> anyhow::Result<()> {
+        wait_until(&format!("receive {:?}", text), || {
+            Ok(self.clipboard.get_text()?.as_deref() == Some(text))
+        })
+        .await
+    }
+}
+
+async fn wait_until(
+    what: &str,
+    mut done: impl FnMut() -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    while !done()? {
+        if tokio::time::Instant::now() > deadline {
+            anyhow::bail!("The daemon didn't {} in time", what);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok(())
+}
+
+impl Drop for TestDaemon {
+    fn drop(&mut self) {
+        self.context.stop();
+        self.stop.send_replace(true);
+        self.task.abort();
+    }
+}
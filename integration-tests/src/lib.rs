@@ -0,0 +1,77 @@
+//! In-process test harness, starts the websocket server and an MQTT broker stand-in on
+//! ephemeral ports so the real clients and the sync loop of the daemon can be tested end to end.
+
+use std::time::Duration;
+
+use client_interface::{ClipboardContent, ClipboardRecord, ClipboardSource, ImageData};
+
+mod daemon;
+mod mqtt_broker;
+mod server;
+
+pub use daemon::TestDaemon;
+pub use mqtt_broker::{BackgroundSource, MqttBroker};
+pub use server::TestServer;
+
+/// How long to wait for something that is expected to happen.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before deciding that something didn't happen.
+pub const QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// Pick a free local port, the listener is closed so the port can be bound again.
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("Failed to find a free port")
+}
+
+/// Wait until something is listening on `port`.
+pub async fn wait_for_port(port: u16) -> anyhow::Result<()> {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    while tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_err()
+    {
+        if tokio::time::Instant::now() > deadline {
+            anyhow::bail!("Nothing is listening on port {}", port);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok(())
+}
+
+/// Receive the next record, fails if nothing arrives in time.
+pub async fn recv(source: &mut impl ClipboardSource) -> anyhow::Result<ClipboardRecord> {
+    tokio::time::timeout(TIMEOUT, source.poll())
+        .await
+        .map_err(|_| anyhow::anyhow!("No clipboard record received"))?
+}
+
+/// Fails if a record arrives during the quiet period.
+pub async fn expect_nothing(source: &mut impl ClipboardSource) -> anyhow::Result<()> {
+    match tokio::time::timeout(QUIET_PERIOD, source.poll()).await {
+        Ok(Ok(record)) => anyhow::bail!("Unexpected clipboard record {:?}", record),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Ok(()),
+    }
+}
+
+pub fn text_record(source: &str, text: &str) -> ClipboardRecord {
+    ClipboardRecord {
+        source: source.to_string(),
+        content: ClipboardContent::Text(text.to_string()),
+    }
+}
+
+/// A small RGBA image with some variation, so PNG round trips are meaningful.
+pub fn test_image(width: usize, height: usize) -> ImageData {
+    ImageData {
+        width,
+        height,
+        data: (0..width * height * 4)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect(),
+    }
+}
@@ -0,0 +1,626 @@
+//! An in-process MQTT broker for the tests.
+//!
+//! It only implements what `MqttClipSyncClient` needs to exchange entries, see [`MqttBroker`]
+//! for what it leaves out. Tests of `qos` and `persistent-session` need a real broker.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use log::{debug, warn};
+use rumqttc::{
+    mqttbytes::{self, v4},
+    v5::mqttbytes::{self as mqttbytes5, v5},
+    ConnectReturnCode, QoS, SubscribeReasonCode,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+
+use client_interface::{ClipSyncClient, ClipboardRecord, ClipboardSource};
+use mqtt_client::{MqttClipSyncClient, MqttPublisher, MqttSubscriber};
+
+use crate::{wait_for_port, TIMEOUT};
+
+const MAX_PACKET_SIZE: usize = 1024 * 1024 * 100;
+
+/// A minimal MQTT 3.1.1 and 5 broker, just enough for `MqttClipSyncClient`.
+///
+/// Subscriptions are exact topic matches, `+` for one level or `#` for everything. Retained
+/// messages and last wills are supported, and for MQTT 5 clients publish properties, message
+/// expiry and `nolocal`.
+///
+/// Its limits:
+/// - Every subscription is granted and delivered at QoS 0. QoS 1 and 2 publishes are acknowledged
+///   right away, and nothing is ever redelivered.
+/// - Sessions are never kept, `session_present` is always false and entries published while a
+///   client is offline are lost, whatever `persistent-session` is set to.
+/// - Passwords are the only authentication, and there are no topic permissions.
+pub struct MqttBroker {
+    pub port: u16,
+    /// Client ids of all subscriptions, in order
+    subscribed: Arc<Mutex<Vec<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl MqttBroker {
+    pub async fn start() -> anyhow::Result<Self> {
+        Self::start_with_login(None).await
+    }
+
+    /// Only accept connections with this username and password.
+    pub async fn start_with_login(login: Option<(&str, &str)>) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let login = login.map(|(username, password)| (username.to_string(), password.to_string()));
+        let subscribed = Arc::new(Mutex::new(vec![]));
+        let (sender, _) = broadcast::channel::<Message>(32);
+        let retained = Retained::default();
+        let subscribed_clone = subscribed.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                let retained = retained.clone();
+                let login = login.clone();
+                let subscribed = subscribed_clone.clone();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        handle_connection(stream, sender, retained, login, subscribed).await
+                    {
+                        debug!("MQTT connection closed: {}", e);
+                    }
+                });
+            }
+        });
+        wait_for_port(port).await?;
+        Ok(Self {
+            port,
+            subscribed,
+            task,
+        })
+    }
+
+    pub fn client_config(&self, client_id: &str) -> mqtt_client::MqttClientConfig {
+        mqtt_client::MqttClientConfig {
+            mqtt_server_addr: "127.0.0.1".to_string(),
+            mqtt_server_port: self.port,
+            mqtt_client_id: Some(client_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Connect a client, and wait until it has subscribed so it doesn't miss anything.
+    pub async fn connect(
+        &self,
+        config: mqtt_client::MqttClientConfig,
+    ) -> anyhow::Result<(String, BackgroundSource, MqttPublisher)> {
+        let client_id = config.mqtt_client_id.clone().unwrap_or_default();
+        let seen = self.subscribed.lock().unwrap().len();
+        let (sender_id, source, sink) = MqttClipSyncClient::connect(config).await?;
+        let source = BackgroundSource::new(source);
+        self.wait_subscribed_since(&client_id, seen).await?;
+        Ok((sender_id, source, sink))
+    }
+
+    /// Wait until a client that this test doesn't poll itself has subscribed.
+    pub async fn wait_subscribed(&self, client_id: &str) -> anyhow::Result<()> {
+        self.wait_subscribed_since(client_id, 0).await
+    }
+
+    async fn wait_subscribed_since(&self, client_id: &str, seen: usize) -> anyhow::Result<()> {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        while !self.subscribed.lock().unwrap()[seen..]
+            .iter()
+            .any(|id| id == client_id)
+        {
+            if tokio::time::Instant::now() > deadline {
+                anyhow::bail!("MQTT client '{}' didn't subscribe", client_id);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        Ok(())
+    }
+}
+
+/// Polls an MQTT subscriber in a background task, the client only sends and receives
+/// anything while its event loop is polled.
+pub struct BackgroundSource {
+    receiver: mpsc::UnboundedReceiver<anyhow::Result<ClipboardRecord>>,
+    task: JoinHandle<()>,
+}
+
+impl BackgroundSource {
+    pub fn new(mut source: MqttSubscriber) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            loop {
+                let result = source.poll().await;
+                let failed = result.is_err();
+                if sender.send(result).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self { receiver, task }
+    }
+}
+
+impl ClipboardSource for BackgroundSource {
+    async fn poll(&mut self) -> anyhow::Result<ClipboardRecord> {
+        match self.receiver.recv().await {
+            Some(result) => result,
+            None => anyhow::bail!("MQTT client stopped"),
+        }
+    }
+}
+
+impl Drop for BackgroundSource {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for MqttBroker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    V4,
+    V5,
+}
+
+/// A published message, whichever version the publisher uses.
+#[derive(Debug, Clone)]
+struct Message {
+    topic: String,
+    payload: Bytes,
+    retain: bool,
+    /// Only sent by MQTT 5 clients, and only forwarded to them
+    properties: Option<v5::PublishProperties>,
+    /// Client id of the publisher, for `nolocal` subscriptions
+    sender: String,
+    received: Instant,
+}
+
+impl Message {
+    /// The properties with the expiry interval reduced by the time it waited on the broker,
+    /// `None` if it expired.
+    fn properties(&self) -> Option<Option<v5::PublishProperties>> {
+        let mut properties = self.properties.clone();
+        if let Some(expiry) = properties
+            .as_mut()
+            .and_then(|p| p.message_expiry_interval.as_mut())
+        {
+            let elapsed = self.received.elapsed().as_secs() as u32;
+            if elapsed >= *expiry {
+                return None;
+            }
+            *expiry -= elapsed;
+        }
+        Some(properties)
+    }
+}
+
+struct Filter {
+    path: String,
+    /// Don't forward messages of the subscriber itself
+    nolocal: bool,
+}
+
+struct Connect {
+    client_id: String,
+    login: Option<(String, String)>,
+    will: Option<Message>,
+}
+
+enum Packet {
+    Subscribe {
+        pkid: u16,
+        filters: Vec<Filter>,
+    },
+    Publish {
+        pkid: u16,
+        qos: u8,
+        message: Box<Message>,
+    },
+    PubRel {
+        pkid: u16,
+    },
+    PingReq,
+    Disconnect,
+    Other(String),
+}
+
+enum Reply<'a> {
+    ConnAck { success: bool },
+    SubAck { pkid: u16, count: usize },
+    PubAck { pkid: u16 },
+    PubRec { pkid: u16 },
+    PubComp { pkid: u16 },
+    PingResp,
+    Publish(&'a Message, Option<v5::PublishProperties>),
+}
+
+/// Read more bytes into `buffer`.
+async fn fill(reader: &mut OwnedReadHalf, buffer: &mut BytesMut) -> anyhow::Result<()> {
+    if reader.read_buf(buffer).await? == 0 {
+        anyhow::bail!("Connection closed by client");
+    }
+    Ok(())
+}
+
+/// The protocol level of the complete CONNECT packet at the start of `buffer`.
+fn protocol_level(buffer: &[u8]) -> Option<u8> {
+    // The remaining length takes 1 to 4 bytes, the last one without the high bit, then comes
+    // the protocol name "MQTT" with its 2 byte length
+    let length_bytes = buffer.get(1..)?.iter().position(|b| b & 0x80 == 0)? + 1;
+    buffer.get(1 + length_bytes + 6).copied()
+}
+
+/// Read the CONNECT packet, it tells the version of the client.
+async fn read_connect(
+    reader: &mut OwnedReadHalf,
+    buffer: &mut BytesMut,
+) -> anyhow::Result<(Version, Connect)> {
+    loop {
+        match mqttbytes::check(buffer.iter(), MAX_PACKET_SIZE) {
+            Ok(_) => break,
+            Err(mqttbytes::Error::InsufficientBytes(_)) => fill(reader, buffer).await?,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // The v4 parser reads a v5 CONNECT without failing, but wrongly
+    if protocol_level(buffer) == Some(5) {
+        let v5::Packet::Connect(connect, will, login) =
+            v5::Packet::read(buffer, Some(MAX_PACKET_SIZE))?
+        else {
+            anyhow::bail!("Expected a CONNECT packet");
+        };
+        let will = will.map(|will| Message {
+            topic: String::from_utf8_lossy(&will.topic).to_string(),
+            payload: will.message,
+            retain: will.retain,
+            properties: None,
+            sender: connect.client_id.clone(),
+            received: Instant::now(),
+        });
+        let connect = Connect {
+            login: login.map(|l| (l.username, l.password)),
+            client_id: connect.client_id,
+            will,
+        };
+        return Ok((Version::V5, connect));
+    }
+    let v4::Packet::Connect(connect) = v4::read(buffer, MAX_PACKET_SIZE)? else {
+        anyhow::bail!("Expected a CONNECT packet");
+    };
+    let will = connect.last_will.map(|will| Message {
+        topic: will.topic,
+        payload: will.message,
+        retain: will.retain,
+        properties: None,
+        sender: connect.client_id.clone(),
+        received: Instant::now(),
+    });
+    let connect = Connect {
+        login: connect.login.map(|l| (l.username, l.password)),
+        client_id: connect.client_id,
+        will,
+    };
+    Ok((Version::V4, connect))
+}
+
+async fn read_packet(
+    reader: &mut OwnedReadHalf,
+    buffer: &mut BytesMut,
+    version: Version,
+    client_id: &str,
+) -> anyhow::Result<Packet> {
+    let message = |topic: String, payload: Bytes, retain, properties| Message {
+        topic,
+        payload,
+        retain,
+        properties,
+        sender: client_id.to_string(),
+        received: Instant::now(),
+    };
+    loop {
+        match version {
+            Version::V4 => match v4::read(buffer, MAX_PACKET_SIZE) {
+                Ok(packet) => {
+                    return Ok(match packet {
+                        v4::Packet::Subscribe(subscribe) => Packet::Subscribe {
+                            pkid: subscribe.pkid,
+                            filters: subscribe
+                                .filters
+                                .into_iter()
+                                .map(|f| Filter {
+                                    path: f.path,
+                                    nolocal: false,
+                                })
+                                .collect(),
+                        },
+                        v4::Packet::Publish(p) => Packet::Publish {
+                            pkid: p.pkid,
+                            qos: p.qos as u8,
+                            message: Box::new(message(p.topic, p.payload, p.retain, None)),
+                        },
+                        v4::Packet::PubRel(p) => Packet::PubRel { pkid: p.pkid },
+                        v4::Packet::PingReq => Packet::PingReq,
+                        v4::Packet::Disconnect => Packet::Disconnect,
+                        packet => Packet::Other(format!("{:?}", packet)),
+                    })
+                }
+                Err(mqttbytes::Error::InsufficientBytes(_)) => fill(reader, buffer).await?,
+                Err(e) => return Err(e.into()),
+            },
+            Version::V5 => match v5::Packet::read(buffer, Some(MAX_PACKET_SIZE)) {
+                Ok(packet) => {
+                    return Ok(match packet {
+                        v5::Packet::Subscribe(subscribe) => Packet::Subscribe {
+                            pkid: subscribe.pkid,
+                            filters: subscribe
+                                .filters
+                                .into_iter()
+                                .map(|f| Filter {
+                                    path: f.path,
+                                    nolocal: f.nolocal,
+                                })
+                                .collect(),
+                        },
+                        v5::Packet::Publish(p) => Packet::Publish {
+                            pkid: p.pkid,
+                            qos: p.qos as u8,
+                            message: Box::new(message(
+                                String::from_utf8_lossy(&p.topic).to_string(),
+                                p.payload,
+                                p.retain,
+                                p.properties,
+                            )),
+                        },
+                        v5::Packet::PubRel(p) => Packet::PubRel { pkid: p.pkid },
+                        v5::Packet::PingReq(_) => Packet::PingReq,
+                        v5::Packet::Disconnect(_) => Packet::Disconnect,
+                        packet => Packet::Other(format!("{:?}", packet)),
+                    })
+                }
+                Err(mqttbytes5::Error::InsufficientBytes(_)) => fill(reader, buffer).await?,
+                Err(e) => return Err(e.into()),
+            },
+        }
+    }
+}
+
+async fn write_packet(
+    writer: &mut OwnedWriteHalf,
+    version: Version,
+    reply: Reply<'_>,
+) -> anyhow::Result<()> {
+    let mut b = BytesMut::new();
+    match version {
+        Version::V4 => match reply {
+            Reply::ConnAck { success } => {
+                let code = if success {
+                    ConnectReturnCode::Success
+                } else {
+                    ConnectReturnCode::BadUserNamePassword
+                };
+                v4::ConnAck::new(code, false).write(&mut b)?
+            }
+            Reply::SubAck { pkid, count } => {
+                let codes = vec![SubscribeReasonCode::Success(QoS::AtMostOnce); count];
+                v4::SubAck::new(pkid, codes).write(&mut b)?
+            }
+            Reply::PubAck { pkid } => v4::PubAck::new(pkid).write(&mut b)?,
+            Reply::PubRec { pkid } => v4::PubRec::new(pkid).write(&mut b)?,
+            Reply::PubComp { pkid } => v4::PubComp::new(pkid).write(&mut b)?,
+            Reply::PingResp => v4::PingResp.write(&mut b)?,
+            Reply::Publish(message, _) => {
+                let mut p = v4::Publish::from_bytes(
+                    &message.topic,
+                    QoS::AtMostOnce,
+                    message.payload.clone(),
+                );
+                p.retain = message.retain;
+                p.write(&mut b)?
+            }
+        },
+        Version::V5 => {
+            let packet = match reply {
+                Reply::ConnAck { success } => v5::Packet::ConnAck(v5::ConnAck {
+                    session_present: false,
+                    code: if success {
+                        v5::ConnectReturnCode::Success
+                    } else {
+                        v5::ConnectReturnCode::BadUserNamePassword
+                    },
+                    properties: None,
+                }),
+                Reply::SubAck { pkid, count } => v5::Packet::SubAck(v5::SubAck {
+                    pkid,
+                    return_codes: vec![
+                        v5::SubscribeReasonCode::Success(mqttbytes5::QoS::AtMostOnce);
+                        count
+                    ],
+                    properties: None,
+                }),
+                Reply::PubAck { pkid } => v5::Packet::PubAck(v5::PubAck::new(pkid, None)),
+                Reply::PubRec { pkid } => v5::Packet::PubRec(v5::PubRec::new(pkid, None)),
+                Reply::PubComp { pkid } => v5::Packet::PubComp(v5::PubComp::new(pkid, None)),
+                Reply::PingResp => v5::Packet::PingResp(v5::PingResp),
+                Reply::Publish(message, properties) => {
+                    let mut p = v5::Publish::new(
+                        &message.topic,
+                        mqttbytes5::QoS::AtMostOnce,
+                        message.payload.clone(),
+                        properties,
+                    );
+                    p.retain = message.retain;
+                    v5::Packet::Publish(p)
+                }
+            };
+            packet.write(&mut b)?
+        }
+    };
+    writer.write_all(&b).await?;
+    Ok(())
+}
+
+/// Forward `message` to a subscriber, unless it expired.
+async fn forward(
+    writer: &mut OwnedWriteHalf,
+    version: Version,
+    message: &Message,
+) -> anyhow::Result<()> {
+    match message.properties() {
+        Some(properties) => {
+            write_packet(writer, version, Reply::Publish(message, properties)).await
+        }
+        None => {
+            debug!("Dropping expired message on {}", message.topic);
+            Ok(())
+        }
+    }
+}
+
+/// The last retained message of each topic.
+type Retained = Arc<Mutex<HashMap<String, Message>>>;
+
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+fn publish(sender: &broadcast::Sender<Message>, retained: &Retained, message: Message) {
+    if message.retain {
+        let mut retained = retained.lock().unwrap();
+        if message.payload.is_empty() {
+            retained.remove(&message.topic);
+        } else {
+            retained.insert(message.topic.clone(), message.clone());
+        }
+    }
+    sender.send(message).ok();
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    sender: broadcast::Sender<Message>,
+    retained: Retained,
+    login: Option<(String, String)>,
+    subscribed: Arc<Mutex<Vec<String>>>,
+) -> anyhow::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buffer = BytesMut::new();
+    let (version, connect) = read_connect(&mut reader, &mut buffer).await?;
+    if login.is_some() && connect.login != login {
+        warn!("Rejected MQTT client '{}'", connect.client_id);
+        write_packet(&mut writer, version, Reply::ConnAck { success: false }).await?;
+        return Ok(());
+    }
+    write_packet(&mut writer, version, Reply::ConnAck { success: true }).await?;
+
+    let result = serve(
+        &mut reader,
+        &mut writer,
+        buffer,
+        version,
+        &sender,
+        &retained,
+        &connect.client_id,
+        &subscribed,
+    )
+    .await;
+    // The will is only published if the client didn't disconnect cleanly
+    if result.is_err() {
+        if let Some(will) = connect.will {
+            publish(&sender, &retained, will);
+        }
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    mut buffer: BytesMut,
+    version: Version,
+    sender: &broadcast::Sender<Message>,
+    retained: &Retained,
+    client_id: &str,
+    subscribed: &Mutex<Vec<String>>,
+) -> anyhow::Result<()> {
+    let mut filters: Vec<Filter> = vec![];
+    let mut receiver = sender.subscribe();
+    loop {
+        tokio::select! {
+            packet = read_packet(reader, &mut buffer, version, client_id) => match packet? {
+                Packet::Subscribe { pkid, filters: new_filters } => {
+                    let count = new_filters.len();
+                    write_packet(writer, version, Reply::SubAck { pkid, count }).await?;
+                    let messages: Vec<Message> = retained
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .filter(|p| new_filters.iter().any(|f| matches(&f.path, &p.topic)))
+                        .cloned()
+                        .collect();
+                    for message in messages {
+                        forward(writer, version, &message).await?;
+                    }
+                    filters.extend(new_filters);
+                    subscribed.lock().unwrap().push(client_id.to_string());
+                }
+                Packet::Publish { pkid, qos, message } => {
+                    match qos {
+                        1 => write_packet(writer, version, Reply::PubAck { pkid }).await?,
+                        2 => write_packet(writer, version, Reply::PubRec { pkid }).await?,
+                        _ => {}
+                    }
+                    publish(sender, retained, *message);
+                }
+                Packet::PubRel { pkid } => {
+                    write_packet(writer, version, Reply::PubComp { pkid }).await?;
+                }
+                Packet::PingReq => {
+                    write_packet(writer, version, Reply::PingResp).await?;
+                }
+                Packet::Disconnect => return Ok(()),
+                Packet::Other(packet) => debug!("Ignoring MQTT packet {}", packet),
+            },
+            p = receiver.recv() => {
+                let mut p = p?;
+                let local = p.sender == client_id;
+                if filters
+                    .iter()
+                    .any(|f| matches(&f.path, &p.topic) && !(f.nolocal && local))
+                {
+                    // Only retained messages sent on subscribing have the flag
+                    p.retain = false;
+                    forward(writer, version, &p).await?;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use log::warn;
+use tempfile::TempDir;
+use tokio::{sync::oneshot, task::JoinHandle};
+use websocket_client::{ClientConfig, WebSocketSink, WebSocketSource, WebsocketClipSyncClient};
+use websocket_server::ServerConfig;
+
+use client_interface::ClipSyncClient;
+
+use crate::{free_port, wait_for_port, TIMEOUT};
+
+/// `server_main` running on an ephemeral port, with an in-RAM index and a temporary image dir.
+pub struct TestServer {
+    pub url: String,
+    pub secret: Option<String>,
+    config: ServerConfig,
+    bridge: Option<mqtt_client::MqttClientConfig>,
+    task: JoinHandle<()>,
+    shutdown: Option<oneshot::Sender<()>>,
+    _dir: TempDir,
+}
+
+impl TestServer {
+    pub async fn start() -> anyhow::Result<Self> {
+        Self::start_with_secret(None).await
+    }
+
+    pub async fn start_with_secret(secret: Option<&str>) -> anyhow::Result<Self> {
+        Self::start_with(secret, None, None).await
+    }
+
+    pub async fn start_with_max_upload_size(max_upload_size: u64) -> anyhow::Result<Self> {
+        Self::start_with(None, None, Some(max_upload_size)).await
+    }
+
+    /// Run the `mqtt-bridge` role with the server.
+    pub async fn start_with_bridge(bridge: mqtt_client::MqttClientConfig) -> anyhow::Result<Self> {
+        Self::start_with(None, Some(bridge), None).await
+    }
+
+    async fn start_with(
+        secret: Option<&str>,
+        bridge: Option<mqtt_client::MqttClientConfig>,
+        max_upload_size: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let port = free_port();
+        let config = ServerConfig {
+            endpoint: format!("127.0.0.1:{}", port),
+            secret: secret.map(ToString::to_string),
+            use_tls: false,
+            cert_path: None,
+            key_path: None,
+            web_root: Some(dir.path().join("static-files")),
+            index_path: None,
+            image_path: Some(dir.path().join("images")),
+            max_upload_size,
+        };
+        let (task, shutdown) = spawn(config.clone(), bridge.clone());
+        wait_for_port(port).await?;
+        Ok(Self {
+            url: format!("http://127.0.0.1:{}/", port),
+            secret: secret.map(ToString::to_string),
+            config,
+            bridge,
+            task,
+            shutdown: Some(shutdown),
+            _dir: dir,
+        })
+    }
+
+    /// Start the server again on the same port after [`shutdown`](Self::shutdown), the
+    /// in-RAM index starts empty.
+    pub async fn restart(&mut self) -> anyhow::Result<()> {
+        let (task, shutdown) = spawn(self.config.clone(), self.bridge.clone());
+        self.task = task;
+        self.shutdown = Some(shutdown);
+        let port = self.config.endpoint.rsplit(':').next().unwrap_or_default();
+        wait_for_port(port.parse()?).await
+    }
+
+    pub fn client_config(&self, client_id: &str) -> ClientConfig {
+        ClientConfig {
+            server_url: self.url.clone(),
+            secret: self.secret.clone(),
+            client_id: Some(client_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub async fn connect(
+        &self,
+        client_id: &str,
+    ) -> anyhow::Result<(String, WebSocketSource, WebSocketSink)> {
+        WebsocketClipSyncClient::connect(self.client_config(client_id)).await
+    }
+
+    /// Shut the server down gracefully, fails if it doesn't stop in time.
+    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        tokio::time::timeout(TIMEOUT, &mut self.task)
+            .await
+            .map_err(|_| anyhow::anyhow!("The server didn't shut down in time"))??;
+        Ok(())
+    }
+
+    /// A request to an API of the server, with the secret.
+    pub fn request(&self, method: reqwest::Method, api: &str) -> reqwest::RequestBuilder {
+        let req = reqwest::Client::new().request(method, format!("{}api/{}", self.url, api));
+        match &self.secret {
+            Some(secret) => req.bearer_auth(secret),
+            None => req,
+        }
+    }
+
+    /// Call a JSON API of the server, e.g. `device-list`.
+    pub async fn get_json<T: serde::de::DeserializeOwned>(&self, api: &str) -> anyhow::Result<T> {
+        Ok(self
+            .request(reqwest::Method::GET, api)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+fn spawn(
+    config: ServerConfig,
+    bridge: Option<mqtt_client::MqttClientConfig>,
+) -> (JoinHandle<()>, oneshot::Sender<()>) {
+    let (shutdown, signal) = oneshot::channel::<()>();
+    let signal = async move {
+        signal.await.ok();
+    };
+    let task = tokio::spawn(async move {
+        let result = match bridge {
+            Some(bridge) => {
+                let reconnect = Default::default();
+                websocket_server::server_main_with_bridge(config, bridge, reconnect, signal).await
+            }
+            None => websocket_server::server_main_with_shutdown(config, signal).await,
+        };
+        if let Err(e) = result {
+            warn!("Test server exited: {}", e);
+        }
+    });
+    (task, shutdown)
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,88 @@
+use client_interface::{ClipboardContent, ClipboardRecord, ClipboardSink};
+use integration_tests::{
+    expect_nothing, recv, test_image, text_record, MqttBroker, TestServer, TIMEOUT,
+};
+use websocket_server::QueryResult;
+
+/// Wait until the history has `count` entries, the index is written in the background.
+async fn history(server: &TestServer, count: usize) -> anyhow::Result<QueryResult> {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        let result: QueryResult = server.get_json("query").await?;
+        if result.data.len() >= count || tokio::time::Instant::now() > deadline {
+            return Ok(result);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_bridge() -> anyhow::Result<()> {
+    let broker = MqttBroker::start().await?;
+    let server = TestServer::start_with_bridge(broker.client_config("bridge")).await?;
+    broker.wait_subscribed("bridge").await?;
+    let (laptop, mut laptop_source, mut laptop_sink) = server.connect("laptop").await?;
+    let (board, mut board_source, mut board_sink) =
+        broker.connect(broker.client_config("board")).await?;
+
+    // Websocket to MQTT, with the original source
+    laptop_sink
+        .publish(Some(text_record(&laptop, "from websocket")))
+        .await?;
+    let record = recv(&mut board_source).await?;
+    assert_eq!(record.source, "laptop");
+    assert_eq!(
+        record.content,
+        ClipboardContent::Text("from websocket".to_string())
+    );
+
+    // MQTT to websocket, and not published back to MQTT
+    board_sink
+        .publish(Some(text_record(&board, "from mqtt")))
+        .await?;
+    let record = recv(&mut laptop_source).await?;
+    assert_eq!(record.source, "board");
+    assert_eq!(
+        record.content,
+        ClipboardContent::Text("from mqtt".to_string())
+    );
+    expect_nothing(&mut board_source).await?;
+    expect_nothing(&mut laptop_source).await?;
+
+    // Images are downloaded from the server for MQTT, and uploaded for websocket devices
+    let image = test_image(16, 8);
+    laptop_sink
+        .publish(Some(ClipboardRecord {
+            source: laptop.clone(),
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    assert_eq!(
+        recv(&mut board_source).await?.content,
+        ClipboardContent::Image(image)
+    );
+    let image = test_image(8, 16);
+    board_sink
+        .publish(Some(ClipboardRecord {
+            source: board.clone(),
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    assert_eq!(
+        recv(&mut laptop_source).await?.content,
+        ClipboardContent::Image(image)
+    );
+    expect_nothing(&mut board_source).await?;
+    expect_nothing(&mut laptop_source).await?;
+
+    // Entries from MQTT are stored like the others
+    let result = history(&server, 4).await?;
+    let mut sources: Vec<&str> = result
+        .data
+        .iter()
+        .map(|msg| msg.entry.source.as_str())
+        .collect();
+    sources.sort();
+    assert_eq!(sources, vec!["board", "board", "laptop", "laptop"]);
+    Ok(())
+}
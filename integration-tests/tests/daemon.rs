@@ -0,0 +1,56 @@
+use client_interface::{ClipboardContent, ClipboardSink};
+use clip_sync::system_clipboard::SystemClipboard;
+use integration_tests::{expect_nothing, recv, text_record, TestDaemon, TestServer, QUIET_PERIOD};
+
+#[tokio::test]
+async fn test_daemon_sync() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let a = TestDaemon::start(server.client_config("device-a"), 100);
+    let b = TestDaemon::start(server.client_config("device-b"), 100);
+    a.wait_connected().await?;
+    b.wait_connected().await?;
+
+    a.clipboard.set_text("hello".to_string())?;
+    b.wait_for_text("hello").await?;
+    // Content set from a received entry isn't published back
+    tokio::time::sleep(QUIET_PERIOD).await;
+    assert!(b.published().is_empty());
+    let published = a.published();
+    assert_eq!(published.len(), 1);
+    assert_eq!(
+        published[0].content,
+        ClipboardContent::Text("hello".to_string())
+    );
+
+    b.clipboard.set_text("world".to_string())?;
+    a.wait_for_text("world").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_daemon_reconnect() -> anyhow::Result<()> {
+    let mut server = TestServer::start().await?;
+    // Slow to reconnect, so the other device is back first
+    let a = TestDaemon::start(server.client_config("device-a"), 2000);
+    a.wait_connected().await?;
+
+    server.shutdown().await?;
+    a.wait_disconnected().await?;
+    // Copied while the server is down, it waits in the outbox
+    a.clipboard.set_text("offline copy".to_string())?;
+    server.restart().await?;
+    let (b, mut b_source, mut b_sink) = server.connect("device-b").await?;
+
+    a.wait_connected().await?;
+    let record = recv(&mut b_source).await?;
+    assert_eq!(record.source, "device-a");
+    assert_eq!(
+        record.content,
+        ClipboardContent::Text("offline copy".to_string())
+    );
+    expect_nothing(&mut b_source).await?;
+
+    b_sink.publish(Some(text_record(&b, "back"))).await?;
+    a.wait_for_text("back").await?;
+    Ok(())
+}
@@ -0,0 +1,336 @@
+use client_interface::{ClipSyncClient, ClipboardContent, ClipboardRecord, ClipboardSink};
+use integration_tests::{expect_nothing, recv, test_image, text_record, MqttBroker};
+
+#[tokio::test]
+async fn test_text_sync() -> anyhow::Result<()> {
+    let broker = MqttBroker::start().await?;
+    let (a, mut a_source, mut a_sink) = broker.connect(broker.client_config("device-a")).await?;
+    let (_, mut b_source, _b_sink) = broker.connect(broker.client_config("device-b")).await?;
+
+    a_sink.publish(Some(text_record(&a, "hello"))).await?;
+    let record = recv(&mut b_source).await?;
+    assert_eq!(record.source, "device-a");
+    assert_eq!(record.content, ClipboardContent::Text("hello".to_string()));
+    // The broker sends the entry back, the client must skip it
+    expect_nothing(&mut a_source).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_image_sync() -> anyhow::Result<()> {
+    let broker = MqttBroker::start().await?;
+    let (a, _a_source, mut a_sink) = broker.connect(broker.client_config("device-a")).await?;
+    let (_, mut b_source, _b_sink) = broker.connect(broker.client_config("device-b")).await?;
+
+    let image = test_image(16, 8);
+    a_sink
+        .publish(Some(ClipboardRecord {
+            source: a,
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    assert_eq!(
+        recv(&mut b_source).await?.content,
+        ClipboardContent::Image(image)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunked_image() -> anyhow::Result<()> {
+    let broker = MqttBroker::start().await?;
+    let config = mqtt_client::MqttClientConfig {
+        chunk_size: Some(1000),
+        ..broker.client_config("device-a")
+    };
+    let (a, _a_source, mut a_sink) = broker.connect(config).await?;
+    let (_, mut b_source, _b_sink) = broker.connect(broker.client_config("device-b")).await?;
+
+    // Sent as several messages, the receiver puts them back together
+    let image = test_image(64, 64);
+    a_sink
+        .publish(Some(ClipboardRecord {
+            source: a,
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    assert_eq!(
+        recv(&mut b_source).await?.content,
+        ClipboardContent::Image(image)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auth() -> anyhow::Result<()> {
+    let broker = MqttBroker::start_with_login(Some(("user", "password"))).await?;
+
+    let mut config = broker.client_config("device-a");
+    config.mqtt_username = Some("user".to_string());
+    config.mqtt_password = Some("wrong".to_string());
+    let (_, mut source, _) = mqtt_client::MqttClipSyncClient::connect(config.clone()).await?;
+    assert!(source.wait_subscribed().await.is_err());
+    let (_, source, _) = mqtt_client::MqttClipSyncClient::connect(config).await?;
+    let mut source = integration_tests::BackgroundSource::new(source);
+    assert!(recv(&mut source).await.is_err());
+
+    let mut config = broker.client_config("device-b");
+    config.mqtt_username = Some("user".to_string());
+    config.mqtt_password = Some("password".to_string());
+    let (_, mut source, _) = mqtt_client::MqttClipSyncClient::connect(config.clone()).await?;
+    tokio::time::timeout(integration_tests::TIMEOUT, source.wait_subscribed()).await??;
+    broker.connect(config).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect() -> anyhow::Result<()> {
+    let broker = MqttBroker::start().await?;
+    let (a, _a_source, mut a_sink) = broker.connect(broker.client_config("device-a")).await?;
+    let (_, b_source, b_sink) = broker.connect(broker.client_config("device-b")).await?;
+    let (_, mut c_source, _c_sink) = broker.connect(broker.client_config("device-c")).await?;
+    drop((b_source, b_sink));
+
+    // Entries published while a device is offline are not delivered to it
+    a_sink
+        .publish(Some(text_record(&a, "while offline")))
+        .await?;
+    recv(&mut c_source).await?;
+    let (_, mut b_source, _b_sink) = broker.connect(broker.client_config("device-b")).await?;
+    expect_nothing(&mut b_source).await?;
+
+    a_sink
+        .publish(Some(text_record(&a, "after reconnect")))
+        .await?;
+    assert_eq!(
+        recv(&mut b_source).await?.content,
+        ClipboardContent::Text("after reconnect".to_string())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_payload_formats() -> anyhow::Result<()> {
+    use mqtt_client::payload::{ImageEncoding, PayloadFormat};
+
+    let broker = MqttBroker::start().await?;
+    let mut config = broker.client_config("device-a");
+    config.payload_format = Some(PayloadFormat::Json);
+    config.image_encoding = ImageEncoding::Rgba;
+    let (a, mut a_source, mut a_sink) = broker.connect(config).await?;
+    let (_, mut b_source, _b_sink) = broker.connect(broker.client_config("device-b")).await?;
+    let mut config = broker.client_config("device-c");
+    config.payload_format = Some(PayloadFormat::Text);
+    let (c, mut c_source, mut c_sink) = broker.connect(config).await?;
+
+    a_sink.publish(Some(text_record(&a, "json"))).await?;
+    assert_eq!(recv(&mut b_source).await?.source, "device-a");
+    assert_eq!(recv(&mut c_source).await?.source, "device-a");
+    let image = test_image(16, 8);
+    a_sink
+        .publish(Some(ClipboardRecord {
+            source: a,
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    assert_eq!(
+        recv(&mut b_source).await?.content,
+        ClipboardContent::Image(image.clone())
+    );
+    recv(&mut c_source).await?;
+
+    // Plain text has no source, the sender recognizes it by its content
+    c_sink.publish(Some(text_record(&c, "plain"))).await?;
+    let record = recv(&mut b_source).await?;
+    assert_eq!(record.source, mqtt_client::payload::UNKNOWN_SOURCE);
+    assert_eq!(record.content, ClipboardContent::Text("plain".to_string()));
+    assert_eq!(recv(&mut a_source).await?.source, "mqtt");
+    c_sink
+        .publish(Some(ClipboardRecord {
+            source: c,
+            content: ClipboardContent::Image(image),
+        }))
+        .await?;
+    expect_nothing(&mut c_source).await?;
+    expect_nothing(&mut b_source).await?;
+
+    // Only the echo is skipped, the same text from another device is not
+    let mut config = broker.client_config("device-d");
+    config.payload_format = Some(PayloadFormat::Text);
+    let (d, _d_source, mut d_sink) = broker.connect(config).await?;
+    d_sink.publish(Some(text_record(&d, "plain"))).await?;
+    assert_eq!(
+        recv(&mut c_source).await?.content,
+        ClipboardContent::Text("plain".to_string())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mqtt5() -> anyhow::Result<()> {
+    let broker = MqttBroker::start().await?;
+    let v5_config = |client_id: &str| mqtt_client::MqttClientConfig {
+        protocol: mqtt_client::Protocol::V5,
+        ..broker.client_config(client_id)
+    };
+    let (a, mut a_source, mut a_sink) = broker.connect(v5_config("device-a")).await?;
+    let (b, mut b_source, mut b_sink) = broker.connect(v5_config("device-b")).await?;
+    let (c, mut c_source, mut c_sink) = broker.connect(broker.client_config("device-c")).await?;
+
+    // The payload is just the text or PNG, the source is a property only MQTT 5 clients get
+    a_sink.publish(Some(text_record(&a, "hello"))).await?;
+    assert_eq!(recv(&mut b_source).await?.source, "device-a");
+    let record = recv(&mut c_source).await?;
+    assert_eq!(record.source, mqtt_client::payload::UNKNOWN_SOURCE);
+    assert_eq!(record.content, ClipboardContent::Text("hello".to_string()));
+    let image = test_image(16, 8);
+    b_sink
+        .publish(Some(ClipboardRecord {
+            source: b,
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    let record = recv(&mut a_source).await?;
+    assert_eq!(record.source, "device-b");
+    assert_eq!(record.content, ClipboardContent::Image(image.clone()));
+    assert_eq!(
+        recv(&mut c_source).await?.content,
+        ClipboardContent::Image(image)
+    );
+    c_sink.publish(Some(text_record(&c, "from 3.1.1"))).await?;
+    assert_eq!(recv(&mut a_source).await?.source, "device-c");
+    assert_eq!(recv(&mut b_source).await?.source, "device-c");
+    expect_nothing(&mut a_source).await?;
+    expect_nothing(&mut c_source).await?;
+
+    // Retained entries are no longer delivered once they expire
+    let config = mqtt_client::MqttClientConfig {
+        retain_latest: true,
+        message_expiry_secs: Some(1),
+        ..v5_config("device-d")
+    };
+    let (d, _d_source, mut d_sink) = broker.connect(config).await?;
+    d_sink.publish(Some(text_record(&d, "stale"))).await?;
+    let (_, mut e_source, _e_sink) = broker.connect(v5_config("device-e")).await?;
+    assert_eq!(
+        recv(&mut e_source).await?.content,
+        ClipboardContent::Text("stale".to_string())
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let (_, mut f_source, _f_sink) = broker.connect(v5_config("device-f")).await?;
+    expect_nothing(&mut f_source).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_presence() -> anyhow::Result<()> {
+    let broker = MqttBroker::start().await?;
+    let mut config = broker.client_config("device-a");
+    config.retain_latest = true;
+    let (a, a_source, mut a_sink) = broker.connect(config.clone()).await?;
+    a_sink.publish(Some(text_record(&a, "latest"))).await?;
+    let mut config_b = broker.client_config("device-b");
+    config_b.presence = Some(false);
+    // The retained entry is delivered as soon as the device subscribes
+    let (_, mut b_source, _b_sink) = broker.connect(config_b.clone()).await?;
+    assert_eq!(
+        recv(&mut b_source).await?.content,
+        ClipboardContent::Text("latest".to_string())
+    );
+    assert_eq!(
+        mqtt_client::list_devices(&config_b).await?,
+        vec![("device-a".to_string(), true)]
+    );
+
+    // The last will marks the device offline when the connection is lost
+    drop((a_source, a_sink));
+    let deadline = tokio::time::Instant::now() + integration_tests::TIMEOUT;
+    while mqtt_client::list_devices(&config_b).await? != vec![("device-a".to_string(), false)] {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "device-a is still online"
+        );
+    }
+    let (_, _c_source, _c_sink) = broker.connect(broker.client_config("device-c")).await?;
+    assert_eq!(
+        mqtt_client::list_devices(&config_b).await?,
+        vec![
+            ("device-a".to_string(), false),
+            ("device-c".to_string(), true)
+        ]
+    );
+    Ok(())
+}
+
+/// The next message Home Assistant receives, payloads are JSON.
+async fn next(
+    receiver: &mut tokio::sync::mpsc::UnboundedReceiver<(String, serde_json::Value)>,
+) -> (String, serde_json::Value) {
+    tokio::time::timeout(integration_tests::TIMEOUT, receiver.recv())
+        .await
+        .ok()
+        .flatten()
+        .expect("No message for Home Assistant")
+}
+
+#[tokio::test]
+async fn test_home_assistant() -> anyhow::Result<()> {
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+
+    let broker = MqttBroker::start().await?;
+    let mut config = broker.client_config("device-a");
+    config.home_assistant = Some(Default::default());
+    let (a, mut a_source, mut a_sink) = broker.connect(config).await?;
+    let (_, mut b_source, _b_sink) = broker.connect(broker.client_config("device-b")).await?;
+
+    // Home Assistant gets the retained discovery configs and states
+    let (ha, mut eventloop) =
+        AsyncClient::new(MqttOptions::new("ha", "127.0.0.1", broker.port), 10);
+    ha.subscribe("homeassistant/#", QoS::AtMostOnce).await?;
+    ha.subscribe("clipboard/devices/+/state", QoS::AtMostOnce)
+        .await?;
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::spawn(async move {
+        while let Ok(event) = eventloop.poll().await {
+            if let Event::Incoming(Packet::Publish(p)) = event {
+                let payload: serde_json::Value = serde_json::from_slice(&p.payload).unwrap();
+                sender.send((p.topic, payload)).ok();
+            }
+        }
+    });
+    let mut configs = std::collections::BTreeMap::new();
+    while configs.len() < 3 {
+        let (topic, payload) = next(&mut receiver).await;
+        configs.insert(topic, payload);
+    }
+    let sensor = &configs["homeassistant/sensor/clip-sync_device-a/clipboard/config"];
+    assert_eq!(sensor["state_topic"], "clipboard/devices/device-a/state");
+    assert_eq!(sensor["availability_topic"], "clipboard/devices/device-a");
+    let text = &configs["homeassistant/text/clip-sync_clipboard/clipboard/config"];
+    assert_eq!(text["command_topic"], "clipboard");
+
+    a_sink.publish(Some(text_record(&a, "hello"))).await?;
+    recv(&mut b_source).await?;
+    let (topic, state) = next(&mut receiver).await;
+    assert_eq!(topic, "clipboard/devices/device-a/state");
+    assert_eq!(state["text"], "hello");
+    assert!(state["timestamp"].is_string());
+
+    // Setting the text entity sets the clipboard of every device
+    ha.publish("clipboard", QoS::AtMostOnce, false, "from home assistant")
+        .await?;
+    for source in [&mut a_source, &mut b_source] {
+        let record = recv(source).await?;
+        assert_eq!(record.source, mqtt_client::payload::UNKNOWN_SOURCE);
+        assert_eq!(
+            record.content,
+            ClipboardContent::Text("from home assistant".to_string())
+        );
+    }
+    // Received entries are on the clipboard too
+    let (topic, state) = next(&mut receiver).await;
+    assert_eq!(topic, "clipboard/devices/device-a/state");
+    assert_eq!(state["text"], "from home assistant");
+    task.abort();
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use client_interface::{
+    ClipSyncClient, ClipboardContent, ClipboardRecord, ClipboardSink, ClipboardSource,
+};
+use integration_tests::{expect_nothing, recv, test_image, text_record, TestServer, TIMEOUT};
+use reqwest::{Method, StatusCode};
+use websocket_client::{ClientConfig, WebsocketClipSyncClient};
+use websocket_server::QueryResult;
+
+#[tokio::test]
+async fn test_text_sync() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let (a, mut a_source, mut a_sink) = server.connect("device-a").await?;
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+    let (_, mut c_source, _c_sink) = server.connect("device-c").await?;
+
+    a_sink.publish(Some(text_record(&a, "hello"))).await?;
+    for source in [&mut b_source, &mut c_source] {
+        let record = recv(source).await?;
+        assert_eq!(record.source, "device-a");
+        assert_eq!(record.content, ClipboardContent::Text("hello".to_string()));
+    }
+    // The server doesn't send entries back to where they came from
+    expect_nothing(&mut a_source).await?;
+
+    let devices: Vec<String> = server.get_json("online-device-list").await?;
+    assert_eq!(devices, vec!["device-a", "device-b", "device-c"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_image_sync() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let (a, _a_source, mut a_sink) = server.connect("device-a").await?;
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+
+    // Images are uploaded by the sender and downloaded by the receiver
+    let image = test_image(16, 8);
+    a_sink
+        .publish(Some(ClipboardRecord {
+            source: a,
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    let record = recv(&mut b_source).await?;
+    assert_eq!(record.source, "device-a");
+    assert_eq!(record.content, ClipboardContent::Image(image));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_targets() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let (a, _a_source, a_sink) = server.connect("device-a").await?;
+    let mut a_sink = a_sink.with_targets(vec!["device-b".to_string()]);
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+    let (_, mut c_source, _c_sink) = server.connect("device-c").await?;
+
+    a_sink.publish(Some(text_record(&a, "secret"))).await?;
+    assert_eq!(
+        recv(&mut b_source).await?.content,
+        ClipboardContent::Text("secret".to_string())
+    );
+    expect_nothing(&mut c_source).await?;
+    // Not kept in the history, every device could read it there
+    let result: QueryResult = server.get_json("query").await?;
+    assert_eq!(result.total, 0);
+
+    // The same entry sent to everyone isn't taken for a duplicate
+    let mut a_sink = a_sink.with_targets(vec![]);
+    a_sink.publish(Some(text_record(&a, "secret"))).await?;
+    assert_eq!(
+        recv(&mut c_source).await?.content,
+        ClipboardContent::Text("secret".to_string())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_duplicates() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let (a, _a_source, mut a_sink) = server.connect("device-a").await?;
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+    for text in ["first", "second"] {
+        a_sink.publish(Some(text_record(&a, text))).await?;
+        recv(&mut b_source).await?;
+    }
+    // An outbox re-sending both after reconnecting, not only the latest is dropped
+    let (a, _a_source, mut a_sink) = server.connect("device-a").await?;
+    for text in ["first", "second"] {
+        a_sink.publish(Some(text_record(&a, text))).await?;
+    }
+    expect_nothing(&mut b_source).await?;
+    let result: QueryResult = server.get_json("query").await?;
+    assert_eq!(result.total, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunked_image() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let config = ClientConfig {
+        chunk_size: Some(1000),
+        ..server.client_config("device-a")
+    };
+    let (a, _a_source, mut a_sink) = WebsocketClipSyncClient::connect(config).await?;
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+
+    let image = test_image(256, 256);
+    a_sink
+        .publish(Some(ClipboardRecord {
+            source: a,
+            content: ClipboardContent::Image(image.clone()),
+        }))
+        .await?;
+    assert_eq!(
+        recv(&mut b_source).await?.content,
+        ClipboardContent::Image(image)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resume_upload() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let png = test_image(64, 64).to_png()?;
+    let api = "upload-image/device-a/resumed";
+    let put = |offset: usize, end: usize| {
+        server
+            .request(Method::PUT, &format!("{}?offset={}", api, offset))
+            .body(png[offset..end].to_vec())
+            .send()
+    };
+
+    // A retry overlapping the original request is refused with the size to continue from
+    let (first, second) = tokio::join!(put(0, 100), put(0, 100));
+    let mut statuses = [first?.status(), second?.status()];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+    let res = server.request(Method::GET, api).send().await?;
+    assert_eq!(res.text().await?, "100");
+    assert_eq!(
+        put(100, png.len()).await?.text().await?,
+        png.len().to_string()
+    );
+
+    let url = server
+        .request(Method::POST, api)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let res = server
+        .request(Method::GET, &format!("images/{}", url))
+        .send()
+        .await?;
+    assert_eq!(res.bytes().await?, png);
+    // Unfinished uploads aren't served with the images
+    let res = server
+        .request(Method::GET, "images/.uploads/")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_upload_size() -> anyhow::Result<()> {
+    let server = TestServer::start_with_max_upload_size(1000).await?;
+    let config = ClientConfig {
+        chunk_size: Some(300),
+        ..server.client_config("device-a")
+    };
+    let (a, _a_source, mut a_sink) = WebsocketClipSyncClient::connect(config).await?;
+
+    let record = ClipboardRecord {
+        source: a,
+        content: ClipboardContent::Image(test_image(256, 256)),
+    };
+    let error = a_sink.publish(Some(record)).await.unwrap_err();
+    assert!(
+        error.to_string().contains("more than the server accepts"),
+        "{}",
+        error
+    );
+
+    // The multipart upload of older clients has the same limit
+    let png = test_image(256, 256).to_png()?;
+    let upload = |name: &str, png: Vec<u8>| -> anyhow::Result<_> {
+        let part = reqwest::multipart::Part::bytes(png).mime_str("image/png")?;
+        Ok(server
+            .request(Method::POST, &format!("upload-image/{}", name))
+            .multipart(reqwest::multipart::Form::new().part("file", part))
+            .send())
+    };
+    assert_eq!(
+        upload("device-a", png)?.await?.status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    let png = test_image(4, 4).to_png()?;
+    let url = upload("device-a", png.clone())?
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let res = server
+        .request(Method::GET, &format!("images/{}", url))
+        .send()
+        .await?;
+    assert_eq!(res.bytes().await?, png);
+    // The device name is a directory, it can't point elsewhere
+    for name in [".uploads", "..%2Fdevice-a", "a%5Cb"] {
+        assert_eq!(
+            upload(name, png.clone())?.await?.status(),
+            StatusCode::BAD_REQUEST,
+            "{}",
+            name
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auth() -> anyhow::Result<()> {
+    let server = TestServer::start_with_secret(Some("secret")).await?;
+
+    let mut config = server.client_config("device-a");
+    config.secret = None;
+    assert!(websocket_client::WebsocketClipSyncClient::connect(config)
+        .await
+        .is_err());
+    let mut config = server.client_config("device-a");
+    config.secret = Some("wrong".to_string());
+    assert!(websocket_client::WebsocketClipSyncClient::connect(config)
+        .await
+        .is_err());
+    let status = reqwest::get(format!("{}api/device-list", server.url))
+        .await?
+        .status();
+    assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+
+    let (a, _a_source, mut a_sink) = server.connect("device-a").await?;
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+    a_sink.publish(Some(text_record(&a, "authorized"))).await?;
+    assert_eq!(recv(&mut b_source).await?.source, "device-a");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect() -> anyhow::Result<()> {
+    let server = TestServer::start().await?;
+    let (a, _a_source, mut a_sink) = server.connect("device-a").await?;
+    let (_, b_source, b_sink) = server.connect("device-b").await?;
+    let (_, mut c_source, _c_sink) = server.connect("device-c").await?;
+    drop((b_source, b_sink));
+
+    // Entries published while a device is offline are not delivered to it
+    a_sink
+        .publish(Some(text_record(&a, "while offline")))
+        .await?;
+    recv(&mut c_source).await?;
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+    expect_nothing(&mut b_source).await?;
+
+    a_sink
+        .publish(Some(text_record(&a, "after reconnect")))
+        .await?;
+    let record = recv(&mut b_source).await?;
+    assert_eq!(
+        record.content,
+        ClipboardContent::Text("after reconnect".to_string())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown() -> anyhow::Result<()> {
+    let mut server = TestServer::start().await?;
+    let (_, mut a_source, _a_sink) = server.connect("device-a").await?;
+    let (_, mut b_source, _b_sink) = server.connect("device-b").await?;
+
+    // Clients have to keep polling to answer the close frames
+    let (result, a, b) = tokio::time::timeout(TIMEOUT, async {
+        tokio::join!(server.shutdown(), a_source.poll(), b_source.poll())
+    })
+    .await?;
+    result?;
+    assert!(a.is_err());
+    assert!(b.is_err());
+    // Nothing is listening anymore
+    assert!(server.connect("device-c").await.is_err());
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Messages larger than `chunk-size` are split into chunks, for brokers that limit the packet
+//! size and so a large image doesn't hold up everything else in one packet.
+//!
+//! Each chunk starts with [`MAGIC`], which can't start UTF-8, PNG, JSON or a bincode record, so
+//! versions without chunks ignore them. Then come the id of the message, the index of the chunk
+//! and the number of chunks, both big endian.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use client_interface::Progress;
+
+const MAGIC: &[u8] = b"\xffCSC";
+const ID_LEN: usize = 16;
+pub(crate) const HEADER_LEN: usize = MAGIC.len() + ID_LEN + 4 + 4;
+
+/// Messages being reassembled at once, the oldest is dropped for a new one.
+const MAX_PENDING: usize = 4;
+/// Messages missing chunks for this long are dropped.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub(crate) fn is_chunk(payload: &[u8]) -> bool {
+    payload.len() >= HEADER_LEN && payload.starts_with(MAGIC)
+}
+
+pub(crate) fn split(payload: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+    let id = random_string::generate(
+        ID_LEN,
+        "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
+    );
+    let count = payload.len().div_ceil(chunk_size) as u32;
+    payload
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut chunk = Vec::with_capacity(HEADER_LEN + data.len());
+            chunk.extend_from_slice(MAGIC);
+            chunk.extend_from_slice(id.as_bytes());
+            chunk.extend_from_slice(&(index as u32).to_be_bytes());
+            chunk.extend_from_slice(&count.to_be_bytes());
+            chunk.extend_from_slice(data);
+            chunk
+        })
+        .collect()
+}
+
+struct Pending {
+    /// The header's count isn't trusted with an allocation, so chunks are kept by index
+    chunks: HashMap<usize, Vec<u8>>,
+    count: usize,
+    size: usize,
+    started: Instant,
+    progress: Progress,
+}
+
+pub(crate) struct Reassembler {
+    pending: HashMap<Vec<u8>, Pending>,
+    max_size: usize,
+}
+
+impl Reassembler {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            max_size,
+        }
+    }
+
+    /// Add a chunk, returns the message once all of its chunks arrived.
+    pub fn add(&mut self, chunk: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if !is_chunk(chunk) {
+            anyhow::bail!("Not a chunk");
+        }
+        let (id, rest) = chunk[MAGIC.len()..].split_at(ID_LEN);
+        let index = u32::from_be_bytes(rest[..4].try_into()?) as usize;
+        let count = u32::from_be_bytes(rest[4..8].try_into()?) as usize;
+        let data = &rest[8..];
+        if index >= count {
+            anyhow::bail!("Chunk {} of {} is out of range", index, count);
+        }
+
+        self.pending
+            .retain(|_, pending| pending.started.elapsed() < PENDING_TIMEOUT);
+        if !self.pending.contains_key(id) && self.pending.len() >= MAX_PENDING {
+            if let Some(oldest) = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pending)| pending.started)
+                .map(|(id, _)| id.clone())
+            {
+                debug!("Dropping an incomplete chunked message");
+                self.pending.remove(&oldest);
+            }
+        }
+        let pending = self.pending.entry(id.to_vec()).or_insert_with(|| Pending {
+            chunks: HashMap::new(),
+            count,
+            size: 0,
+            started: Instant::now(),
+            // Only the last chunk is shorter, so this is close enough
+            progress: Progress::new(
+                "Receiving chunked message",
+                (count as u64).saturating_mul(data.len() as u64),
+            ),
+        });
+        if pending.count != count {
+            anyhow::bail!("Chunk count changed from {} to {}", pending.count, count);
+        }
+        if !pending.chunks.contains_key(&index) {
+            pending.size += data.len();
+            if pending.size > self.max_size {
+                self.pending.remove(id);
+                anyhow::bail!("Chunked message is larger than {} bytes", self.max_size);
+            }
+            pending.chunks.insert(index, data.to_vec());
+            pending.progress.update(pending.size as u64);
+        }
+        if pending.chunks.len() < count {
+            return Ok(None);
+        }
+        let mut pending = self.pending.remove(id).unwrap();
+        Ok(Some(
+            (0..count)
+                .flat_map(|index| pending.chunks.remove(&index).unwrap_or_default())
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reassemble() {
+        let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut chunks = split(&payload, 300);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| is_chunk(chunk)));
+        assert!(!is_chunk(&payload));
+
+        let mut reassembler = Reassembler::new(payload.len());
+        // Out of order and duplicated chunks are fine
+        chunks.swap(0, 2);
+        assert_eq!(reassembler.add(&chunks[0]).unwrap(), None);
+        assert_eq!(reassembler.add(&chunks[0]).unwrap(), None);
+        assert_eq!(reassembler.add(&chunks[1]).unwrap(), None);
+        assert_eq!(reassembler.add(&chunks[3]).unwrap(), None);
+        assert_eq!(reassembler.add(&chunks[2]).unwrap(), Some(payload.clone()));
+
+        let mut small = Reassembler::new(500);
+        let chunks = split(&payload, 300);
+        assert_eq!(small.add(&chunks[0]).unwrap(), None);
+        assert!(small.add(&chunks[1]).is_err());
+    }
+}
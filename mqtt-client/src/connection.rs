@@ -0,0 +1,297 @@
+//! MQTT 3.1.1 and 5 behind one client, the rest of the crate doesn't depend on the version.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use rumqttc::{v5, Outgoing};
+
+use crate::{chunks, payload::Metadata, MqttClientConfig, Protocol};
+
+/// Default of `session-expiry-secs` for persistent MQTT 5 sessions.
+const DEFAULT_SESSION_EXPIRY_SECS: u32 = 24 * 3600;
+
+/// A retained message published when the connection is lost.
+pub(crate) struct Will {
+    pub topic: String,
+    pub payload: &'static [u8],
+}
+
+#[derive(Clone)]
+enum Inner {
+    V4(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+#[derive(Clone)]
+pub(crate) struct Client {
+    inner: Inner,
+    qos: u8,
+    message_expiry_secs: Option<u32>,
+}
+
+pub(crate) enum EventLoop {
+    V4(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+pub(crate) struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    /// Only MQTT 5 messages have metadata
+    pub metadata: Metadata,
+}
+
+pub(crate) enum Incoming {
+    Message(Message),
+    SubAck {
+        refused: bool,
+    },
+    /// The DISCONNECT requested by the client was sent
+    Disconnected,
+    Other,
+}
+
+fn qos_v4(qos: u8) -> rumqttc::QoS {
+    match qos {
+        0 => rumqttc::QoS::AtMostOnce,
+        1 => rumqttc::QoS::AtLeastOnce,
+        _ => rumqttc::QoS::ExactlyOnce,
+    }
+}
+
+fn qos_v5(qos: u8) -> v5::mqttbytes::QoS {
+    match qos {
+        0 => v5::mqttbytes::QoS::AtMostOnce,
+        1 => v5::mqttbytes::QoS::AtLeastOnce,
+        _ => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+pub(crate) fn connect(
+    args: &MqttClientConfig,
+    client_id: String,
+    will: Option<Will>,
+) -> anyhow::Result<(Client, EventLoop)> {
+    let transport = crate::transport(args)?;
+    let qos = args.qos();
+    let keep_alive = Duration::from_secs(args.keepalive_secs.unwrap_or(60));
+    let max_packet_size = args.max_packet_size();
+    let credentials = (args.mqtt_username.is_some() || args.mqtt_password.is_some()).then(|| {
+        (
+            args.mqtt_username.clone().unwrap_or_default(),
+            args.mqtt_password.clone().unwrap_or_default(),
+        )
+    });
+    let (inner, eventloop) = match args.protocol {
+        Protocol::V311 => {
+            let mut options = rumqttc::MqttOptions::new(
+                client_id,
+                args.mqtt_server_addr.clone(),
+                args.mqtt_server_port,
+            );
+            options.set_max_packet_size(max_packet_size, max_packet_size);
+            options.set_transport(transport);
+            options.set_keep_alive(keep_alive);
+            options.set_clean_session(!args.persistent_session);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if let Some(will) = will {
+                options.set_last_will(rumqttc::LastWill::new(
+                    will.topic,
+                    will.payload,
+                    qos_v4(qos),
+                    true,
+                ));
+            }
+            let (client, eventloop) = rumqttc::AsyncClient::new(options, 10);
+            (Inner::V4(client), EventLoop::V4(Box::new(eventloop)))
+        }
+        Protocol::V5 => {
+            let mut options = v5::MqttOptions::new(
+                client_id,
+                args.mqtt_server_addr.clone(),
+                args.mqtt_server_port,
+            );
+            let mut properties = v5::mqttbytes::v5::ConnectProperties::new();
+            properties.max_packet_size = Some(max_packet_size.min(u32::MAX as usize) as u32);
+            // Without an expiry the broker drops the session as soon as the connection closes
+            if args.persistent_session {
+                properties.session_expiry_interval = Some(
+                    args.session_expiry_secs
+                        .unwrap_or(DEFAULT_SESSION_EXPIRY_SECS),
+                );
+            }
+            options.set_connect_properties(properties);
+            options.set_transport(transport);
+            options.set_keep_alive(keep_alive);
+            options.set_clean_start(!args.persistent_session);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if let Some(will) = will {
+                options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                    will.topic,
+                    will.payload,
+                    qos_v5(qos),
+                    true,
+                    None,
+                ));
+            }
+            let (client, eventloop) = v5::AsyncClient::new(options, 10);
+            (Inner::V5(client), EventLoop::V5(Box::new(eventloop)))
+        }
+    };
+    Ok((
+        Client {
+            inner,
+            qos,
+            message_expiry_secs: args.message_expiry_secs,
+        },
+        eventloop,
+    ))
+}
+
+impl Client {
+    /// Subscribe to `filter`, with MQTT 5 the broker doesn't send back what this client publishes.
+    pub async fn subscribe(&self, filter: String) -> anyhow::Result<()> {
+        match &self.inner {
+            Inner::V4(client) => client.subscribe(filter, qos_v4(self.qos)).await?,
+            Inner::V5(client) => {
+                let mut filter = v5::mqttbytes::v5::Filter::new(filter, qos_v5(self.qos));
+                filter.nolocal = true;
+                client.subscribe_many([filter]).await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish a status, it is retained and never expires.
+    pub async fn publish_status(
+        &self,
+        topic: String,
+        payload: impl Into<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let payload = payload.into();
+        match &self.inner {
+            Inner::V4(client) => {
+                client
+                    .publish(topic, qos_v4(self.qos), true, payload)
+                    .await?
+            }
+            Inner::V5(client) => {
+                client
+                    .publish(topic, qos_v5(self.qos), true, payload)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish a retained state, with MQTT 5 it expires after `message-expiry-secs` like the
+    /// entries it describes. It doesn't wait for room in the request queue, so it can be called
+    /// while the event loop isn't polled.
+    pub fn publish_state(&self, topic: String, payload: Vec<u8>) -> anyhow::Result<()> {
+        match &self.inner {
+            Inner::V4(client) => client.try_publish(topic, qos_v4(self.qos), true, payload)?,
+            Inner::V5(client) => {
+                let properties = v5::mqttbytes::v5::PublishProperties {
+                    message_expiry_interval: self.message_expiry_secs,
+                    ..Default::default()
+                };
+                client.try_publish_with_properties(
+                    topic,
+                    qos_v5(self.qos),
+                    true,
+                    payload,
+                    properties,
+                )?
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish an entry, with MQTT 5 `metadata` is sent as properties and it expires after
+    /// `message-expiry-secs`.
+    pub async fn publish_entry(
+        &self,
+        topic: String,
+        retain: bool,
+        payload: Vec<u8>,
+        metadata: Metadata,
+    ) -> anyhow::Result<()> {
+        match &self.inner {
+            Inner::V4(client) => {
+                client
+                    .publish(topic, qos_v4(self.qos), retain, payload)
+                    .await?
+            }
+            Inner::V5(client) => {
+                let properties = v5::mqttbytes::v5::PublishProperties {
+                    // Chunks of text aren't UTF-8 because of their header
+                    payload_format_indicator: (metadata.is_utf8() && !chunks::is_chunk(&payload))
+                        .then_some(1),
+                    message_expiry_interval: self.message_expiry_secs,
+                    content_type: metadata.content_type.clone(),
+                    user_properties: metadata.user_properties(),
+                    ..Default::default()
+                };
+                client
+                    .publish_with_properties(topic, qos_v5(self.qos), retain, payload, properties)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> anyhow::Result<()> {
+        match &self.inner {
+            Inner::V4(client) => client.disconnect().await?,
+            Inner::V5(client) => client.disconnect().await?,
+        }
+        Ok(())
+    }
+}
+
+impl EventLoop {
+    pub async fn poll(&mut self) -> anyhow::Result<Incoming> {
+        Ok(match self {
+            EventLoop::V4(eventloop) => match eventloop.poll().await? {
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(p)) => {
+                    Incoming::Message(Message {
+                        topic: p.topic,
+                        payload: p.payload,
+                        metadata: Metadata::default(),
+                    })
+                }
+                rumqttc::Event::Incoming(rumqttc::Packet::SubAck(ack)) => Incoming::SubAck {
+                    refused: ack
+                        .return_codes
+                        .contains(&rumqttc::SubscribeReasonCode::Failure),
+                },
+                rumqttc::Event::Outgoing(Outgoing::Disconnect) => Incoming::Disconnected,
+                _ => Incoming::Other,
+            },
+            EventLoop::V5(eventloop) => match eventloop.poll().await? {
+                v5::Event::Incoming(v5::Incoming::Publish(p)) => {
+                    let properties = p.properties.unwrap_or_default();
+                    Incoming::Message(Message {
+                        topic: String::from_utf8_lossy(&p.topic).to_string(),
+                        payload: p.payload,
+                        metadata: Metadata::from_properties(
+                            properties.content_type,
+                            &properties.user_properties,
+                        ),
+                    })
+                }
+                v5::Event::Incoming(v5::Incoming::SubAck(ack)) => Incoming::SubAck {
+                    refused: ack.return_codes.iter().any(|code| {
+                        !matches!(code, v5::mqttbytes::v5::SubscribeReasonCode::Success(_))
+                    }),
+                },
+                v5::Event::Outgoing(Outgoing::Disconnect) => Incoming::Disconnected,
+                _ => Incoming::Other,
+            },
+        })
+    }
+}
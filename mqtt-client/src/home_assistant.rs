@@ -0,0 +1,194 @@
+//! Home Assistant MQTT discovery.
+//!
+//! Each device shows up with sensors for the latest entry on its clipboard, copied or received,
+//! and when, and all devices on a topic share a text entity that publishes plain text to the
+//! topic, so setting it from Home Assistant sets the clipboard of every device.
+
+use client_interface::{ClipboardContent, ClipboardRecord};
+use serde_json::{json, Value};
+
+use crate::{HomeAssistantConfig, MqttClientConfig, OFFLINE, ONLINE};
+
+/// Longest state Home Assistant accepts, the state is retained so nothing more is published.
+const MAX_STATE_LEN: usize = 255;
+
+pub(crate) struct Discovery {
+    prefix: String,
+    /// Where the device publishes its latest entry, see [`state`]
+    pub state_topic: String,
+    /// The device of the sensors
+    device: (String, Value),
+    /// The device of the text entity, shared by everyone on the topic
+    topic_device: (String, Value),
+    command_topic: String,
+    availability_topic: Option<String>,
+}
+
+/// Node and object ids may only have letters, digits, `_` and `-`.
+fn node_id(name: &str) -> String {
+    let id: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("clip-sync_{}", id)
+}
+
+impl Discovery {
+    pub fn new(
+        args: &MqttClientConfig,
+        config: &HomeAssistantConfig,
+        client_id: &str,
+        presence_topic: Option<&str>,
+    ) -> Self {
+        let device_id = node_id(client_id);
+        let device = json!({
+            "identifiers": [device_id],
+            "name": config.device_name.as_deref().unwrap_or(client_id),
+            "model": "clip-sync",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let topic_id = node_id(&args.topic());
+        let topic_device = json!({
+            "identifiers": [topic_id],
+            "name": format!("clip-sync {}", args.topic()),
+            "model": "clip-sync",
+        });
+        Self {
+            prefix: config.discovery_prefix.trim_end_matches('/').to_string(),
+            state_topic: format!("{}/state", args.presence_topic(client_id)),
+            device: (device_id, device),
+            topic_device: (topic_id, topic_device),
+            command_topic: args.topic(),
+            availability_topic: presence_topic.map(ToString::to_string),
+        }
+    }
+
+    fn topic(&self, component: &str, node_id: &str, object_id: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.prefix, component, node_id, object_id
+        )
+    }
+
+    fn sensor(&self, object_id: &str, mut config: Value) -> (String, Vec<u8>) {
+        let (node_id, device) = &self.device;
+        config["unique_id"] = json!(format!("{}_{}", node_id, object_id));
+        config["state_topic"] = json!(self.state_topic);
+        config["device"] = device.clone();
+        if let Some(topic) = &self.availability_topic {
+            config["availability_topic"] = json!(topic);
+            config["payload_available"] = json!(String::from_utf8_lossy(ONLINE));
+            config["payload_not_available"] = json!(String::from_utf8_lossy(OFFLINE));
+        }
+        (
+            self.topic("sensor", node_id, object_id),
+            config.to_string().into_bytes(),
+        )
+    }
+
+    /// Topics and payloads of the discovery configs, they are published retained.
+    pub fn configs(&self) -> Vec<(String, Vec<u8>)> {
+        let (node_id, device) = &self.topic_device;
+        let text = json!({
+            "name": "Clipboard",
+            "unique_id": format!("{}_clipboard", node_id),
+            "command_topic": self.command_topic,
+            "max": MAX_STATE_LEN,
+            "icon": "mdi:clipboard-arrow-down",
+            "device": device,
+        });
+        vec![
+            self.sensor(
+                "clipboard",
+                json!({
+                    "name": "Clipboard",
+                    "value_template": "{{ value_json.text }}",
+                    "json_attributes_topic": self.state_topic,
+                    "icon": "mdi:clipboard-text",
+                }),
+            ),
+            self.sensor(
+                "clipboard_updated",
+                json!({
+                    "name": "Clipboard updated",
+                    "value_template": "{{ value_json.timestamp }}",
+                    "device_class": "timestamp",
+                }),
+            ),
+            (
+                self.topic("text", node_id, "clipboard"),
+                text.to_string().into_bytes(),
+            ),
+        ]
+    }
+}
+
+/// The state of the sensors, images are described by their size. Text is cut to what the sensor
+/// shows, as it stays on the broker.
+pub(crate) fn state(record: &ClipboardRecord) -> Vec<u8> {
+    let text = match &record.content {
+        ClipboardContent::Text(text) => text.chars().take(MAX_STATE_LEN).collect(),
+        ClipboardContent::Image(image) => format!("Image {}x{}", image.width, image.height),
+    };
+    json!({
+        "text": text,
+        "source": record.source,
+        "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    })
+    .to_string()
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovery() {
+        let args = MqttClientConfig {
+            mqtt_topic: Some("home/clipboard".to_string()),
+            ..Default::default()
+        };
+        let discovery = Discovery::new(
+            &args,
+            &HomeAssistantConfig::default(),
+            "my laptop",
+            Some("home/clipboard/devices/my laptop"),
+        );
+        let configs = discovery.configs();
+        let topics: Vec<&str> = configs.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/clip-sync_my_laptop/clipboard/config",
+                "homeassistant/sensor/clip-sync_my_laptop/clipboard_updated/config",
+                "homeassistant/text/clip-sync_home_clipboard/clipboard/config",
+            ]
+        );
+        let sensor: Value = serde_json::from_slice(&configs[0].1).unwrap();
+        assert_eq!(
+            sensor["state_topic"],
+            "home/clipboard/devices/my laptop/state"
+        );
+        assert_eq!(
+            sensor["availability_topic"],
+            "home/clipboard/devices/my laptop"
+        );
+        assert_eq!(sensor["device"]["name"], "my laptop");
+        let text: Value = serde_json::from_slice(&configs[2].1).unwrap();
+        assert_eq!(text["command_topic"], "home/clipboard");
+
+        let record = ClipboardRecord {
+            source: "my laptop".to_string(),
+            content: ClipboardContent::Text("x".repeat(1000)),
+        };
+        let state: Value = serde_json::from_slice(&state(&record)).unwrap();
+        assert_eq!(state["text"].as_str().unwrap().len(), MAX_STATE_LEN);
+    }
+}
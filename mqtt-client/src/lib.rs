@@ -1,31 +1,281 @@
+use std::{
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
 use gethostname::gethostname;
 use log::{debug, warn};
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use rumqttc::{
+    tokio_rustls::rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore},
+    TlsConfiguration, Transport,
+};
 use serde::Deserialize;
 
-use client_interface::{ClipSyncClient, ClipboardRecord, ClipboardSink, ClipboardSource};
+use client_interface::{ClipSyncClient, ClipboardRecord, ClipboardSink, ClipboardSource, Progress};
+use connection::{Client, EventLoop, Incoming, Will};
+
+mod chunks;
+mod connection;
+mod home_assistant;
+pub mod payload;
+
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+
+use payload::{ImageEncoding, Metadata, PayloadFormat};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
 
-#[derive(Debug, Clone, Default, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HomeAssistantConfig {
+    /// Discovery topics are `<discovery-prefix>/<component>/<node-id>/<object-id>/config`
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Name of the device in Home Assistant, default is the client id
+    pub device_name: Option<String>,
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> Self {
+        Self {
+            discovery_prefix: default_discovery_prefix(),
+            device_name: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct MqttClientConfig {
+    /// Host name of the broker, or a `ws://` or `wss://` URL to connect over websocket
     pub mqtt_server_addr: String,
+    /// Not used with websocket URLs, their port is part of the URL
+    #[serde(default)]
     pub mqtt_server_port: u16,
     pub mqtt_topic: Option<String>,
     pub mqtt_username: Option<String>,
     pub mqtt_password: Option<String>,
     pub mqtt_client_id: Option<String>,
+    /// Connect with TLS, implied by `wss://` URLs
+    #[serde(default)]
+    pub use_tls: bool,
+    /// CA certificates to verify the broker with instead of the system roots, in PEM
+    pub ca_path: Option<PathBuf>,
+    /// Client certificate in PEM, if the broker requires one
+    pub cert_path: Option<PathBuf>,
+    /// Private key of the client certificate in PEM
+    pub key_path: Option<PathBuf>,
+    /// ALPN protocols to offer, e.g. `x-amzn-mqtt-ca` for AWS IoT on port 443
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// MQTT version, "3.1.1" or "5"
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// 0, 1 or 2, default is 1
+    pub qos: Option<u8>,
+    /// Default is 60
+    pub keepalive_secs: Option<u64>,
+    /// Keep the subscription on the broker while disconnected, so entries published meanwhile
+    /// are delivered on reconnect, needs a fixed `mqtt-client-id`
+    #[serde(default)]
+    pub persistent_session: bool,
+    /// How long the broker keeps a persistent session, MQTT 5 only. Default is a day
+    pub session_expiry_secs: Option<u32>,
+    /// Entries not delivered within this are dropped by the broker, MQTT 5 only
+    pub message_expiry_secs: Option<u32>,
+    /// Largest message sent or received in bytes, default is 100 MB
+    pub max_packet_size: Option<usize>,
+    /// Split messages larger than this many bytes into chunks, which are not retained. Every
+    /// device on the topic needs a version that reassembles them
+    pub chunk_size: Option<usize>,
+    /// Format of published messages, messages in any format are received. Default is `raw` with
+    /// MQTT 5, its metadata is in properties, and `bincode` otherwise
+    pub payload_format: Option<PayloadFormat>,
+    /// How images are encoded in the `json` format
+    #[serde(default)]
+    pub image_encoding: ImageEncoding,
+    /// Publish `online` to `<topic>/devices/<client-id>`, and `offline` as the last will, both
+    /// retained. Default is true
+    pub presence: Option<bool>,
+    /// Retain the latest entry so devices get it as soon as they subscribe
+    #[serde(default)]
+    pub retain_latest: bool,
+    /// Publish Home Assistant discovery configs, so the device shows up with its clipboard
+    pub home_assistant: Option<HomeAssistantConfig>,
+}
+
+impl MqttClientConfig {
+    /// Whether `mqtt-server-addr` is a websocket URL rather than a host name.
+    pub fn is_websocket(&self) -> bool {
+        self.mqtt_server_addr.starts_with("ws://") || self.mqtt_server_addr.starts_with("wss://")
+    }
+
+    pub fn topic(&self) -> String {
+        self.mqtt_topic.clone().unwrap_or("clipboard".to_string())
+    }
+
+    /// Topic of the retained `online` or `offline` status of `client_id`.
+    pub fn presence_topic(&self, client_id: &str) -> String {
+        format!("{}/devices/{}", self.topic(), client_id)
+    }
+
+    pub fn qos(&self) -> u8 {
+        self.qos.unwrap_or(1)
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size.unwrap_or(1024 * 1024 * 100)
+    }
+
+    pub fn payload_format(&self) -> PayloadFormat {
+        self.payload_format.unwrap_or(match self.protocol {
+            Protocol::V311 => PayloadFormat::Bincode,
+            Protocol::V5 => PayloadFormat::Raw,
+        })
+    }
+
+    /// The broker as shown to users, `host:port` or the websocket URL.
+    pub fn broker(&self) -> String {
+        if self.is_websocket() {
+            self.mqtt_server_addr.clone()
+        } else {
+            format!("{}:{}", self.mqtt_server_addr, self.mqtt_server_port)
+        }
+    }
+}
+
+fn read_certs(path: &PathBuf) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate in {:?}", path);
+    }
+    Ok(certs)
+}
+
+/// The TLS settings for `use-tls` and `wss://` URLs.
+fn tls_config(args: &MqttClientConfig) -> anyhow::Result<TlsConfiguration> {
+    let mut roots = RootCertStore::empty();
+    match &args.ca_path {
+        Some(path) => {
+            roots.add_parsable_certificates(read_certs(path)?);
+        }
+        None => {
+            let certs = rustls_native_certs::load_native_certs()
+                .context("Failed to load the system root certificates")?;
+            roots.add_parsable_certificates(certs);
+        }
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let mut config = match (&args.cert_path, &args.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let file = std::fs::File::open(key_path)
+                .with_context(|| format!("Failed to open {:?}", key_path))?;
+            let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+                .with_context(|| format!("Failed to read {:?}", key_path))?
+                .with_context(|| format!("No private key in {:?}", key_path))?;
+            builder
+                .with_client_auth_cert(read_certs(cert_path)?, key)
+                .context("Invalid client certificate or key")?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("`cert-path` and `key-path` must be set together"),
+    };
+    config.alpn_protocols = args.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+fn transport(args: &MqttClientConfig) -> anyhow::Result<Transport> {
+    let scheme = args
+        .mqtt_server_addr
+        .split_once("://")
+        .map(|(scheme, _)| scheme);
+    Ok(match scheme {
+        None if args.use_tls => Transport::Tls(tls_config(args)?),
+        None => Transport::Tcp,
+        #[cfg(feature = "websocket")]
+        Some("ws") => Transport::Ws,
+        #[cfg(feature = "websocket")]
+        Some("wss") => Transport::Wss(tls_config(args)?),
+        #[cfg(not(feature = "websocket"))]
+        Some("ws" | "wss") => anyhow::bail!("This build doesn't support MQTT over websocket"),
+        Some(scheme) => anyhow::bail!(
+            "Unsupported scheme '{}' in `mqtt-server-addr`, use a host name or a ws:// or wss:// URL",
+            scheme
+        ),
+    })
+}
+
+/// The last text or raw payload this client published, without MQTT 5 properties it has no
+/// source to recognize it by when the broker sends it back. It's cleared once the echo arrived,
+/// so the same content from another device isn't skipped.
+type LastSent = Arc<Mutex<Option<Vec<u8>>>>;
+
+/// Publish `data` as the latest entry on the clipboard of this device for Home Assistant.
+fn publish_state(client: &Client, state_topic: Option<&String>, data: &ClipboardRecord) {
+    if let Some(topic) = state_topic {
+        if let Err(e) = client.publish_state(topic.clone(), home_assistant::state(data)) {
+            warn!("Failed to publish the state for Home Assistant: {}", e);
+        }
+    }
 }
 
 pub struct MqttSubscriber {
     eventloop: EventLoop,
+    client: Client,
     device_id: String,
+    /// Where received entries are published for Home Assistant, if discovery is enabled
+    state_topic: Option<String>,
+    last_sent: LastSent,
+    chunks: chunks::Reassembler,
 }
 
 impl MqttSubscriber {
-    pub fn new(eventloop: EventLoop, device_id: String) -> Self {
+    fn new(
+        eventloop: EventLoop,
+        client: Client,
+        device_id: String,
+        state_topic: Option<String>,
+        last_sent: LastSent,
+        max_size: usize,
+    ) -> Self {
         Self {
             eventloop,
+            client,
             device_id,
+            state_topic,
+            last_sent,
+            chunks: chunks::Reassembler::new(max_size),
+        }
+    }
+
+    /// Wait until the broker accepted the connection and the subscription, nothing is sent
+    /// before the event loop is polled.
+    pub async fn wait_subscribed(&mut self) -> anyhow::Result<()> {
+        loop {
+            if let Incoming::SubAck { refused } = self.eventloop.poll().await? {
+                if refused {
+                    anyhow::bail!("The broker refused the subscription");
+                }
+                return Ok(());
+            }
         }
     }
 }
@@ -34,12 +284,44 @@ impl ClipboardSource for MqttSubscriber {
     async fn poll(&mut self) -> anyhow::Result<ClipboardRecord> {
         loop {
             match self.eventloop.poll().await {
-                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(p))) => {
-                    let data: ClipboardRecord = bincode::deserialize(&p.payload)?;
+                Ok(Incoming::Message(mut m)) => {
+                    if chunks::is_chunk(&m.payload) {
+                        m.payload = match self.chunks.add(&m.payload) {
+                            Ok(Some(payload)) => payload.into(),
+                            Ok(None) => continue,
+                            Err(e) => {
+                                warn!("Ignoring chunk on {}: {}", m.topic, e);
+                                continue;
+                            }
+                        };
+                    }
+                    if m.metadata.source.is_none() {
+                        let mut last_sent = self.last_sent.lock().unwrap();
+                        if last_sent.as_deref() == Some(&m.payload[..]) {
+                            *last_sent = None;
+                            debug!("Skipping clipboard update from self");
+                            continue;
+                        }
+                    }
+                    // Other devices may use a newer format, that shouldn't break the connection
+                    let data = match payload::decode_with(&m.payload, &m.metadata) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            warn!("Ignoring message on {}: {}", m.topic, e);
+                            continue;
+                        }
+                    };
                     if data.source == self.device_id {
                         debug!("Skipping clipboard update from self");
                         continue;
                     }
+                    if let Some(timestamp) = m.metadata.timestamp {
+                        debug!(
+                            "Received entry from {} published at {}",
+                            data.source, timestamp
+                        );
+                    }
+                    publish_state(&self.client, self.state_topic.as_ref(), &data);
                     return Ok(data);
                 }
                 Ok(_) => {
@@ -53,29 +335,108 @@ impl ClipboardSource for MqttSubscriber {
             }
         }
     }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        // The DISCONNECT requested by the publisher is only sent while the event loop is polled
+        tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Incoming::Disconnected = self.eventloop.poll().await? {
+                    return Ok(());
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out disconnecting from the MQTT broker"))?
+    }
 }
 
 pub struct MqttPublisher {
-    client: AsyncClient,
+    client: Client,
     topic: String,
+    format: PayloadFormat,
+    image_encoding: ImageEncoding,
+    retain: bool,
+    chunk_size: Option<usize>,
+    /// Where `offline` is published before disconnecting, if presence is enabled
+    presence_topic: Option<String>,
+    /// Where the latest entry is published for Home Assistant, if discovery is enabled
+    state_topic: Option<String>,
+    /// Whether the metadata is sent as MQTT 5 properties, the echo is then recognized by its source
+    has_properties: bool,
+    last_sent: LastSent,
 }
 
 impl MqttPublisher {
-    pub fn new(client: AsyncClient, topic: String) -> Self {
-        Self { client, topic }
+    fn new(
+        client: Client,
+        args: &MqttClientConfig,
+        presence_topic: Option<String>,
+        state_topic: Option<String>,
+        last_sent: LastSent,
+    ) -> Self {
+        Self {
+            client,
+            topic: args.topic(),
+            format: args.payload_format(),
+            image_encoding: args.image_encoding,
+            retain: args.retain_latest,
+            chunk_size: args.chunk_size,
+            presence_topic,
+            state_topic,
+            has_properties: args.protocol == Protocol::V5,
+            last_sent,
+        }
     }
 }
 
 impl ClipboardSink for MqttPublisher {
     async fn publish(&mut self, data: Option<ClipboardRecord>) -> anyhow::Result<()> {
-        if let Some(data) = data.map(|d| bincode::serialize(&d).unwrap()) {
-            self.client
-                .publish(self.topic.clone(), QoS::AtLeastOnce, false, data)
-                .await
-                .ok();
+        let Some(data) = data else {
+            return Ok(());
+        };
+        let metadata = Metadata::new(&data, self.format);
+        if let Some(payload) = payload::encode(&data, self.format, self.image_encoding)? {
+            if !self.has_properties
+                && matches!(self.format, PayloadFormat::Text | PayloadFormat::Raw)
+            {
+                *self.last_sent.lock().unwrap() = Some(payload.clone());
+            }
+            match self.chunk_size.filter(|size| payload.len() > *size) {
+                Some(chunk_size) => {
+                    let chunks = chunks::split(&payload, chunk_size);
+                    let mut progress = Progress::new(
+                        format!("Publishing {} chunks", chunks.len()),
+                        payload.len() as u64,
+                    );
+                    let mut done = 0;
+                    for chunk in chunks {
+                        done += (chunk.len() - chunks::HEADER_LEN) as u64;
+                        // A retained chunk would only be the last part of the entry
+                        self.client
+                            .publish_entry(self.topic.clone(), false, chunk, metadata.clone())
+                            .await?;
+                        progress.update(done);
+                    }
+                }
+                None => {
+                    self.client
+                        .publish_entry(self.topic.clone(), self.retain, payload, metadata)
+                        .await?;
+                }
+            }
+            publish_state(&self.client, self.state_topic.as_ref(), &data);
         }
         Ok(())
     }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        // The broker only publishes the last will if the connection is lost
+        if let Some(topic) = &self.presence_topic {
+            self.client.publish_status(topic.clone(), OFFLINE).await?;
+        }
+        self.client.disconnect().await?;
+        Ok(())
+    }
 }
 
 pub struct MqttClipSyncClient;
@@ -87,31 +448,146 @@ impl ClipSyncClient for MqttClipSyncClient {
     async fn connect(
         args: Self::Config,
     ) -> anyhow::Result<(String, MqttSubscriber, MqttPublisher)> {
-        let sender_id = args.mqtt_client_id.unwrap_or(
+        let sender_id = args.mqtt_client_id.clone().unwrap_or(
             gethostname()
                 .into_string()
                 .unwrap_or(random_string::generate(12, "abcdefghijklmnopqrstuvwxyz")),
         );
-        let mut options = MqttOptions::new(
+        let presence_topic = args
+            .presence
+            .unwrap_or(true)
+            .then(|| args.presence_topic(&sender_id));
+        let will = presence_topic.clone().map(|topic| Will {
+            topic,
+            payload: OFFLINE,
+        });
+
+        let (client, eventloop) = connection::connect(&args, sender_id.clone(), will)?;
+        client.subscribe(args.topic()).await?;
+        if let Some(topic) = &presence_topic {
+            client.publish_status(topic.clone(), ONLINE).await?;
+        }
+        let state_topic = match &args.home_assistant {
+            Some(config) => {
+                let discovery = home_assistant::Discovery::new(
+                    &args,
+                    config,
+                    &sender_id,
+                    presence_topic.as_deref(),
+                );
+                for (topic, payload) in discovery.configs() {
+                    client.publish_status(topic, payload).await?;
+                }
+                Some(discovery.state_topic)
+            }
+            None => None,
+        };
+
+        let last_sent = LastSent::default();
+        let sink = MqttPublisher::new(
+            client.clone(),
+            &args,
+            presence_topic,
+            state_topic.clone(),
+            last_sent.clone(),
+        );
+        let source = MqttSubscriber::new(
+            eventloop,
+            client,
             sender_id.clone(),
-            args.mqtt_server_addr,
-            args.mqtt_server_port,
+            state_topic,
+            last_sent,
+            args.max_packet_size(),
         );
-        options.set_max_packet_size(1024 * 1024 * 100, 1024 * 1024 * 100); // 100 MB
+        Ok((sender_id, source, sink))
+    }
+}
 
-        if args.mqtt_username.is_some() || args.mqtt_password.is_some() {
-            options.set_credentials(
-                args.mqtt_username.unwrap_or_default(),
-                args.mqtt_password.unwrap_or_default(),
-            );
+/// The devices that published their presence on the topic, and whether they are online.
+///
+/// The statuses are retained, so they are all sent right after subscribing.
+pub async fn list_devices(args: &MqttClientConfig) -> anyhow::Result<Vec<(String, bool)>> {
+    let client_id = format!(
+        "$list-devices-{}",
+        random_string::generate(6, "abcdefghijklmnopqrstuvwxyz")
+    );
+    let prefix = args.presence_topic("");
+    // A throwaway client shouldn't leave a session behind on the broker
+    let args = MqttClientConfig {
+        persistent_session: false,
+        ..args.clone()
+    };
+    let (client, mut eventloop) = connection::connect(&args, client_id, None)?;
+    client.subscribe(format!("{}+", prefix)).await?;
+    let mut devices = std::collections::BTreeMap::new();
+    let mut subscribed = false;
+    loop {
+        let event = if subscribed {
+            // Retained messages follow the SUBACK, the broker has sent them all once it is quiet
+            match tokio::time::timeout(Duration::from_millis(500), eventloop.poll()).await {
+                Ok(event) => event?,
+                Err(_) => break,
+            }
+        } else {
+            eventloop.poll().await?
+        };
+        match event {
+            Incoming::SubAck { .. } => subscribed = true,
+            Incoming::Message(p) => {
+                let Some(device) = p.topic.strip_prefix(&prefix) else {
+                    continue;
+                };
+                // An empty retained message clears the status
+                if p.payload.is_empty() {
+                    devices.remove(device);
+                } else {
+                    devices.insert(device.to_string(), p.payload[..] == *ONLINE);
+                }
+            }
+            _ => {}
         }
+    }
+    client.disconnect().await?;
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while !matches!(eventloop.poll().await, Ok(Incoming::Disconnected) | Err(_)) {}
+    })
+    .await
+    .ok();
+    Ok(devices.into_iter().collect())
+}
 
-        let topic = args.mqtt_topic.unwrap_or("clipboard".to_string());
-        let (client, eventloop) = AsyncClient::new(options, 10);
-        client.subscribe(topic.clone(), QoS::AtLeastOnce).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let sink = MqttPublisher::new(client.clone(), topic.clone());
-        let source = MqttSubscriber::new(eventloop, sender_id.clone());
-        Ok((sender_id, source, sink))
+    #[test]
+    fn test_transport() {
+        let config = |addr: &str| MqttClientConfig {
+            mqtt_server_addr: addr.to_string(),
+            mqtt_server_port: 8883,
+            ..Default::default()
+        };
+        assert!(matches!(transport(&config("broker")), Ok(Transport::Tcp)));
+        assert!(transport(&config("mqtts://broker")).is_err());
+        #[cfg(feature = "websocket")]
+        assert!(matches!(
+            transport(&config("ws://broker/mqtt")),
+            Ok(Transport::Ws)
+        ));
+
+        let tls = MqttClientConfig {
+            use_tls: true,
+            ca_path: Some(PathBuf::from("/nonexistent/ca.crt")),
+            ..config("broker")
+        };
+        let error = transport(&tls).err().unwrap().to_string();
+        assert!(error.contains("/nonexistent/ca.crt"), "{}", error);
+        let tls = MqttClientConfig {
+            ca_path: None,
+            cert_path: Some(PathBuf::from("/nonexistent/client.crt")),
+            ..tls
+        };
+        let error = transport(&tls).err().unwrap().to_string();
+        assert!(error.contains("must be set together"), "{}", error);
     }
 }
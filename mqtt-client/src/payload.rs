@@ -0,0 +1,367 @@
+//! Encoding of clipboard records in MQTT messages.
+//!
+//! Every format is accepted on receipt regardless of `payload-format`, so devices with
+//! different settings or versions can share a topic. With MQTT 5 the source, content type and
+//! timestamp are also sent as properties, see [`Metadata`].
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use client_interface::{ClipboardContent, ClipboardRecord, ImageData};
+
+/// The `version` of the JSON envelope written by this build.
+pub const JSON_VERSION: u32 = 1;
+
+/// Source of entries received as plain text or PNG without a `source` property.
+pub const UNKNOWN_SOURCE: &str = "mqtt";
+
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+pub const PNG_CONTENT_TYPE: &str = "image/png";
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayloadFormat {
+    /// The format of older versions, only readable by clip-sync
+    Bincode,
+    /// A versioned JSON object like the entries of the server API
+    Json,
+    /// The text as is, images are not published
+    Text,
+    /// The text as is and images as PNG, the metadata is only sent with MQTT 5
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageEncoding {
+    /// Base64 of a PNG
+    #[default]
+    Png,
+    /// Base64 of the raw RGBA pixels, larger but cheap to encode
+    Rgba,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonImage {
+    width: usize,
+    height: usize,
+    encoding: ImageEncoding,
+    data: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonEnvelope {
+    version: u32,
+    source: String,
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image: Option<JsonImage>,
+}
+
+/// The metadata of a message, sent as MQTT 5 properties.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub source: Option<String>,
+    pub content_type: Option<String>,
+    /// Unix time when it was published
+    pub timestamp: Option<i64>,
+}
+
+impl Metadata {
+    pub fn new(record: &ClipboardRecord, format: PayloadFormat) -> Self {
+        let content_type = match (format, &record.content) {
+            (PayloadFormat::Bincode, _) => None,
+            (PayloadFormat::Json, _) => Some(JSON_CONTENT_TYPE),
+            (_, ClipboardContent::Text(_)) => Some(TEXT_CONTENT_TYPE),
+            (_, ClipboardContent::Image(_)) => Some(PNG_CONTENT_TYPE),
+        };
+        Self {
+            source: Some(record.source.clone()),
+            content_type: content_type.map(str::to_string),
+            timestamp: Some(now()),
+        }
+    }
+
+    /// Whether the payload is UTF-8, for the payload format indicator.
+    pub fn is_utf8(&self) -> bool {
+        matches!(
+            self.content_type.as_deref(),
+            Some(TEXT_CONTENT_TYPE | JSON_CONTENT_TYPE)
+        )
+    }
+
+    pub fn user_properties(&self) -> Vec<(String, String)> {
+        let mut properties = vec![];
+        if let Some(source) = &self.source {
+            properties.push(("source".to_string(), source.clone()));
+        }
+        if let Some(timestamp) = self.timestamp {
+            properties.push(("timestamp".to_string(), timestamp.to_string()));
+        }
+        properties
+    }
+
+    pub fn from_properties(
+        content_type: Option<String>,
+        user_properties: &[(String, String)],
+    ) -> Self {
+        let property = |name: &str| {
+            user_properties
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        Self {
+            source: property("source"),
+            content_type,
+            timestamp: property("timestamp").and_then(|t| t.parse().ok()),
+        }
+    }
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// The payload for `record`, `None` if the format can't carry it, e.g. an image as text.
+pub fn encode(
+    record: &ClipboardRecord,
+    format: PayloadFormat,
+    image_encoding: ImageEncoding,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(match format {
+        PayloadFormat::Bincode => Some(bincode::serialize(record)?),
+        PayloadFormat::Json => {
+            let mut envelope = JsonEnvelope {
+                version: JSON_VERSION,
+                source: record.source.clone(),
+                timestamp: now(),
+                text: None,
+                image: None,
+            };
+            match &record.content {
+                ClipboardContent::Text(text) => envelope.text = Some(text.clone()),
+                ClipboardContent::Image(image) => {
+                    let data = match image_encoding {
+                        ImageEncoding::Png => image.to_png()?,
+                        ImageEncoding::Rgba => image.data.clone(),
+                    };
+                    envelope.image = Some(JsonImage {
+                        width: image.width,
+                        height: image.height,
+                        encoding: image_encoding,
+                        data: STANDARD.encode(data),
+                    });
+                }
+            }
+            Some(serde_json::to_vec(&envelope)?)
+        }
+        PayloadFormat::Text => match &record.content {
+            ClipboardContent::Text(text) => Some(text.clone().into_bytes()),
+            ClipboardContent::Image(_) => {
+                debug!("Images are not published in the text payload format");
+                None
+            }
+        },
+        PayloadFormat::Raw => Some(match &record.content {
+            ClipboardContent::Text(text) => text.clone().into_bytes(),
+            ClipboardContent::Image(image) => image.to_png()?,
+        }),
+    })
+}
+
+fn decode_json(envelope: JsonEnvelope) -> anyhow::Result<ClipboardRecord> {
+    if envelope.version > JSON_VERSION {
+        anyhow::bail!(
+            "Payload version {} from '{}' is newer than this clip-sync supports",
+            envelope.version,
+            envelope.source
+        );
+    }
+    let content = match (envelope.text, envelope.image) {
+        (Some(text), None) => ClipboardContent::Text(text),
+        (None, Some(image)) => {
+            let data = STANDARD.decode(&image.data)?;
+            let image = match image.encoding {
+                ImageEncoding::Png => ImageData::from_png(&data)?,
+                ImageEncoding::Rgba => {
+                    if data.len() != image.width * image.height * 4 {
+                        anyhow::bail!(
+                            "{} bytes is not a {}x{} RGBA image",
+                            data.len(),
+                            image.width,
+                            image.height
+                        );
+                    }
+                    ImageData {
+                        width: image.width,
+                        height: image.height,
+                        data,
+                    }
+                }
+            };
+            ClipboardContent::Image(image)
+        }
+        _ => anyhow::bail!("A JSON payload needs either `text` or `image`"),
+    };
+    Ok(ClipboardRecord {
+        source: envelope.source,
+        content,
+    })
+}
+
+/// Decode a payload in any of the formats.
+pub fn decode(payload: &[u8]) -> anyhow::Result<ClipboardRecord> {
+    // Only objects with a `version` are envelopes, other JSON is taken as text
+    if payload.starts_with(b"{") {
+        if let Ok(value) = serde_json::from_slice::<serde_json::Value>(payload) {
+            if value.get("version").is_some() {
+                return decode_json(serde_json::from_value(value)?);
+            }
+        }
+    }
+    if payload.starts_with(PNG_MAGIC) {
+        return Ok(ClipboardRecord {
+            source: UNKNOWN_SOURCE.to_string(),
+            content: ClipboardContent::Image(ImageData::from_png(payload)?),
+        });
+    }
+    if let Ok(record) = bincode::deserialize::<ClipboardRecord>(payload) {
+        return Ok(record);
+    }
+    match std::str::from_utf8(payload) {
+        Ok(text) => Ok(text_record(text)),
+        Err(_) => anyhow::bail!("Unknown payload format"),
+    }
+}
+
+fn text_record(text: &str) -> ClipboardRecord {
+    ClipboardRecord {
+        source: UNKNOWN_SOURCE.to_string(),
+        content: ClipboardContent::Text(text.to_string()),
+    }
+}
+
+/// Decode a payload with the metadata of an MQTT 5 message, the content type is trusted over
+/// guessing, so text that happens to look like another format stays text.
+pub fn decode_with(payload: &[u8], metadata: &Metadata) -> anyhow::Result<ClipboardRecord> {
+    let content_type = metadata.content_type.as_deref().unwrap_or_default();
+    let mut record = if content_type.starts_with("text/plain") {
+        text_record(std::str::from_utf8(payload)?)
+    } else if content_type == PNG_CONTENT_TYPE {
+        ClipboardRecord {
+            source: UNKNOWN_SOURCE.to_string(),
+            content: ClipboardContent::Image(ImageData::from_png(payload)?),
+        }
+    } else {
+        decode(payload)?
+    };
+    if let Some(source) = &metadata.source {
+        if record.source == UNKNOWN_SOURCE {
+            record.source = source.clone();
+        }
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text = ClipboardRecord {
+            source: "laptop".to_string(),
+            content: ClipboardContent::Text("{\"hello\": 1}".to_string()),
+        };
+        let image = ClipboardRecord {
+            source: "laptop".to_string(),
+            content: ClipboardContent::Image(ImageData {
+                width: 2,
+                height: 1,
+                data: vec![255, 0, 0, 255, 0, 255, 0, 255],
+            }),
+        };
+        for format in [PayloadFormat::Bincode, PayloadFormat::Json] {
+            for encoding in [ImageEncoding::Png, ImageEncoding::Rgba] {
+                for record in [&text, &image] {
+                    let payload = encode(record, format, encoding).unwrap().unwrap();
+                    let decoded = decode(&payload).unwrap();
+                    assert_eq!(decoded.source, record.source);
+                    assert_eq!(decoded.content, record.content);
+                }
+            }
+        }
+
+        // Plain text, including JSON that isn't an envelope, has no source
+        let payload = encode(&text, PayloadFormat::Text, ImageEncoding::Png)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, b"{\"hello\": 1}");
+        let decoded = decode(&payload).unwrap();
+        assert_eq!(decoded.source, UNKNOWN_SOURCE);
+        assert_eq!(decoded.content, text.content);
+        assert!(encode(&image, PayloadFormat::Text, ImageEncoding::Png)
+            .unwrap()
+            .is_none());
+
+        let json = encode(&image, PayloadFormat::Json, ImageEncoding::Rgba)
+            .unwrap()
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["version"], JSON_VERSION);
+        assert_eq!(value["image"]["encoding"], "rgba");
+        assert!(decode(br#"{"version": 2, "source": "future", "text": "hi"}"#).is_err());
+        assert!(decode(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let text = ClipboardRecord {
+            source: "laptop".to_string(),
+            content: ClipboardContent::Text("{\"version\": 1}".to_string()),
+        };
+        let image = ClipboardRecord {
+            source: "laptop".to_string(),
+            content: ClipboardContent::Image(ImageData {
+                width: 1,
+                height: 1,
+                data: vec![0, 0, 255, 255],
+            }),
+        };
+        for record in [&text, &image] {
+            let metadata = Metadata::new(record, PayloadFormat::Raw);
+            let received = Metadata::from_properties(
+                metadata.content_type.clone(),
+                &metadata.user_properties(),
+            );
+            assert_eq!(received, metadata);
+            let payload = encode(record, PayloadFormat::Raw, ImageEncoding::Png)
+                .unwrap()
+                .unwrap();
+            let decoded = decode_with(&payload, &received).unwrap();
+            assert_eq!(decoded.source, "laptop");
+            assert_eq!(decoded.content, record.content);
+        }
+        assert!(Metadata::new(&text, PayloadFormat::Raw).is_utf8());
+        assert!(!Metadata::new(&image, PayloadFormat::Raw).is_utf8());
+
+        // Without properties a PNG is still recognized
+        let png = encode(&image, PayloadFormat::Raw, ImageEncoding::Png)
+            .unwrap()
+            .unwrap();
+        let decoded = decode_with(&png, &Metadata::default()).unwrap();
+        assert_eq!(decoded.source, UNKNOWN_SOURCE);
+        assert_eq!(decoded.content, image.content);
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt::Debug;
 use futures::{stream::SplitStream, SinkExt, StreamExt};
 use futures_util::stream::SplitSink;
 use gethostname::gethostname;
-use log::{debug, info, warn};
+use log::{debug, info};
 use serde::Deserialize;
 use tokio::net::TcpStream;
 use tokio_tungstenite::{
@@ -16,14 +16,36 @@ use client_interface::{
     ServerClipboardContent, ServerClipboardRecord,
 };
 
-#[derive(Debug, Clone, Default, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct ClientConfig {
     pub server_url: String,
     pub secret: Option<String>,
     pub client_id: Option<String>,
+    #[serde(default)]
+    pub publish_to: Vec<String>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Images are uploaded in chunks of this many bytes, default is 1 MiB
+    pub chunk_size: Option<usize>,
+    /// Largest image downloaded in bytes, default is 100 MB
+    pub max_image_size: Option<u64>,
 }
 
+impl ClientConfig {
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.unwrap_or(1024 * 1024)
+    }
+
+    pub fn max_image_size(&self) -> u64 {
+        self.max_image_size.unwrap_or(100 * 1024 * 1024)
+    }
+}
+
+mod transfer;
+
+pub use transfer::Transfer;
+
 pub struct WebsocketClipSyncClient;
 
 impl ClipSyncClient for WebsocketClipSyncClient {
@@ -33,6 +55,11 @@ impl ClipSyncClient for WebsocketClipSyncClient {
     async fn connect(
         args: Self::Config,
     ) -> anyhow::Result<(String, WebSocketSource, WebSocketSink)> {
+        let transfer = Transfer::new(
+            args.secret.clone(),
+            args.chunk_size(),
+            args.max_image_size(),
+        );
         let sender_id = args.client_id.unwrap_or(
             gethostname()
                 .into_string()
@@ -51,6 +78,10 @@ impl ClipSyncClient for WebsocketClipSyncClient {
         } else {
             url.set_scheme("wss").unwrap();
         }
+        if !args.channels.is_empty() {
+            url.query_pairs_mut()
+                .append_pair("channels", &args.channels.join(","));
+        }
         info!("Connecting to {} ...", url);
 
         let req = Request::builder();
@@ -75,8 +106,9 @@ impl ClipSyncClient for WebsocketClipSyncClient {
         info!("Connected to {}", url);
 
         let (write, read) = ws_stream.split();
-        let write = WebSocketSink::new(write, &sender_id, &args.server_url, args.secret.clone())?;
-        let read = WebSocketSource::new(read, &args.server_url, args.secret.clone())?;
+        let write = WebSocketSink::new(write, &sender_id, &args.server_url, transfer.clone())?
+            .with_targets(args.publish_to);
+        let read = WebSocketSource::new(read, &args.server_url, transfer)?;
         Ok((sender_id, read, write))
     }
 }
@@ -84,14 +116,14 @@ impl ClipSyncClient for WebsocketClipSyncClient {
 pub struct WebSocketSource {
     source: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
     image_url: String,
-    secret: Option<String>,
+    transfer: Transfer,
 }
 
 impl WebSocketSource {
     pub fn new(
         source: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
         server_url: &str,
-        secret: Option<String>,
+        transfer: Transfer,
     ) -> anyhow::Result<Self> {
         let mut url = url::Url::parse(server_url).unwrap();
         if url.scheme() == "ws" || url.scheme() == "http" {
@@ -105,7 +137,7 @@ impl WebSocketSource {
         Ok(Self {
             source,
             image_url: url.into(),
-            secret,
+            transfer,
         })
     }
 
@@ -123,19 +155,7 @@ impl WebSocketSource {
     async fn download_image(&mut self, url: &str) -> anyhow::Result<client_interface::ImageData> {
         debug!("Downloading image from {}", url);
         let url = format!("{}/{}", self.image_url, url);
-        let client = reqwest::Client::new();
-        let req = client.get(&url);
-        let req = match &self.secret {
-            Some(secret) => req.bearer_auth(secret),
-            None => req,
-        };
-        let res = req.send().await.map_err(|e| anyhow::anyhow!("{:?}", e))?;
-        if !res.status().is_success() {
-            warn!("Download failed: {}", res.status());
-            return Err(anyhow::anyhow!("Download failed"));
-        }
-        info!("Image downloaded from {}", url);
-        client_interface::ImageData::from_png(&res.bytes().await?)
+        self.transfer.download(&url).await
     }
 }
 
@@ -164,7 +184,8 @@ impl ClipboardSource for WebSocketSource {
 pub struct WebSocketSink {
     sink: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
     upload_url: String,
-    secret: Option<String>,
+    transfer: Transfer,
+    targets: Option<Vec<String>>,
 }
 
 impl WebSocketSink {
@@ -172,7 +193,7 @@ impl WebSocketSink {
         sink: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
         device_id: &str,
         server_url: &str,
-        secret: Option<String>,
+        transfer: Transfer,
     ) -> anyhow::Result<Self> {
         let mut url = url::Url::parse(server_url).unwrap();
         if url.scheme() == "ws" || url.scheme() == "http" {
@@ -186,10 +207,21 @@ impl WebSocketSink {
         Ok(Self {
             sink,
             upload_url: url.into(),
-            secret,
+            transfer,
+            targets: None,
         })
     }
 
+    /// Deliver published entries only to these devices, broadcast to all devices if empty.
+    pub fn with_targets(mut self, targets: Vec<String>) -> Self {
+        self.targets = if targets.is_empty() {
+            None
+        } else {
+            Some(targets)
+        };
+        self
+    }
+
     async fn publish_raw_string(&mut self, data: Option<String>) -> anyhow::Result<()> {
         self.sink
             .send(match data {
@@ -201,28 +233,9 @@ impl WebSocketSink {
         Ok(())
     }
 
-    async fn upload_image(&mut self, data: &client_interface::ImageData) -> anyhow::Result<String> {
+    async fn upload_image(&mut self, data: client_interface::ImageData) -> anyhow::Result<String> {
         debug!("Uploading image to {}", self.upload_url);
-        let client = reqwest::Client::new();
-        let part = reqwest::multipart::Part::bytes(data.to_png()?).mime_str("image/png")?;
-        let form = reqwest::multipart::Form::new().part("file", part);
-        let req = client.post(&self.upload_url);
-        let req = match &self.secret {
-            Some(secret) => req.bearer_auth(secret),
-            None => req,
-        };
-        let res = req
-            .multipart(form)
-            .send()
-            .await
-            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
-        if !res.status().is_success() {
-            warn!("Upload failed: {}", res.status());
-            return Err(anyhow::anyhow!("Upload failed"));
-        }
-        let image_url = res.text().await?;
-        debug!("Image uploaded to {}", image_url);
-        Ok(image_url)
+        self.transfer.upload(&self.upload_url, data).await
     }
 }
 
@@ -236,6 +249,8 @@ impl ClipboardSink for WebSocketSink {
                             id: None,
                             source: data.source,
                             content: ServerClipboardContent::Text(text),
+                            targets: self.targets.clone(),
+                            channel: None,
                         };
                         Some(serde_json::to_string(&data)?)
                     }
@@ -245,8 +260,10 @@ impl ClipboardSink for WebSocketSink {
                             id: None,
                             source: data.source,
                             content: ServerClipboardContent::ImageUrl(
-                                self.upload_image(&img).await?,
+                                self.upload_image(img).await?,
                             ),
+                            targets: self.targets.clone(),
+                            channel: None,
                         };
                         Some(serde_json::to_string(&data)?)
                     }
@@ -257,4 +274,10 @@ impl ClipboardSink for WebSocketSink {
         self.publish_raw_string(raw_string).await?;
         Ok(())
     }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        // Sends a close frame, the server then closes the source side
+        self.sink.close().await?;
+        Ok(())
+    }
 }
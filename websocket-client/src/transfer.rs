@@ -0,0 +1,256 @@
+//! Image uploads and downloads that go through a temporary file instead of memory, and continue
+//! where they stopped when the connection drops.
+
+use std::{
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use reqwest::{header, Method, RequestBuilder, StatusCode};
+use sha2::{Digest, Sha512};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use client_interface::{ImageData, Progress};
+
+/// Attempts for each chunk before the transfer fails.
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    client: reqwest::Client,
+    secret: Option<String>,
+    chunk_size: usize,
+    max_image_size: u64,
+}
+
+/// Hashes what is written, the hash names the upload so an interrupted one can be resumed.
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha512,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+async fn retry(retries: &mut u32, what: &str, e: impl std::fmt::Display) -> anyhow::Result<()> {
+    *retries += 1;
+    if *retries > MAX_RETRIES {
+        return Err(anyhow::anyhow!("{} failed: {}", what, e));
+    }
+    warn!("{} failed, retrying: {}", what, e);
+    tokio::time::sleep(RETRY_DELAY * 2u32.pow(*retries - 1)).await;
+    Ok(())
+}
+
+async fn parse_size(res: reqwest::Response) -> anyhow::Result<u64> {
+    Ok(res.text().await?.trim().parse()?)
+}
+
+impl Transfer {
+    pub fn new(secret: Option<String>, chunk_size: usize, max_image_size: u64) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            secret,
+            chunk_size,
+            max_image_size,
+        }
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let req = self.client.request(method, url);
+        match &self.secret {
+            Some(secret) => req.bearer_auth(secret),
+            None => req,
+        }
+    }
+
+    /// Upload the image to `upload_url` in chunks, and return its URL on the server.
+    pub async fn upload(&self, upload_url: &str, image: ImageData) -> anyhow::Result<String> {
+        let (file, id) = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+            let mut writer = HashWriter {
+                inner: BufWriter::new(tempfile::tempfile()?),
+                hasher: Sha512::new(),
+            };
+            image.write_png(&mut writer)?;
+            let id = hex::encode(writer.hasher.finalize())[..32].to_string();
+            let mut file = writer.inner.into_inner()?;
+            file.seek(SeekFrom::Start(0))?;
+            Ok((file, id))
+        })
+        .await??;
+        let size = file.metadata()?.len();
+        let mut file = tokio::fs::File::from_std(file);
+        let url = format!("{}/{}", upload_url, id);
+
+        let res = self.request(Method::GET, &url).send().await?;
+        if matches!(
+            res.status(),
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+        ) {
+            debug!("The server doesn't support chunked uploads");
+            let mut png = Vec::new();
+            file.read_to_end(&mut png).await?;
+            return self.upload_whole(upload_url, png).await;
+        }
+        let mut offset = parse_size(res.error_for_status()?).await?;
+        if offset > size {
+            return Err(anyhow::anyhow!(
+                "The server has more of upload {} than sent",
+                id
+            ));
+        }
+        if offset > 0 {
+            info!("Resuming upload {} at {} of {} bytes", id, offset, size);
+        }
+
+        let mut progress = Progress::new(format!("Uploading image {}", id), size);
+        let mut buf = vec![0u8; self.chunk_size];
+        let mut retries = 0;
+        while offset < size {
+            let len = (size - offset).min(self.chunk_size as u64) as usize;
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.read_exact(&mut buf[..len]).await?;
+            let res = self
+                .request(Method::PUT, &url)
+                .query(&[("offset", offset)])
+                .body(buf[..len].to_vec())
+                .send()
+                .await;
+            match res {
+                Ok(res) if res.status().is_success() => {
+                    offset = parse_size(res).await?;
+                    retries = 0;
+                    progress.update(offset);
+                }
+                Ok(res) if res.status() == StatusCode::CONFLICT => {
+                    // A chunk was stored but the response got lost, continue from the server's size
+                    offset = parse_size(res).await?;
+                    retry(&mut retries, "Upload", "out of step with the server").await?;
+                }
+                Ok(res) if res.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                    return Err(anyhow::anyhow!(
+                        "The image is {} bytes, more than the server accepts",
+                        size
+                    ));
+                }
+                Ok(res) if res.status().is_client_error() => {
+                    warn!("Upload failed: {}", res.status());
+                    return Err(anyhow::anyhow!("Upload failed"));
+                }
+                Ok(res) => retry(&mut retries, "Upload", res.status()).await?,
+                Err(e) => retry(&mut retries, "Upload", e).await?,
+            }
+        }
+
+        let res = self.request(Method::POST, &url).send().await?;
+        if !res.status().is_success() {
+            warn!("Upload failed: {}", res.status());
+            return Err(anyhow::anyhow!("Upload failed"));
+        }
+        let image_url = res.text().await?;
+        debug!("Image uploaded to {}", image_url);
+        Ok(image_url)
+    }
+
+    /// The multipart upload of servers without chunked uploads.
+    async fn upload_whole(&self, upload_url: &str, png: Vec<u8>) -> anyhow::Result<String> {
+        let part = reqwest::multipart::Part::bytes(png).mime_str("image/png")?;
+        let form = reqwest::multipart::Form::new().part("file", part);
+        let res = self
+            .request(Method::POST, upload_url)
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        if !res.status().is_success() {
+            warn!("Upload failed: {}", res.status());
+            return Err(anyhow::anyhow!("Upload failed"));
+        }
+        let image_url = res.text().await?;
+        debug!("Image uploaded to {}", image_url);
+        Ok(image_url)
+    }
+
+    /// Download the image into a temporary file, resuming with range requests after errors.
+    pub async fn download(&self, url: &str) -> anyhow::Result<ImageData> {
+        let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+        let mut size = 0u64;
+        let mut progress = None;
+        let mut retries = 0;
+        'download: loop {
+            let mut req = self.request(Method::GET, url);
+            if size > 0 {
+                req = req.header(header::RANGE, format!("bytes={}-", size));
+            }
+            let mut res = match req.send().await {
+                Ok(res) => res,
+                Err(e) => {
+                    retry(&mut retries, "Download", e).await?;
+                    continue;
+                }
+            };
+            match res.status() {
+                StatusCode::PARTIAL_CONTENT => {}
+                StatusCode::OK => {
+                    if size > 0 {
+                        debug!("The server can't resume the download, restarting");
+                        file.set_len(0).await?;
+                        file.seek(SeekFrom::Start(0)).await?;
+                        size = 0;
+                    }
+                }
+                status => {
+                    warn!("Download failed: {}", status);
+                    return Err(anyhow::anyhow!("Download failed"));
+                }
+            }
+            let total = res.content_length().map(|len| size + len);
+            if total.is_some_and(|total| total > self.max_image_size) {
+                return Err(anyhow::anyhow!(
+                    "The image is larger than {} bytes",
+                    self.max_image_size
+                ));
+            }
+            let progress = progress.get_or_insert_with(|| {
+                Progress::new(format!("Downloading {}", url), total.unwrap_or(0))
+            });
+            loop {
+                match res.chunk().await {
+                    Ok(Some(chunk)) => {
+                        size += chunk.len() as u64;
+                        if size > self.max_image_size {
+                            return Err(anyhow::anyhow!(
+                                "The image is larger than {} bytes",
+                                self.max_image_size
+                            ));
+                        }
+                        file.write_all(&chunk).await?;
+                        retries = 0;
+                        progress.update(size);
+                    }
+                    Ok(None) => break 'download,
+                    Err(e) => {
+                        retry(&mut retries, "Download", e).await?;
+                        continue 'download;
+                    }
+                }
+            }
+        }
+        info!("Image downloaded from {}", url);
+        file.flush().await?;
+        let mut file = file.into_std().await;
+        file.seek(SeekFrom::Start(0))?;
+        tokio::task::spawn_blocking(move || ImageData::read_png(BufReader::new(file))).await?
+    }
+}
@@ -0,0 +1,233 @@
+//! The `mqtt-bridge` role, exchanges entries between the server and devices on an MQTT broker.
+//!
+//! Entries from MQTT are added like entries from websocket devices, so they are stored in the
+//! history and broadcast, and broadcast entries of the default channel are published to MQTT.
+//! Images are read from and saved to the image directory of the server.
+
+use std::{collections::VecDeque, path::Path, sync::Arc};
+
+use anyhow::Context;
+use client_interface::{
+    Backoff, ClipSyncClient, ClipboardContent, ClipboardMessage, ClipboardRecord, ClipboardSink,
+    ClipboardSource, ImageData, ReconnectConfig, ServerClipboardContent, ServerClipboardRecord,
+};
+use log::{debug, info, warn};
+use mqtt_client::{MqttClientConfig, MqttClipSyncClient};
+use tokio::sync::{broadcast::error::RecvError, RwLock};
+
+use crate::global_state::{digest, GlobalState};
+
+/// Client id of the bridge if `mqtt-client-id` isn't set.
+const DEFAULT_CLIENT_ID: &str = "clip-sync-bridge";
+
+/// How many bridged entries are remembered to recognize them when they come back.
+const RECENT_ENTRIES: usize = 64;
+
+/// Source and id of the latest entries bridged in one direction.
+#[derive(Default)]
+struct Recent(VecDeque<(String, String)>);
+
+impl Recent {
+    fn insert(&mut self, source: &str, id: &str) {
+        if self.0.len() == RECENT_ENTRIES {
+            self.0.pop_front();
+        }
+        self.0.push_back((source.to_string(), id.to_string()));
+    }
+
+    fn contains(&self, source: &str, id: &str) -> bool {
+        self.0.iter().any(|(s, i)| s == source && i == id)
+    }
+}
+
+/// The id the server gives the entry, for images the digest of the PNG the bridge saves.
+fn entry_id(record: &ClipboardRecord) -> anyhow::Result<String> {
+    Ok(match &record.content {
+        ClipboardContent::Text(text) => digest(text.as_bytes()),
+        ClipboardContent::Image(image) => digest(&image.to_png()?),
+    })
+}
+
+/// Image directory of a device, sources from MQTT may not be usable as a file name.
+fn image_dir(source: &str) -> &str {
+    if source.is_empty() || source.starts_with('.') || source.contains(['/', '\\']) {
+        mqtt_client::payload::UNKNOWN_SOURCE
+    } else {
+        source
+    }
+}
+
+async fn to_message(
+    record: ClipboardRecord,
+    id: &str,
+    image_path: &Path,
+) -> anyhow::Result<ClipboardMessage> {
+    let content = match record.content {
+        ClipboardContent::Text(text) => ServerClipboardContent::Text(text),
+        ClipboardContent::Image(image) => {
+            // Named by content, so the same image is saved once
+            let url = format!("{}/{}.png", image_dir(&record.source), &id[0..32]);
+            let path = image_path.join(&url);
+            if !path.exists() {
+                if let Some(dir) = path.parent() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                tokio::fs::write(&path, image.to_png()?)
+                    .await
+                    .with_context(|| format!("Failed to write {:?}", path))?;
+            }
+            ServerClipboardContent::ImageUrl(url)
+        }
+    };
+    Ok(ClipboardMessage {
+        entry: ServerClipboardRecord {
+            id: None,
+            source: record.source,
+            content,
+            targets: None,
+            channel: None,
+        },
+        timestamp: chrono::Utc::now().timestamp(),
+        pinned: false,
+    })
+}
+
+async fn to_record(msg: &ClipboardMessage, image_path: &Path) -> anyhow::Result<ClipboardRecord> {
+    let content = match &msg.entry.content {
+        ServerClipboardContent::Text(text) => ClipboardContent::Text(text.clone()),
+        ServerClipboardContent::ImageUrl(url) => {
+            let path = image_path.join(url);
+            let png = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("Failed to read {:?}", path))?;
+            ClipboardContent::Image(ImageData::from_png(&png)?)
+        }
+    };
+    Ok(ClipboardRecord {
+        source: msg.entry.source.clone(),
+        content,
+    })
+}
+
+/// Bridge entries until the connection fails or the server shuts down.
+async fn bridge(
+    config: &MqttClientConfig,
+    global_state: &Arc<RwLock<GlobalState>>,
+    backoff: &mut Backoff,
+) -> anyhow::Result<()> {
+    let shutdown = global_state.read().await.shutdown_signal();
+    tokio::pin!(shutdown);
+    let connected = async {
+        let (_, mut source, sink) = MqttClipSyncClient::connect(config.clone()).await?;
+        source.wait_subscribed().await?;
+        anyhow::Ok((source, sink))
+    };
+    let (mut source, mut sink) = tokio::select! {
+        connected = connected => connected?,
+        _ = &mut shutdown => return Ok(()),
+    };
+    info!("MQTT bridge connected to {}", config.broker());
+    backoff.reset();
+
+    let (image_path, mut receiver) = {
+        let global_state = global_state.read().await;
+        (
+            global_state.get_image_path().clone(),
+            global_state.get_receiver(),
+        )
+    };
+    // Entries added from MQTT, and published to MQTT
+    let mut from_mqtt = Recent::default();
+    let mut to_mqtt = Recent::default();
+    loop {
+        tokio::select! {
+            record = source.poll() => {
+                let record = record?;
+                let id = entry_id(&record)?;
+                if to_mqtt.contains(&record.source, &id) {
+                    debug!("Skipping entry the bridge published");
+                    continue;
+                }
+                let msg = to_message(record, &id, &image_path).await?;
+                from_mqtt.insert(&msg.entry.source, &id);
+                global_state.read().await.add_entry(msg, true).await?;
+            }
+            msg = receiver.recv() => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("MQTT bridge skipped {} entries", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                // Only the default channel is bridged, and not entries for specific devices
+                if msg.entry.channel.is_some() || msg.entry.targets.is_some() {
+                    continue;
+                }
+                let id = msg.entry.id.as_deref().unwrap_or_default();
+                if from_mqtt.contains(&msg.entry.source, id) {
+                    continue;
+                }
+                let record = match to_record(&msg, &image_path).await {
+                    Ok(record) => record,
+                    Err(e) => {
+                        warn!("MQTT bridge skipped an entry from '{}': {:#}", msg.entry.source, e);
+                        continue;
+                    }
+                };
+                to_mqtt.insert(&record.source, &entry_id(&record)?);
+                sink.publish(Some(record)).await?;
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    // Publishes `offline`, the last will is only sent if the connection is lost
+    sink.close().await.and(source.close().await)
+}
+
+/// Run the bridge until the server shuts down, reconnecting with the jittered backoff of
+/// `reconnect`.
+pub(crate) async fn run(
+    mut config: MqttClientConfig,
+    reconnect: ReconnectConfig,
+    global_state: Arc<RwLock<GlobalState>>,
+) {
+    config
+        .mqtt_client_id
+        .get_or_insert_with(|| DEFAULT_CLIENT_ID.to_string());
+    let shutdown = global_state.read().await.shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut backoff = Backoff::new(&reconnect);
+    loop {
+        match bridge(&config, &global_state, &mut backoff).await {
+            Ok(()) => return,
+            Err(e) => warn!("MQTT bridge disconnected: {:#}", e),
+        }
+        let delay = backoff.next_delay();
+        info!("Reconnecting the MQTT bridge in {:?}", delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = &mut shutdown => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent() {
+        let mut recent = Recent::default();
+        for i in 0..=RECENT_ENTRIES {
+            recent.insert("laptop", &i.to_string());
+        }
+        assert!(!recent.contains("laptop", "0"));
+        assert!(recent.contains("laptop", "1"));
+        assert!(!recent.contains("phone", "1"));
+        assert_eq!(image_dir("laptop"), "laptop");
+        assert_eq!(image_dir("../etc"), "mqtt");
+        assert_eq!(image_dir("a/b"), "mqtt");
+    }
+}
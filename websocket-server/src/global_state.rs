@@ -1,4 +1,4 @@
-use std::{collections::HashSet, path::PathBuf};
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
 
 use client_interface::ServerClipboardContent;
 use log::{debug, info, warn};
@@ -7,20 +7,71 @@ use sha2::Digest;
 use tokio::{
     io::AsyncReadExt,
     runtime::{Builder, Handle},
-    sync::broadcast::Sender,
+    sync::{broadcast::Sender, watch},
 };
 
-use super::{search::Search, ClipboardMessage, QueryParam, QueryResult, ServerConfig};
+use super::{
+    search::Search, ClipboardMessage, QueryParam, QueryResult, ServerConfig, DEFAULT_CHANNEL,
+};
+
+/// Directory in the image path for unfinished uploads, it isn't served.
+pub(crate) const UPLOAD_DIR: &str = ".uploads";
+
+/// The channel, id and targets of an entry, re-sending all of them is a duplicate.
+type EntryKey = (String, String, Option<Vec<String>>);
+
+/// How long entries are remembered to drop them if they are sent again, e.g. from an outbox
+/// after reconnecting. Copying the same content again within this time isn't synced.
+const RECENT_ENTRY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// SHA-512 in hex, the ID of text entries and of images by their PNG file.
+pub(crate) fn digest(bytes: &[u8]) -> String {
+    let mut hasher = <sha2::Sha512 as Digest>::new();
+    hasher.update(bytes);
+    hex::encode(std::convert::Into::<[u8; 64]>::into(hasher.finalize()))
+}
+
+/// SHA-512 in hex of a file read in pieces, `None` if it's missing or empty.
+pub(crate) async fn file_digest(path: impl AsRef<std::path::Path>) -> Option<String> {
+    let mut file = tokio::fs::File::open(path).await.ok()?;
+    let mut buf = Vec::with_capacity(4096);
+    let mut read_bytes = 0;
+    let mut hasher = <sha2::Sha512 as Digest>::new();
+    while let Ok(n) = file.read_buf(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        read_bytes += n;
+        hasher.update(&buf[0..n]);
+        buf.clear();
+    }
+    (read_bytes > 0).then(|| hex::encode(std::convert::Into::<[u8; 64]>::into(hasher.finalize())))
+}
 
 pub struct GlobalState {
     sender: Sender<ClipboardMessage>,
     device_list: HashSet<String>,
     online_device_list: HashSet<String>,
     search: Search,
-    _rt: tokio::runtime::Runtime,
+    rt: Option<tokio::runtime::Runtime>,
     thread_pool: Handle,
     image_path: PathBuf,
+    max_upload_size: u64,
+    /// Serializes the requests of each chunked upload, a retry may overlap the original request
+    upload_locks: Cache<PathBuf, Arc<tokio::sync::Mutex<()>>>,
     cache: Cache<String, String>,
+    /// Entries received lately, to drop re-sent ones
+    recent_entries: Cache<EntryKey, ()>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Drop for GlobalState {
+    fn drop(&mut self) {
+        // The state is dropped inside the async runtime when the server is stopped
+        if let Some(rt) = self.rt.take() {
+            rt.shutdown_background();
+        }
+    }
 }
 
 impl GlobalState {
@@ -41,10 +92,41 @@ impl GlobalState {
             device_list,
             online_device_list: HashSet::new(),
             search,
-            _rt: rt,
+            rt: Some(rt),
             thread_pool: handle,
             image_path: args.image_path.clone().unwrap(),
+            max_upload_size: args.max_upload_size(),
+            upload_locks: Cache::builder()
+                .time_to_idle(Duration::from_secs(60 * 60))
+                .build(),
             cache: Cache::new(10_000),
+            recent_entries: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(RECENT_ENTRY_TTL)
+                .build(),
+            shutdown: watch::Sender::new(false),
+        }
+    }
+
+    /// Resolves once the server starts shutting down.
+    pub fn shutdown_signal(&self) -> impl std::future::Future<Output = ()> {
+        let mut receiver = self.shutdown.subscribe();
+        async move {
+            receiver.wait_for(|shutdown| *shutdown).await.ok();
+        }
+    }
+
+    /// Tell all connections to close.
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Wait for pending index writes, so no entry is lost halfway through a commit.
+    pub async fn finish_shutdown(&mut self) {
+        if let Some(rt) = self.rt.take() {
+            tokio::task::spawn_blocking(move || rt.shutdown_timeout(Duration::from_secs(10)))
+                .await
+                .ok();
         }
     }
 
@@ -52,6 +134,22 @@ impl GlobalState {
         &self.image_path
     }
 
+    /// Where chunked uploads are kept until they are finished.
+    pub fn get_upload_path(&self) -> PathBuf {
+        self.image_path.join(UPLOAD_DIR)
+    }
+
+    pub fn get_max_upload_size(&self) -> u64 {
+        self.max_upload_size
+    }
+
+    /// The lock of the chunked upload at `path`.
+    pub async fn upload_lock(&self, path: &std::path::Path) -> Arc<tokio::sync::Mutex<()>> {
+        self.upload_locks
+            .get_with(path.to_path_buf(), async { Default::default() })
+            .await
+    }
+
     pub fn get_receiver(&self) -> tokio::sync::broadcast::Receiver<ClipboardMessage> {
         self.sender.subscribe()
     }
@@ -83,19 +181,38 @@ impl GlobalState {
             warn!("Ignored invalid clipboard entry.");
             return Ok(());
         }
-        self.sender.send(msg.clone())?;
+        msg.pinned = false;
         match &msg.entry.content {
             ServerClipboardContent::ImageUrl(url) => {
                 let digest = self.image_digest(url).await?;
                 msg.entry.id = Some(digest);
             }
             ServerClipboardContent::Text(text) => {
-                let mut hasher = <sha2::Sha512 as Digest>::new();
-                hasher.update(text.as_bytes());
-                let digest = hex::encode(std::convert::Into::<[u8; 64]>::into(hasher.finalize()));
-                msg.entry.id = Some(digest);
+                msg.entry.id = Some(digest(text.as_bytes()));
             }
         }
+        // Clients may re-send entries they are not sure were delivered, e.g. after reconnecting.
+        let key: EntryKey = (
+            msg.entry
+                .channel
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CHANNEL.to_string()),
+            msg.entry.id.clone().unwrap_or_default(),
+            msg.entry.targets.clone(),
+        );
+        if !self
+            .recent_entries
+            .entry(key)
+            .or_insert(())
+            .await
+            .is_fresh()
+        {
+            debug!("Ignored duplicated clipboard entry.");
+            return Ok(());
+        }
+        // The history is shared by all devices, entries for specific devices aren't kept in it
+        let store = store && msg.entry.targets.is_none();
+        self.sender.send(msg.clone())?;
         let search = self.search.clone();
         self.thread_pool
             .spawn_blocking(move || -> anyhow::Result<()> {
@@ -165,6 +282,38 @@ impl GlobalState {
         }
     }
 
+    /// Remove an entry and its image, returns whether it existed.
+    pub async fn delete_entry(&self, id: &str) -> anyhow::Result<bool> {
+        let Some(msg) = self.get_entry_by_id(id).await? else {
+            return Ok(false);
+        };
+        let search = self.search.clone();
+        let id = id.to_string();
+        let deleted = self
+            .thread_pool
+            .spawn_blocking(move || search.delete_entry(&id))
+            .await??;
+        if let ServerClipboardContent::ImageUrl(url) = &msg.entry.content {
+            let path = self.image_path.join(url);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("Failed to remove image {:?}: {}", path, e);
+            }
+            self.cache
+                .invalidate(path.to_str().unwrap_or_default())
+                .await;
+        }
+        Ok(deleted)
+    }
+
+    /// Pin or unpin an entry, returns whether it exists.
+    pub async fn set_pinned(&self, id: &str, pinned: bool) -> anyhow::Result<bool> {
+        let search = self.search.clone();
+        let id = id.to_string();
+        self.thread_pool
+            .spawn_blocking(move || search.set_pinned(&id, pinned))
+            .await?
+    }
+
     async fn update_image_digest_cache(&self, msg: &ClipboardMessage) {
         // Whenever we do query, we update the image digest cache if possible.
         if let ServerClipboardContent::ImageUrl(url) = &msg.entry.content {
@@ -186,24 +335,7 @@ impl GlobalState {
         let digest = self
             .cache
             .get_with(path.to_string(), async move {
-                let Ok(mut file) = tokio::fs::File::open(path).await else {
-                    return Default::default();
-                };
-                let mut buf = Vec::with_capacity(4096);
-                let mut read_bytes = 0;
-                let mut hasher = <sha2::Sha512 as Digest>::new();
-                while let Ok(n) = file.read_buf(&mut buf).await {
-                    if n == 0 {
-                        break;
-                    }
-                    read_bytes += n;
-                    hasher.update(&buf[0..n]);
-                    buf.clear();
-                }
-                if read_bytes == 0 {
-                    return Default::default();
-                }
-                hex::encode(std::convert::Into::<[u8; 64]>::into(hasher.finalize()))
+                file_digest(path).await.unwrap_or_default()
             })
             .await;
         if digest.is_empty() {
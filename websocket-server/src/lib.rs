@@ -1,6 +1,5 @@
 use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use chrono::Utc;
 use client_interface::{ClipboardMessage, Params, ServerClipboardContent};
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, info, trace, warn};
@@ -10,40 +9,79 @@ use poem::{
     http::StatusCode,
     listener::{Listener, RustlsCertificate, RustlsConfig, TcpListener},
     middleware::Cors,
-    post,
+    post, put,
     web::{
-        websocket::{Message, WebSocket},
-        Data, Json, Multipart, Path,
+        websocket::{CloseCode, Message, WebSocket},
+        Data, Json, Multipart, Path, Query,
     },
     EndpointExt, IntoResponse, Request, Route, Server,
 };
-use sha2::Digest;
-use tokio::sync::{broadcast::channel, RwLock};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{broadcast::channel, RwLock},
+};
 
 use crate::global_state::GlobalState;
 
 mod auth;
+#[cfg(feature = "mqtt-bridge")]
+mod bridge;
 mod global_state;
 mod models;
 mod search;
+mod upload;
 
 pub use models::*;
+pub use search::Search;
+
+/// How long open connections get to finish after a shutdown is requested.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct ConnectParams {
+    channels: Option<String>,
+}
+
+impl ConnectParams {
+    /// Channels to join, the first one is where entries from the device are published by default.
+    fn channels(&self) -> Vec<String> {
+        let mut channels: Vec<String> = vec![];
+        for channel in self.channels.as_deref().unwrap_or_default().split(',') {
+            let channel = channel.trim();
+            if !channel.is_empty() && !channels.iter().any(|c| c == channel) {
+                channels.push(channel.to_string());
+            }
+        }
+        if channels.is_empty() {
+            channels.push(DEFAULT_CHANNEL.to_string());
+        }
+        channels
+    }
+}
 
 #[handler]
 async fn ws(
     Path(name): Path<String>,
+    Query(params): Query<ConnectParams>,
     ws: WebSocket,
     data: Data<&Arc<RwLock<GlobalState>>>,
 ) -> impl IntoResponse {
-    debug!("New connection from device '{}'.", &name);
+    let channels = params.channels();
+    debug!(
+        "New connection from device '{}' in channels {:?}.",
+        &name, &channels
+    );
     let global_state = data.0.clone();
     let mut receiver = global_state.read().await.get_receiver();
+    let shutdown = global_state.read().await.shutdown_signal();
     ws.on_upgrade(move |socket| async move {
         info!("Websocket to device '{}' created.", &name);
         let (mut sink, mut stream) = socket.split();
         global_state.write().await.add_device(&name);
 
         let name_clone = name.clone();
+        let channels_clone = channels.clone();
         let global_state_clone = global_state.clone();
         tokio::spawn(async move {
             while let Some(Ok(msg)) = stream.next().await {
@@ -52,7 +90,7 @@ async fn ws(
                     continue;
                 }
                 if let Message::Text(text) = msg {
-                    if let Ok(data) = serde_json::from_str::<ClipboardMessage>(&text) {
+                    if let Ok(mut data) = serde_json::from_str::<ClipboardMessage>(&text) {
                         if name_clone != data.entry.source {
                             warn!(
                                 "Invalid message source '{}' from device '{name_clone}'.",
@@ -60,6 +98,16 @@ async fn ws(
                             );
                             continue;
                         }
+                        let channel = data
+                            .entry
+                            .channel
+                            .clone()
+                            .unwrap_or_else(|| channels_clone[0].clone());
+                        if !channels_clone.contains(&channel) {
+                            warn!("Device '{name_clone}' is not in channel '{channel}'.");
+                            continue;
+                        }
+                        data.entry.channel = (channel != DEFAULT_CHANNEL).then_some(channel);
                         if global_state_clone
                             .read()
                             .await
@@ -80,12 +128,32 @@ async fn ws(
         });
 
         tokio::spawn(async move {
+            tokio::pin!(shutdown);
             loop {
-                match tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await {
+                let msg = tokio::select! {
+                    msg = tokio::time::timeout(Duration::from_secs(5), receiver.recv()) => msg,
+                    _ = &mut shutdown => {
+                        debug!("Closing connection to device '{}'.", &name);
+                        let frame = (CloseCode::Away, "Server is shutting down".to_string());
+                        sink.send(Message::Close(Some(frame))).await.ok();
+                        break;
+                    }
+                };
+                match msg {
                     Ok(Ok(msg)) => {
                         if msg.entry.source == name {
                             continue;
                         }
+                        let channel = msg.entry.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+                        if !channels.iter().any(|c| c == channel) {
+                            continue;
+                        }
+                        if let Some(targets) = &msg.entry.targets {
+                            if !targets.contains(&name) {
+                                trace!("Message is not targeted to device '{}'.", &name);
+                                continue;
+                            }
+                        }
                         if sink
                             .send(Message::Text(serde_json::to_string(&msg).unwrap()))
                             .await
@@ -162,65 +230,150 @@ async fn upload_image(
     mut multipart: Multipart,
     data: Data<&Arc<RwLock<GlobalState>>>,
 ) -> poem::Result<String> {
+    // The device name is the directory of the image
+    if !upload::is_safe_name(&name) {
+        return Err(poem::Error::from_status(StatusCode::BAD_REQUEST));
+    }
     if let Ok(Some(field)) = multipart.next_field().await {
         if field.content_type().unwrap_or("") != "image/png" {
             return Err(poem::Error::from_status(StatusCode::BAD_REQUEST));
         }
-        let timestamp = Utc::now().format("%Y-%m-%d-%H-%M-%S-%6f");
-        let dir: PathBuf = data.0.read().await.get_image_path().join(&name);
-        tokio::fs::create_dir_all(&dir).await.map_err(|e| {
-            warn!("Failed to create directory: {}", e);
+        let internal_error = |e: std::io::Error| {
+            warn!("Failed to save image: {}", e);
             poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)
-        })?;
-        let mut suffix = 1usize;
-        loop {
-            let filename = format!("{}-{}.png", timestamp, suffix);
-            let path: PathBuf = dir.join(&filename);
-            if path.exists() {
-                suffix += 1;
-                continue;
-            }
-            break;
-        }
-        let filename: String = format!("{}-{}.png", timestamp, suffix);
+        };
+        let (dir, upload_dir, max_size) = {
+            let global_state = data.0.read().await;
+            (
+                global_state.get_image_path().join(&name),
+                global_state.get_upload_path().join(&name),
+                global_state.get_max_upload_size(),
+            )
+        };
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(internal_error)?;
+        tokio::fs::create_dir_all(&upload_dir)
+            .await
+            .map_err(internal_error)?;
+        let filename = upload::new_image_name(&dir);
         let filepath: PathBuf = dir.join(&filename);
+        // Written next to the chunked uploads first, so a partial image is never served
+        let part_path = upload_dir.join(format!("{}.part", filename));
         let part_name = field.name().map(ToString::to_string);
         let file_name = field.file_name().map(ToString::to_string);
-        if let Ok(bytes) = field.bytes().await {
-            println!(
-                "name={:?} filename={:?} length={}, save={:?}",
-                part_name,
-                file_name,
-                bytes.len(),
-                filepath,
-            );
-            let mut hasher = <sha2::Sha512 as Digest>::new();
-            hasher.update(&bytes);
-            let digest = hex::encode(Into::<[u8; 64]>::into(hasher.finalize()));
-            let existing_entry = data.0.read().await.get_entry_by_id(&digest).await.unwrap();
-            if let Some(existing_entry) = existing_entry {
-                if let ServerClipboardContent::ImageUrl(url) = &existing_entry.entry.content {
-                    debug!("Image already exists: {:?}", existing_entry);
-                    return Ok(url.clone());
-                }
+        let mut file = tokio::fs::File::create(&part_path)
+            .await
+            .map_err(internal_error)?;
+        // One byte more than allowed is read to tell whether the limit is exceeded
+        let mut body = field.into_async_read().take(max_size + 1);
+        let written = match tokio::io::copy(&mut body, &mut file).await {
+            Ok(written) => written,
+            Err(e) => {
+                tokio::fs::remove_file(&part_path).await.ok();
+                return Err(internal_error(e));
+            }
+        };
+        file.flush().await.map_err(internal_error)?;
+        drop(file);
+        if written > max_size {
+            warn!("Image from device '{}' is too large.", name);
+            tokio::fs::remove_file(&part_path).await.ok();
+            return Err(poem::Error::from_status(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        debug!(
+            "name={:?} filename={:?} length={}, save={:?}",
+            part_name, file_name, written, filepath,
+        );
+        let digest = global_state::file_digest(&part_path)
+            .await
+            .unwrap_or_default();
+        if let Ok(Some(existing_entry)) = data.0.read().await.get_entry_by_id(&digest).await {
+            if let ServerClipboardContent::ImageUrl(url) = &existing_entry.entry.content {
+                debug!("Image already exists: {:?}", existing_entry);
+                tokio::fs::remove_file(&part_path).await.ok();
+                return Ok(url.clone());
             }
-            tokio::fs::write(&filepath, bytes).await.map_err(|e| {
-                warn!("Failed to write file: {}", e);
-                poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)
-            })?;
-            debug!("Image saved to {:?}", filepath);
         }
+        tokio::fs::rename(&part_path, &filepath)
+            .await
+            .map_err(internal_error)?;
+        debug!("Image saved to {:?}", filepath);
         return Ok(format!("{name}/{}", filename));
     }
     warn!("No image data received.");
     Err(poem::Error::from_status(StatusCode::BAD_REQUEST))
 }
 
+#[handler]
+async fn get_entry(
+    Path(id): Path<String>,
+    data: Data<&Arc<RwLock<GlobalState>>>,
+) -> poem::Result<Json<ClipboardMessage>> {
+    match data.0.read().await.get_entry_by_id(&id).await {
+        Ok(Some(entry)) => Ok(Json(entry)),
+        Ok(None) => Err(poem::Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => {
+            warn!("Failed to get entry '{}': {}", id, e);
+            Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[handler]
+async fn delete_entry(
+    Path(id): Path<String>,
+    data: Data<&Arc<RwLock<GlobalState>>>,
+) -> poem::Result<StatusCode> {
+    match data.0.read().await.delete_entry(&id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(poem::Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => {
+            warn!("Failed to delete entry '{}': {}", id, e);
+            Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+async fn set_pinned(
+    id: &str,
+    pinned: bool,
+    global_state: &Arc<RwLock<GlobalState>>,
+) -> poem::Result<StatusCode> {
+    match global_state.read().await.set_pinned(id, pinned).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(poem::Error::from_status(StatusCode::NOT_FOUND)),
+        Err(e) => {
+            warn!("Failed to pin entry '{}': {}", id, e);
+            Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[handler]
+async fn pin_entry(
+    Path(id): Path<String>,
+    data: Data<&Arc<RwLock<GlobalState>>>,
+) -> poem::Result<StatusCode> {
+    set_pinned(&id, true, data.0).await
+}
+
+#[handler]
+async fn unpin_entry(
+    Path(id): Path<String>,
+    data: Data<&Arc<RwLock<GlobalState>>>,
+) -> poem::Result<StatusCode> {
+    set_pinned(&id, false, data.0).await
+}
+
 #[handler]
 async fn get_image_collection(
     Path(name): Path<String>,
     data: Data<&Arc<RwLock<GlobalState>>>,
 ) -> poem::Result<Json<Vec<String>>> {
+    if !upload::is_safe_name(&name) {
+        return Err(poem::Error::from_status(StatusCode::NOT_FOUND));
+    }
     let dir: PathBuf = data.0.read().await.get_image_path().join(&name);
     let mut ret = Vec::new();
     let mut entries = tokio::fs::read_dir(&dir).await.map_err(|e| {
@@ -237,6 +390,19 @@ async fn get_image_collection(
     Ok(Json(ret))
 }
 
+/// Unfinished chunked uploads are kept with the images, but aren't served.
+async fn hide_uploads(req: Request) -> poem::Result<Request> {
+    if req
+        .uri()
+        .path()
+        .split('/')
+        .any(|segment| segment.starts_with('.'))
+    {
+        return Err(poem::Error::from_status(StatusCode::NOT_FOUND));
+    }
+    Ok(req)
+}
+
 fn api(
     args: ServerConfig,
     global_state: Arc<RwLock<GlobalState>>,
@@ -253,23 +419,139 @@ fn api(
             get(get_online_device_list).data(global_state.clone()),
         )
         .at("/query", get(query).data(global_state.clone()))
+        .at(
+            "/entry/:id",
+            get(get_entry)
+                .delete(delete_entry)
+                .data(global_state.clone()),
+        )
+        .at(
+            "/entry/:id/pin",
+            put(pin_entry)
+                .delete(unpin_entry)
+                .data(global_state.clone()),
+        )
         .at(
             "/collection/:device_id",
             get(get_image_collection).data(global_state.clone()),
         )
         .nest(
             "/images",
-            StaticFilesEndpoint::new(image_path).show_files_listing(),
+            StaticFilesEndpoint::new(image_path)
+                .show_files_listing()
+                .before(hide_uploads),
         )
         .at(
             "/upload-image/:device_id",
             post(upload_image.data(global_state.clone())),
         )
+        .at(
+            "/upload-image/:device_id/:upload_id",
+            get(upload::upload_status)
+                .put(upload::upload_chunk)
+                .post(upload::finish_upload)
+                .data(global_state.clone()),
+        )
         .with(Cors::new())
         .with(auth::ApiKeyAuth::new(args.secret))
 }
 
-pub async fn server_main(mut args: ServerConfig) -> Result<(), std::io::Error> {
+/// How often the TLS certificate and key are checked for changes.
+const TLS_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+fn load_tls_config(cert_path: &PathBuf, key_path: &PathBuf) -> std::io::Result<RustlsConfig> {
+    let cert = std::fs::read(cert_path)?;
+    let key = std::fs::read(key_path)?;
+    Ok(RustlsConfig::new().fallback(RustlsCertificate::new().key(key).cert(cert)))
+}
+
+fn modified_time(path: &PathBuf) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Certificate path, key path and their modified times.
+type TlsFiles = (
+    PathBuf,
+    PathBuf,
+    Option<std::time::SystemTime>,
+    Option<std::time::SystemTime>,
+);
+
+/// Wait until the certificate or the key changes, and load them again.
+async fn next_tls_config(files: TlsFiles) -> Option<(RustlsConfig, TlsFiles)> {
+    let (cert_path, key_path, mut cert_modified, mut key_modified) = files;
+    loop {
+        tokio::time::sleep(TLS_POLL_INTERVAL).await;
+        let modified = (modified_time(&cert_path), modified_time(&key_path));
+        if modified == (cert_modified, key_modified) {
+            continue;
+        }
+        (cert_modified, key_modified) = modified;
+        match load_tls_config(&cert_path, &key_path) {
+            Ok(config) => {
+                info!("TLS certificate reloaded.");
+                return Some((config, (cert_path, key_path, cert_modified, key_modified)));
+            }
+            // Probably only one of the files is updated so far
+            Err(e) => warn!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
+
+/// The TLS config, followed by a new one whenever the certificate or the key file changes.
+///
+/// Renewed certificates are used by new connections without restarting the server.
+fn tls_config_stream(
+    cert_path: PathBuf,
+    key_path: PathBuf,
+) -> std::io::Result<impl futures_util::Stream<Item = RustlsConfig> + Send + 'static> {
+    let initial = load_tls_config(&cert_path, &key_path)?;
+    let (cert_modified, key_modified) = (modified_time(&cert_path), modified_time(&key_path));
+    let files = (cert_path, key_path, cert_modified, key_modified);
+    Ok(
+        futures_util::stream::once(futures_util::future::ready(initial))
+            .chain(futures_util::stream::unfold(files, next_tls_config)),
+    )
+}
+
+pub async fn server_main(args: ServerConfig) -> Result<(), std::io::Error> {
+    server_main_with_shutdown(args, std::future::pending()).await
+}
+
+/// Run the server until `signal` resolves, then stop accepting connections, close the
+/// websockets and wait for pending index writes.
+pub async fn server_main_with_shutdown(
+    args: ServerConfig,
+    signal: impl std::future::Future<Output = ()>,
+) -> Result<(), std::io::Error> {
+    run_server(args, signal, |_| None).await
+}
+
+/// Like [`server_main_with_shutdown`], and exchange entries with the MQTT broker of `bridge`,
+/// reconnecting to it as set in `reconnect`.
+#[cfg(feature = "mqtt-bridge")]
+pub async fn server_main_with_bridge(
+    args: ServerConfig,
+    bridge: mqtt_client::MqttClientConfig,
+    reconnect: client_interface::ReconnectConfig,
+    signal: impl std::future::Future<Output = ()>,
+) -> Result<(), std::io::Error> {
+    run_server(args, signal, |global_state| {
+        Some(tokio::spawn(bridge::run(
+            bridge,
+            reconnect,
+            global_state.clone(),
+        )))
+    })
+    .await
+}
+
+/// Run the server, `spawn` starts a task that stops with the server.
+async fn run_server(
+    mut args: ServerConfig,
+    signal: impl std::future::Future<Output = ()>,
+    spawn: impl FnOnce(&Arc<RwLock<GlobalState>>) -> Option<tokio::task::JoinHandle<()>>,
+) -> Result<(), std::io::Error> {
     let (sender, _) = channel::<ClipboardMessage>(32);
     if args.image_path.is_none() {
         args.image_path = Some(PathBuf::from("./images"));
@@ -283,21 +565,42 @@ pub async fn server_main(mut args: ServerConfig) -> Result<(), std::io::Error> {
             "/",
             StaticFilesEndpoint::new(args.web_root.as_ref().unwrap()).index_file("index.html"),
         )
-        .nest("/api", api(args.clone(), global_state));
+        .nest("/api", api(args.clone(), global_state.clone()));
 
+    let global_state_clone = global_state.clone();
+    let signal = async move {
+        signal.await;
+        info!("Shutting down server.");
+        global_state_clone.read().await.begin_shutdown();
+    };
+    let task = spawn(&global_state);
+    let cleanup = tokio::spawn(upload::remove_stale_uploads(
+        global_state.read().await.get_upload_path(),
+    ));
     let listener = TcpListener::bind(args.endpoint);
-    if args.use_tls {
-        let cert = std::fs::read(args.cert_path.unwrap())?;
-        let key = std::fs::read(args.key_path.unwrap())?;
-        Server::new(
-            listener
-                .rustls(RustlsConfig::new().fallback(RustlsCertificate::new().key(key).cert(cert))),
-        )
-        .run(app)
-        .await?;
-    } else {
-        Server::new(listener).run(app).await?;
+    let result = async {
+        if args.use_tls {
+            let tls_config = tls_config_stream(args.cert_path.unwrap(), args.key_path.unwrap())?;
+            Server::new(listener.rustls(tls_config))
+                .run_with_graceful_shutdown(app, signal, Some(SHUTDOWN_TIMEOUT))
+                .await
+        } else {
+            Server::new(listener)
+                .run_with_graceful_shutdown(app, signal, Some(SHUTDOWN_TIMEOUT))
+                .await
+        }
+    }
+    .await;
+    if let Some(task) = task {
+        if result.is_err() {
+            task.abort();
+        }
+        task.await.ok();
     }
+    cleanup.abort();
+    result?;
+    global_state.write().await.finish_shutdown().await;
+    info!("Server stopped.");
     Ok(())
 }
 
@@ -310,6 +613,8 @@ mod tests {
             id: None,
             source: "test".to_string(),
             content: ServerClipboardContent::Text("test".to_string()),
+            targets: None,
+            channel: None,
         };
         let json = serde_json::to_string(&data).unwrap();
         println!("{}", json);
@@ -319,6 +624,7 @@ mod tests {
         let msg = super::ClipboardMessage {
             entry: data,
             timestamp: 0,
+            pinned: false,
         };
         let json = serde_json::to_string(&msg).unwrap();
         println!("{}", json);
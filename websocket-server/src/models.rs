@@ -4,7 +4,10 @@ use chrono::{DateTime, TimeZone, Utc};
 use client_interface::{ClipboardMessage, Params};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Default, Deserialize)]
+/// The channel clients join when they don't ask for any.
+pub const DEFAULT_CHANNEL: &str = "default";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct ServerConfig {
     pub endpoint: String,
@@ -16,15 +19,34 @@ pub struct ServerConfig {
     pub web_root: Option<PathBuf>,
     pub index_path: Option<PathBuf>,
     pub image_path: Option<PathBuf>,
+    /// Largest image accepted in bytes, default is 100 MB
+    pub max_upload_size: Option<u64>,
+}
+
+impl ServerConfig {
+    pub fn max_upload_size(&self) -> u64 {
+        self.max_upload_size.unwrap_or(100 * 1024 * 1024)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Sort {
+    #[default]
+    Newest,
+    Oldest,
+    /// Best match for the query first
+    Score,
 }
 
 pub struct QueryParam {
     pub query: Option<String>,
     pub sources: HashSet<String>,
+    pub channels: HashSet<String>,
     pub time_range: Option<(DateTime<Utc>, DateTime<Utc>)>,
     pub skip: usize,
     pub size: usize,
-    pub sort_by_score: bool,
+    pub sort: Sort,
+    pub pinned_only: bool,
 }
 
 impl From<Params> for QueryParam {
@@ -38,6 +60,13 @@ impl From<Params> for QueryParam {
                 .map(|s| s.to_string())
                 .filter(|s| !s.is_empty())
                 .collect(),
+            channels: val
+                .channel
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
             time_range: match (val.begin, val.end) {
                 (Some(begin), Some(end)) => Some((
                     Utc.timestamp_opt(begin, 0).unwrap(),
@@ -52,12 +81,17 @@ impl From<Params> for QueryParam {
             },
             skip: val.skip.unwrap_or(0),
             size: val.size.unwrap_or(10),
-            sort_by_score: val.sort.unwrap_or("time".to_string()) == "score",
+            sort: match val.sort.as_deref() {
+                Some("score") => Sort::Score,
+                Some("oldest") => Sort::Oldest,
+                _ => Sort::Newest,
+            },
+            pinned_only: val.pinned.unwrap_or(false),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueryResult {
     pub total: usize,
     pub skip: usize,
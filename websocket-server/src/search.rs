@@ -1,22 +1,31 @@
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
+use anyhow::Context;
 use client_interface::{ServerClipboardContent, ServerClipboardRecord};
-use log::debug;
+use log::{debug, warn};
 use tantivy::{
     collector::{Count, FruitHandle, MultiCollector, TopDocs},
     directory::MmapDirectory,
     doc,
     merge_policy::LogMergePolicy,
-    query::{AllQuery, BooleanQuery, Query, QueryParser, RangeQuery, TermQuery, TermSetQuery},
+    query::{
+        AllQuery, BooleanQuery, EmptyQuery, Query, QueryParser, RangeQuery, TermQuery, TermSetQuery,
+    },
     query_grammar::Occur,
     schema::{
-        Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, STORED,
+        Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED,
+        STORED,
     },
     tokenizer::{LowerCaser, NgramTokenizer, TextAnalyzer},
-    DocAddress, Index, IndexReader, Order, ReloadPolicy, TantivyDocument, Term,
+    DocAddress, Index, IndexReader, IndexSettings, IndexWriter, Order, ReloadPolicy,
+    TantivyDocument, Term,
 };
 
-use super::{ClipboardMessage, QueryParam, QueryResult};
+use super::{ClipboardMessage, QueryParam, QueryResult, Sort, DEFAULT_CHANNEL};
 
 const TOKENIZER_NAME: &str = "ngram_m_n";
 
@@ -29,11 +38,21 @@ pub struct Search {
     content: Field,
     url: Field,
     timestamp: Field,
+    channel: Option<Field>,
+    pinned: Option<Field>,
     query_parser: QueryParser,
+    /// Only one writer can be open at a time, and checks before a write must see the result of
+    /// the previous one. Entries from different devices may arrive together with pins and deletes
+    writer_lock: Arc<Mutex<()>>,
 }
 
 impl Search {
     pub fn new(index_path: Option<PathBuf>) -> Self {
+        Self::open(index_path).expect("Failed to open the index")
+    }
+
+    /// Open or create the index at `index_path`, in memory if it's `None`.
+    pub fn open(index_path: Option<PathBuf>) -> anyhow::Result<Self> {
         let mut schema_builder = Schema::builder();
 
         let token_options = TextOptions::default()
@@ -43,7 +62,7 @@ impl Search {
                     .set_index_option(IndexRecordOption::WithFreqsAndPositions),
             )
             .set_stored();
-        let tokenizer = TextAnalyzer::builder(NgramTokenizer::new(2, 4, false).unwrap())
+        let tokenizer = TextAnalyzer::builder(NgramTokenizer::new(2, 4, false)?)
             .filter(LowerCaser)
             .build();
 
@@ -54,29 +73,51 @@ impl Search {
                     .set_index_option(IndexRecordOption::WithFreqsAndPositions),
             )
             .set_stored();
-        let id = schema_builder.add_text_field("id", token_options.clone());
-        let source = schema_builder.add_text_field("source", token_options.clone());
-        let content = schema_builder.add_text_field("content", text_options);
-        let url = schema_builder.add_text_field("url", token_options);
-        let timestamp = schema_builder.add_i64_field("timestamp", FAST | STORED);
+        schema_builder.add_text_field("id", token_options.clone());
+        schema_builder.add_text_field("source", token_options.clone());
+        schema_builder.add_text_field("content", text_options);
+        schema_builder.add_text_field("url", token_options.clone());
+        schema_builder.add_i64_field("timestamp", FAST | STORED);
+        schema_builder.add_text_field("channel", token_options);
+        schema_builder.add_bool_field("pinned", INDEXED | STORED);
         let schema = schema_builder.build();
         let index = match index_path {
             Some(path) => {
-                std::fs::create_dir_all(&path).unwrap();
-                Index::open_or_create(MmapDirectory::open(path).unwrap(), schema.clone()).unwrap()
+                std::fs::create_dir_all(&path)
+                    .with_context(|| format!("Failed to create the index directory {:?}", path))?;
+                let directory = MmapDirectory::open(&path)?;
+                // Existing indices are opened with their own schema, which may predate some fields.
+                if Index::exists(&directory)? {
+                    Index::open(directory)?
+                } else {
+                    Index::create(directory, schema.clone(), IndexSettings::default())?
+                }
             }
             None => Index::create_in_ram(schema.clone()),
         };
+        let schema = index.schema();
+        let id = schema.get_field("id")?;
+        let source = schema.get_field("source")?;
+        let content = schema.get_field("content")?;
+        let url = schema.get_field("url")?;
+        let timestamp = schema.get_field("timestamp")?;
+        let channel = schema.get_field("channel").ok();
+        if channel.is_none() {
+            warn!("Index was created by an older version, channels are not stored in history. Recreate the index to enable filtering by channel.");
+        }
+        let pinned = schema.get_field("pinned").ok();
+        if pinned.is_none() {
+            warn!("Index was created by an older version, entries can't be pinned. Recreate the index to enable pinning.");
+        }
         index.tokenizers().register(TOKENIZER_NAME, tokenizer);
         let reader = index
             .reader_builder()
             .reload_policy(ReloadPolicy::OnCommitWithDelay)
-            .try_into()
-            .unwrap();
+            .try_into()?;
         let mut query_parser = QueryParser::for_index(&index, vec![content]);
         query_parser.set_conjunction_by_default();
         query_parser.set_field_fuzzy(content, true, 1, true);
-        Self {
+        Ok(Self {
             index,
             reader,
             id,
@@ -84,8 +125,26 @@ impl Search {
             content,
             url,
             timestamp,
+            channel,
+            pinned,
             query_parser,
-        }
+            writer_lock: Default::default(),
+        })
+    }
+
+    fn get_channel(&self, doc: &TantivyDocument) -> Option<String> {
+        self.channel
+            .and_then(|channel| doc.get_first(channel))
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty() && *v != DEFAULT_CHANNEL)
+            .map(|v| v.to_string())
+    }
+
+    fn is_pinned(&self, doc: &TantivyDocument) -> bool {
+        self.pinned
+            .and_then(|pinned| doc.get_first(pinned))
+            .and_then(|v| v.as_bool())
+            .unwrap_or_default()
     }
 
     pub fn get_entry_by_id(&self, id: &str) -> anyhow::Result<Option<ClipboardMessage>> {
@@ -126,14 +185,19 @@ impl Search {
             .get_first(self.timestamp)
             .and_then(|v| v.as_i64())
             .unwrap_or_default();
+        let channel = self.get_channel(&doc);
+        let pinned = self.is_pinned(&doc);
         if url.is_empty() {
             Ok(Some(ClipboardMessage {
                 entry: ServerClipboardRecord {
                     id: Some(id),
                     source: source.to_string(),
                     content: ServerClipboardContent::Text(data.to_string()),
+                    targets: None,
+                    channel,
                 },
                 timestamp,
+                pinned,
             }))
         } else {
             Ok(Some(ClipboardMessage {
@@ -141,8 +205,11 @@ impl Search {
                     id: Some(id),
                     source: source.to_string(),
                     content: ServerClipboardContent::ImageUrl(url.to_string()),
+                    targets: None,
+                    channel,
                 },
                 timestamp,
+                pinned,
             }))
         }
     }
@@ -151,6 +218,8 @@ impl Search {
         debug!("Adding entry: from {}", entry.entry.source);
         assert!(entry.entry.id.is_some());
         let id = entry.entry.id.as_ref().unwrap().clone();
+        // Held across the check too, so a concurrent write can't change the outcome
+        let _lock = self.writer_lock.lock().unwrap();
         let q = TermQuery::new(
             Term::from_field_text(self.id, &id),
             IndexRecordOption::Basic,
@@ -166,9 +235,21 @@ impl Search {
             debug!("Entry already exists, skipping");
             return Ok(());
         }
-        let mut index_writer = self.index.writer(50_000_000)?;
+        let mut index_writer = self.writer()?;
+        index_writer.add_document(self.document(&id, entry))?;
+        index_writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn writer(&self) -> anyhow::Result<IndexWriter> {
+        let index_writer = self.index.writer(50_000_000)?;
         index_writer.set_merge_policy(Box::<LogMergePolicy>::default());
-        index_writer.add_document(match &entry.entry.content {
+        Ok(index_writer)
+    }
+
+    fn document(&self, id: &str, entry: &ClipboardMessage) -> TantivyDocument {
+        let mut document = match &entry.entry.content {
             ServerClipboardContent::Text(text) => {
                 doc!(
                     self.id => id,
@@ -185,9 +266,50 @@ impl Search {
                     self.timestamp => entry.timestamp
                 )
             }
-        })?;
+        };
+        if let Some(channel) = self.channel {
+            document.add_text(
+                channel,
+                entry.entry.channel.as_deref().unwrap_or(DEFAULT_CHANNEL),
+            );
+        }
+        if let Some(pinned) = self.pinned {
+            document.add_bool(pinned, entry.pinned);
+        }
+        document
+    }
+
+    /// Remove an entry, returns whether it existed.
+    pub fn delete_entry(&self, id: &str) -> anyhow::Result<bool> {
+        let _lock = self.writer_lock.lock().unwrap();
+        if self.get_entry_by_id(id)?.is_none() {
+            return Ok(false);
+        }
+        let mut index_writer = self.writer()?;
+        index_writer.delete_term(Term::from_field_text(self.id, id));
         index_writer.commit()?;
-        Ok(())
+        // Don't show the entry until the reader reloads on its own
+        self.reader.reload()?;
+        Ok(true)
+    }
+
+    /// Pin or unpin an entry, returns whether it exists.
+    pub fn set_pinned(&self, id: &str, pinned: bool) -> anyhow::Result<bool> {
+        if self.pinned.is_none() {
+            anyhow::bail!("Index was created by an older version, recreate it to pin entries");
+        }
+        let _lock = self.writer_lock.lock().unwrap();
+        let Some(mut entry) = self.get_entry_by_id(id)? else {
+            return Ok(false);
+        };
+        entry.pinned = pinned;
+        // Documents can't be changed in place, the entry is replaced in a single commit
+        let mut index_writer = self.writer()?;
+        index_writer.delete_term(Term::from_field_text(self.id, id));
+        index_writer.add_document(self.document(id, &entry))?;
+        index_writer.commit()?;
+        self.reader.reload()?;
+        Ok(true)
     }
 
     pub fn get_device_list(&self) -> anyhow::Result<HashSet<String>> {
@@ -249,6 +371,26 @@ impl Search {
                 Box::new(source_q)
             }
         };
+        let channel_q: Box<dyn Query> = match (param.channels.is_empty(), self.channel) {
+            (true, _) => {
+                debug!("Empty channel query");
+                Box::new(AllQuery)
+            }
+            (false, Some(channel)) => {
+                debug!("Channel query: {:?}", param.channels);
+                Box::new(TermSetQuery::new(
+                    param
+                        .channels
+                        .into_iter()
+                        .map(|c| Term::from_field_text(channel, &c))
+                        .collect::<Vec<_>>(),
+                ))
+            }
+            (false, None) => {
+                warn!("Channels are not stored in this index, ignoring channel query");
+                Box::new(AllQuery)
+            }
+        };
         let time_q: Box<dyn Query> = match param.time_range {
             Some((begin, end)) => {
                 let begin = begin.timestamp();
@@ -262,14 +404,26 @@ impl Search {
             }
         };
 
+        let pinned_q: Box<dyn Query> = match (param.pinned_only, self.pinned) {
+            (false, _) => Box::new(AllQuery),
+            (true, Some(pinned)) => Box::new(TermQuery::new(
+                Term::from_field_bool(pinned, true),
+                IndexRecordOption::Basic,
+            )),
+            // Nothing can be pinned in this index
+            (true, None) => Box::new(EmptyQuery),
+        };
+
         let q = BooleanQuery::new(vec![
             (Occur::Must, content_q),
             (Occur::Must, source_q),
+            (Occur::Must, channel_q),
             (Occur::Must, time_q),
+            (Occur::Must, pinned_q),
         ]);
         let mut collectors = MultiCollector::new();
         let count_handle = collectors.add_collector(Count);
-        let (count, ret) = if param.sort_by_score {
+        let (count, ret) = if param.sort == Sort::Score {
             let top_docs_handle =
                 collectors.add_collector(TopDocs::with_limit(param.size).and_offset(param.skip));
             let mut multi_fruit = searcher.search(&q, &collectors)?;
@@ -284,7 +438,14 @@ impl Search {
             let top_docs_handle: FruitHandle<Vec<(i64, DocAddress)>> = collectors.add_collector(
                 TopDocs::with_limit(param.size)
                     .and_offset(param.skip)
-                    .order_by_fast_field("timestamp", Order::Desc),
+                    .order_by_fast_field(
+                        "timestamp",
+                        if param.sort == Sort::Oldest {
+                            Order::Asc
+                        } else {
+                            Order::Desc
+                        },
+                    ),
             );
             let mut multi_fruit = searcher.search(&q, &collectors)?;
             let count = count_handle.extract(&mut multi_fruit);
@@ -321,14 +482,19 @@ impl Search {
                         .get_first(self.timestamp)
                         .and_then(|v| v.as_i64())
                         .unwrap_or_default();
+                    let channel = self.get_channel(&d);
+                    let pinned = self.is_pinned(&d);
                     if url.is_empty() {
                         ClipboardMessage {
                             entry: ServerClipboardRecord {
                                 id: Some(id),
                                 source: source.to_string(),
                                 content: ServerClipboardContent::Text(data.to_string()),
+                                targets: None,
+                                channel,
                             },
                             timestamp,
+                            pinned,
                         }
                     } else {
                         ClipboardMessage {
@@ -336,8 +502,11 @@ impl Search {
                                 id: Some(id),
                                 source: source.to_string(),
                                 content: ServerClipboardContent::ImageUrl(url.to_string()),
+                                targets: None,
+                                channel,
                             },
                             timestamp,
+                            pinned,
                         }
                     }
                 })
@@ -351,3 +520,110 @@ impl Search {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use client_interface::{ServerClipboardContent, ServerClipboardRecord};
+
+    use super::*;
+
+    fn entry(id: &str, text: &str, timestamp: i64) -> ClipboardMessage {
+        ClipboardMessage {
+            entry: ServerClipboardRecord {
+                id: Some(id.to_string()),
+                source: "test".to_string(),
+                content: ServerClipboardContent::Text(text.to_string()),
+                targets: None,
+                channel: None,
+            },
+            timestamp,
+            pinned: false,
+        }
+    }
+
+    fn ids(search: &Search, sort: Sort, pinned_only: bool) -> Vec<String> {
+        let result = search
+            .query(QueryParam {
+                query: None,
+                sources: HashSet::new(),
+                channels: HashSet::new(),
+                time_range: None,
+                skip: 0,
+                size: 10,
+                sort,
+                pinned_only,
+            })
+            .unwrap();
+        result
+            .data
+            .into_iter()
+            .map(|msg| msg.entry.id.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        let search = Search::new(None);
+        search.add_entry(&entry("a", "first", 1)).unwrap();
+        search.add_entry(&entry("b", "second", 2)).unwrap();
+        search.add_entry(&entry("c", "third", 3)).unwrap();
+        search.reader.reload().unwrap();
+        assert_eq!(ids(&search, Sort::Newest, false), ["c", "b", "a"]);
+        assert_eq!(ids(&search, Sort::Oldest, false), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_pin_and_delete() {
+        let search = Search::new(None);
+        search.add_entry(&entry("a", "first", 1)).unwrap();
+        search.add_entry(&entry("b", "second", 2)).unwrap();
+        search.reader.reload().unwrap();
+        assert_eq!(ids(&search, Sort::Newest, false), ["b", "a"]);
+        assert!(ids(&search, Sort::Newest, true).is_empty());
+
+        assert!(search.set_pinned("a", true).unwrap());
+        assert!(!search.set_pinned("missing", true).unwrap());
+        assert_eq!(ids(&search, Sort::Newest, false), ["b", "a"]);
+        assert_eq!(ids(&search, Sort::Newest, true), ["a"]);
+        let pinned = search.get_entry_by_id("a").unwrap().unwrap();
+        assert!(pinned.pinned);
+        assert_eq!(pinned.timestamp, 1);
+        assert_eq!(
+            pinned.entry.content,
+            ServerClipboardContent::Text("first".to_string())
+        );
+
+        assert!(search.set_pinned("a", false).unwrap());
+        assert!(ids(&search, Sort::Newest, true).is_empty());
+
+        assert!(search.delete_entry("b").unwrap());
+        assert!(!search.delete_entry("b").unwrap());
+        assert_eq!(ids(&search, Sort::Newest, false), ["a"]);
+        assert!(search.get_entry_by_id("b").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let search = Search::new(None);
+        search.add_entry(&entry("a", "first", 1)).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let search = search.clone();
+                std::thread::spawn(move || {
+                    let id = format!("entry-{}", i);
+                    search.add_entry(&entry(&id, &id, i + 2)).unwrap();
+                    search.set_pinned("a", i % 2 == 0).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(ids(&search, Sort::Newest, false).len(), 5);
+
+        // A pin after the delete doesn't bring the entry back
+        assert!(search.delete_entry("a").unwrap());
+        assert!(!search.set_pinned("a", true).unwrap());
+        assert!(search.get_entry_by_id("a").unwrap().is_none());
+    }
+}
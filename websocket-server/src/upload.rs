@@ -0,0 +1,260 @@
+//! Chunked image uploads, so large images don't have to be sent in one request and an
+//! interrupted upload can continue where it stopped.
+//!
+//! A client picks an upload id, `PUT`s chunks to `/upload-image/<device>/<id>?offset=<n>` and
+//! `POST`s to the same URL when done, which returns the image URL like the multipart upload.
+//! `GET` returns how much the server has, to resume from.
+
+use std::{
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Utc;
+use client_interface::ServerClipboardContent;
+use log::{debug, info, warn};
+use poem::{
+    handler,
+    http::StatusCode,
+    web::{Data, Path, Query},
+    Body,
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::RwLock,
+};
+
+use crate::global_state::{file_digest, GlobalState};
+
+/// Ids are chosen by clients, so they are limited to something safe as a file name.
+const MAX_ID_LEN: usize = 128;
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Unfinished uploads untouched for this long are removed.
+const STALE_UPLOAD: Duration = Duration::from_secs(24 * 60 * 60);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Deserialize)]
+struct ChunkParams {
+    offset: u64,
+}
+
+fn error(status: StatusCode) -> poem::Error {
+    poem::Error::from_status(status)
+}
+
+fn internal_error(e: impl std::fmt::Display) -> poem::Error {
+    warn!("Upload failed: {}", e);
+    error(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Device names and upload ids end up in file names.
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ID_LEN
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+}
+
+async fn part_path(
+    global_state: &Arc<RwLock<GlobalState>>,
+    name: &str,
+    id: &str,
+) -> poem::Result<PathBuf> {
+    if !is_safe_name(name)
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        || !is_safe_name(id)
+    {
+        return Err(error(StatusCode::BAD_REQUEST));
+    }
+    let dir = global_state.read().await.get_upload_path().join(name);
+    Ok(dir.join(format!("{}.part", id)))
+}
+
+async fn file_size(path: &FsPath) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0)
+}
+
+/// A new file name for an image of the device, `<timestamp>-<n>.png`.
+pub(crate) fn new_image_name(dir: &FsPath) -> String {
+    let timestamp = Utc::now().format("%Y-%m-%d-%H-%M-%S-%6f");
+    let mut suffix = 1usize;
+    loop {
+        let filename = format!("{}-{}.png", timestamp, suffix);
+        if !dir.join(&filename).exists() {
+            return filename;
+        }
+        suffix += 1;
+    }
+}
+
+/// How many bytes of the upload the server has.
+#[handler]
+pub(crate) async fn upload_status(
+    Path((name, id)): Path<(String, String)>,
+    data: Data<&Arc<RwLock<GlobalState>>>,
+) -> poem::Result<String> {
+    let path = part_path(data.0, &name, &id).await?;
+    Ok(file_size(&path).await.to_string())
+}
+
+/// Append a chunk, it must start where the upload ends. Returns the new size.
+#[handler]
+pub(crate) async fn upload_chunk(
+    Path((name, id)): Path<(String, String)>,
+    Query(params): Query<ChunkParams>,
+    body: Body,
+    data: Data<&Arc<RwLock<GlobalState>>>,
+) -> poem::Result<String> {
+    let path = part_path(data.0, &name, &id).await?;
+    let max_size = data.0.read().await.get_max_upload_size();
+    let lock = data.0.read().await.upload_lock(&path).await;
+    let _guard = lock.lock().await;
+    let size = file_size(&path).await;
+    if params.offset != size {
+        // The client lost track, e.g. a chunk was stored but the response didn't arrive
+        return Err(poem::Error::from_string(
+            size.to_string(),
+            StatusCode::CONFLICT,
+        ));
+    }
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(internal_error)?;
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .map_err(internal_error)?;
+    // One byte more than allowed is read to tell whether the limit is exceeded
+    let mut body = body
+        .into_async_read()
+        .take(max_size - size.min(max_size) + 1);
+    let written = tokio::io::copy(&mut body, &mut file)
+        .await
+        .map_err(internal_error)?;
+    file.flush().await.map_err(internal_error)?;
+    if size + written > max_size {
+        warn!(
+            "Upload {} from device '{}' is larger than {} bytes.",
+            id, name, max_size
+        );
+        tokio::fs::remove_file(&path).await.ok();
+        return Err(error(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    debug!(
+        "Upload {} from device '{}' is at {} bytes.",
+        id,
+        name,
+        size + written
+    );
+    Ok((size + written).to_string())
+}
+
+/// Move the finished upload next to the other images of the device, and return its URL.
+#[handler]
+pub(crate) async fn finish_upload(
+    Path((name, id)): Path<(String, String)>,
+    data: Data<&Arc<RwLock<GlobalState>>>,
+) -> poem::Result<String> {
+    let path = part_path(data.0, &name, &id).await?;
+    let lock = data.0.read().await.upload_lock(&path).await;
+    let _guard = lock.lock().await;
+    let mut magic = [0u8; 8];
+    match tokio::fs::File::open(&path).await {
+        Ok(mut file) => {
+            if file.read_exact(&mut magic).await.is_err() || magic != PNG_MAGIC {
+                warn!("Upload {} from device '{}' is not a PNG.", id, name);
+                tokio::fs::remove_file(&path).await.ok();
+                return Err(error(StatusCode::BAD_REQUEST));
+            }
+        }
+        Err(_) => return Err(error(StatusCode::NOT_FOUND)),
+    }
+    let digest = file_digest(&path)
+        .await
+        .ok_or_else(|| error(StatusCode::NOT_FOUND))?;
+    let global_state = data.0.read().await;
+    if let Ok(Some(existing_entry)) = global_state.get_entry_by_id(&digest).await {
+        if let ServerClipboardContent::ImageUrl(url) = &existing_entry.entry.content {
+            debug!("Image already exists: {:?}", existing_entry);
+            tokio::fs::remove_file(&path).await.ok();
+            return Ok(url.clone());
+        }
+    }
+    let dir = global_state.get_image_path().join(&name);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(internal_error)?;
+    let filename = new_image_name(&dir);
+    tokio::fs::rename(&path, dir.join(&filename))
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "Image {} from device '{}' uploaded in chunks.",
+        filename, name
+    );
+    Ok(format!("{name}/{}", filename))
+}
+
+/// Remove abandoned uploads now and then, so they don't fill the disk.
+pub(crate) async fn remove_stale_uploads(upload_path: PathBuf) {
+    loop {
+        match remove_stale(&upload_path).await {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} abandoned uploads.", removed),
+            Err(e) => warn!("Failed to remove abandoned uploads: {}", e),
+        }
+        tokio::time::sleep(CLEANUP_INTERVAL).await;
+    }
+}
+
+async fn remove_stale(upload_path: &FsPath) -> std::io::Result<usize> {
+    let mut removed = 0;
+    let mut devices = match tokio::fs::read_dir(upload_path).await {
+        Ok(devices) => devices,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    while let Some(device) = devices.next_entry().await? {
+        if !device.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut parts = tokio::fs::read_dir(device.path()).await?;
+        while let Some(part) = parts.next_entry().await? {
+            let age = part.metadata().await?.modified()?.elapsed();
+            if age.is_ok_and(|age| age > STALE_UPLOAD) {
+                debug!("Removing abandoned upload {:?}", part.path());
+                tokio::fs::remove_file(part.path()).await?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_name() {
+        assert!(is_safe_name("laptop"));
+        assert!(!is_safe_name(""));
+        assert!(!is_safe_name(".."));
+        assert!(!is_safe_name(".uploads"));
+        assert!(!is_safe_name("a/b"));
+        assert!(!is_safe_name(&"x".repeat(MAX_ID_LEN + 1)));
+    }
+}